@@ -0,0 +1,95 @@
+//! Formatting throughput for single nodes, and shallow and deep syntax trees.
+//!
+//! Run with `cargo bench --bench format`.
+
+use std::fmt::Write;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use syntaxfmt::{syntax_fmt, Mode, SyntaxFmt};
+
+#[derive(SyntaxFmt)]
+#[syntax(pre = "fn ", suf = "\n")]
+struct Function {
+    name: &'static str,
+    #[syntax(pre = "(", suf = ")", sep = ", ")]
+    params: Vec<Param>,
+    body: Block,
+}
+
+#[derive(SyntaxFmt)]
+struct Param {
+    #[syntax(suf = ": ")]
+    name: &'static str,
+    ty: &'static str,
+}
+
+#[derive(SyntaxFmt)]
+#[syntax(pre = [" {", " {"], suf = "}", nl = [inner, cont], ind)]
+struct Block(#[syntax(sep = ["; ", ";"], nl = sep)] Vec<Stmt>);
+
+#[derive(SyntaxFmt)]
+enum Stmt {
+    #[syntax(pre = "let ", suf = ";")]
+    Let(&'static str, #[syntax(pre = " = ")] Expr),
+    #[syntax(pre = "if ")]
+    If(Expr, Block),
+    Expr(Expr),
+}
+
+#[derive(SyntaxFmt)]
+enum Expr {
+    Int(i64),
+    Var(&'static str),
+    #[syntax(pre = "(", suf = ")")]
+    Add(Box<Expr>, #[syntax(pre = " + ")] Box<Expr>),
+    Call(&'static str, #[syntax(pre = "(", suf = ")", sep = ", ")] Vec<Expr>),
+}
+
+// Statements nested `depth` blocks deep, with `width` statements per block
+fn block(depth: usize, width: usize) -> Block {
+    Block(
+        (0..width)
+            .map(|i| match i % 3 {
+                0 if depth > 0 => Stmt::If(Expr::Var("cond"), block(depth - 1, width)),
+                0 | 1 => Stmt::Let(
+                    "x",
+                    Expr::Add(Box::new(Expr::Var("x")), Box::new(Expr::Int(i as i64))),
+                ),
+                _ => Stmt::Expr(Expr::Call("f", vec![Expr::Var("x"), Expr::Int(42)])),
+            })
+            .collect(),
+    )
+}
+
+fn function(depth: usize, width: usize) -> Function {
+    Function {
+        name: "main",
+        params: vec![
+            Param { name: "a", ty: "i32" },
+            Param { name: "b", ty: "String" },
+        ],
+        body: block(depth, width),
+    }
+}
+
+fn bench_format(c: &mut Criterion) {
+    let mut group = c.benchmark_group("format");
+    for (name, depth, width) in [("leaf", 0, 1), ("shallow", 1, 64), ("deep", 12, 3)] {
+        let tree = function(depth, width);
+        for mode in Mode::ALL {
+            let len = syntax_fmt(&tree).mode(mode).to_string().len();
+            group.throughput(Throughput::Bytes(len as u64));
+            let mut out = String::with_capacity(len);
+            group.bench_function(BenchmarkId::new(name, mode), |b| {
+                b.iter(|| {
+                    out.clear();
+                    write!(out, "{}", syntax_fmt(&tree).mode(mode)).unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_format);
+criterion_main!(benches);
@@ -1,8 +1,10 @@
 pub mod args;
 pub mod content;
 pub mod eval;
+pub mod grammar;
 pub mod modal;
 pub mod prefix_suffix;
 pub mod pretty;
+pub mod transform;
 pub mod unverified_args;
 pub mod context;
\ No newline at end of file
@@ -1,8 +1,9 @@
 use std::{collections::HashSet, fmt::Debug};
 
 use syn::{
-    Attribute, GenericArgument, Ident, Lifetime, PathArguments, Result as SynResult,
-    TypeParamBound, TypePath, TypeTraitObject,
+    Attribute, Expr, GenericArgument, Ident, Lifetime, Lit, LitBool, LitStr, PathArguments,
+    Result as SynResult, TypeParamBound, TypePath, TypeTraitObject, WherePredicate,
+    punctuated::Punctuated, token::Comma,
 };
 
 use crate::{
@@ -13,6 +14,7 @@ use crate::{
         modal::Strings,
         prefix_suffix::{Prefix, Suffix},
         pretty::Newlines,
+        transform::{Sort, Transforms},
         unverified_args::{UnverifiedArg, UnverifiedArgKind, UnverifiedArgs},
     },
     syn_err,
@@ -25,11 +27,25 @@ pub enum ArgType {
     Prefix,
     Suffix,
     Sep,
+    KvSep,
+    LastSep,
+    FieldSep,
+    Order,
     Eval,
     Cont,
     Bound,
+    Bounds,
     State,
     Skip,
+    Transparent,
+    StateMut,
+    Elide,
+    Flatten,
+    Sort,
+    Dedup,
+    Rev,
+    Filter,
+    Scope,
 }
 
 pub trait TakeArgs: Sized {
@@ -46,14 +62,65 @@ pub trait TakeArgs: Sized {
             "pre" => ArgType::Prefix,
             "suf" => ArgType::Suffix,
             "sep" => ArgType::Sep,
+            "kv_sep" => ArgType::KvSep,
+            "last_sep" => ArgType::LastSep,
+            "field_sep" => ArgType::FieldSep,
+            "order" => ArgType::Order,
             "eval" | "eval_with" => ArgType::Eval,
-            "cont" | "cont_with" => ArgType::Cont,
+            "cont" | "cont_with" | "quote" | "ident" | "num" | "sorted" | "iter" => ArgType::Cont,
             "bound" => ArgType::Bound,
             "state" => ArgType::State,
             "skip" => ArgType::Skip,
+            "transparent" => ArgType::Transparent,
+            "state_mut" => ArgType::StateMut,
+            "elide" => ArgType::Elide,
+            "flatten" => ArgType::Flatten,
+            "sort" | "sort_by" => ArgType::Sort,
+            "dedup" => ArgType::Dedup,
+            "rev" => ArgType::Rev,
+            "filter" => ArgType::Filter,
+            "scope" => ArgType::Scope,
             _ => unreachable!("earlier checks in UnverifiedArgs::parse should have pre-filtered the possible idents")
         }
     }
+
+    // `bound` is shared by the state bound and the generic bounds override, so these are told
+    // apart by kind
+    #[inline]
+    #[must_use]
+    fn classify_arg(arg: &UnverifiedArg) -> ArgType {
+        match arg.kind {
+            UnverifiedArgKind::Bounds(_) => ArgType::Bounds,
+            _ => Self::classify_ident(&arg.ident),
+        }
+    }
+}
+
+// Checks that an argument which replaces the usual formatting isn't combined with arguments it
+// would silently ignore
+fn check_exclusive(
+    args: &UnverifiedArgs,
+    args_else: &Option<UnverifiedArgs>,
+    name: &str,
+    allowed: fn(&UnverifiedArgKind) -> bool,
+) -> SynResult<()> {
+    if !args.args.iter().any(|a| a.ident == name) {
+        return Ok(());
+    }
+    if let Some(arg) = args.args.iter().find(|a| !allowed(&a.kind)) {
+        return syn_err(
+            &arg.ident,
+            format!("syntaxfmt `{name}` cannot be combined with `{}`", arg.ident),
+        );
+    }
+    if let Some(args_else) = args_else {
+        // Safety: Safe to unwrap because if we have an Else group of args, an ident has been assigned
+        return syn_err(
+            args_else.attr.as_ref().unwrap(),
+            format!("syntaxfmt `{name}` cannot be combined with `syntax_else`"),
+        );
+    }
+    Ok(())
 }
 
 #[derive(Debug, Default, Clone)]
@@ -63,8 +130,14 @@ pub struct CommonArgs {
     pub suffix: Option<Suffix>,
     pub content: Option<Content>,
     pub seps: Strings,
+    pub kv_sep: Option<Strings>,
+    pub last_sep: Option<Strings>,
     pub nl: Newlines,
     pub indent: bool,
+    pub transforms: Transforms,
+    pub scope: Option<Expr>,
+    // Whether the type has `state_mut`, inherited by its variants and fields
+    pub state_mut: bool,
 }
 
 impl CommonArgs {
@@ -77,9 +150,22 @@ impl CommonArgs {
                 | Prefix(_)
                 | Suffix(_)
                 | Seps(_)
+                | KvSeps(_)
+                | LastSeps(_)
                 | Content(_)
                 | ContentTypePath(_)
                 | ContentClosure(_)
+                | ContentQuote(_)
+                | ContentIdent(_)
+                | ContentNum(_)
+                | ContentSorted(_)
+                | ContentIter(..)
+                | Sort(_)
+                | SortBy(_)
+                | Dedup(_)
+                | Rev(_)
+                | Filter(_)
+                | Scope(_)
                 | Newlines(_)
         )
     }
@@ -89,23 +175,43 @@ impl TakeArgs for CommonArgs {
     fn take_args(mut self, args: &mut UnverifiedArgs, _have_eval: bool) -> SynResult<Self> {
         use UnverifiedArgKind as Kind;
         let mut visited = HashSet::new();
+        let mut transform = None;
         let mut i = 0;
         while i < args.args.len() {
             if Self::match_common(&mut args.args[i]) {
                 let arg = args.args.remove(i);
-                if !visited.insert(Self::classify_ident(&arg.ident)) {
+                if !visited.insert(Self::classify_arg(&arg)) {
                     return syn_err(
                         &arg.ident,
                         "syntaxfmt found duplicate or conflicting attribute argument",
                     );
                 }
+                if matches!(
+                    arg.kind,
+                    Kind::Sort(_) | Kind::SortBy(_) | Kind::Dedup(_) | Kind::Rev(_) | Kind::Filter(_)
+                ) {
+                    transform.get_or_insert_with(|| arg.ident.clone());
+                }
                 match arg.kind {
                     Kind::Prefix(i) => self.prefix = Prefix::from_litstrs(i)?,
                     Kind::Suffix(i) => self.suffix = Suffix::from_litstrs(i)?,
                     Kind::Seps(i) => self.seps = Strings::from_litstrs(i)?,
+                    Kind::KvSeps(i) => self.kv_sep = Some(Strings::from_litstrs(i)?),
+                    Kind::LastSeps(i) => self.last_sep = Some(Strings::from_litstrs(i)?),
                     Kind::Content(i) => self.content = Content::from_expr(i)?,
                     Kind::ContentTypePath(i) => self.content = Content::from_type_path(i)?,
                     Kind::ContentClosure(i) => self.content = Content::from_closure(i)?,
+                    Kind::ContentQuote(i) => self.content = Content::from_quote(i)?,
+                    Kind::ContentIdent(i) => self.content = Content::from_ident(i)?,
+                    Kind::ContentNum(i) => self.content = Content::from_num(i)?,
+                    Kind::ContentSorted(_) => self.content = Some(Content::Sorted),
+                    Kind::ContentIter(d, i) => self.content = Content::from_iter(d.is_some(), i)?,
+                    Kind::Sort(_) => self.transforms.sort = Some(Sort::Ord),
+                    Kind::SortBy(i) => self.transforms.sort = Some(Sort::By(i)),
+                    Kind::Dedup(_) => self.transforms.dedup = true,
+                    Kind::Rev(_) => self.transforms.rev = true,
+                    Kind::Filter(i) => self.transforms.set_filter(i)?,
+                    Kind::Scope(i) => self.scope = Some(i),
                     Kind::Indent(_) => self.indent = true,
                     Kind::Newlines(i) => self.nl = Newlines::from_idents(i)?,
                     _ => unreachable!("match_common should have matched all possibilities"),
@@ -114,6 +220,13 @@ impl TakeArgs for CommonArgs {
                 i += 1;
             }
         }
+        // Transforms reorder the default content, so there's nothing to apply them to otherwise
+        if let (Some(ident), Some(_)) = (transform, &self.content) {
+            return syn_err(
+                &ident,
+                format!("syntaxfmt `{ident}` cannot be combined with content replacement"),
+            );
+        }
         Ok(self)
     }
 }
@@ -130,16 +243,47 @@ pub struct TypeArgsNormal {
     pub eval: Option<Eval>,
     pub state: Option<TypePath>,
     pub state_bound: Option<TypeTraitObject>,
+    pub bounds: Option<Punctuated<WherePredicate, Comma>>,
+    pub field_sep: Option<Strings>,
+    pub transparent: bool,
+    pub elide: Option<Elide>,
     skip: bool,
 }
 
+// How a type behaves beyond the maximum depth
+#[derive(Debug, Clone)]
+pub enum Elide {
+    Never,
+    Placeholder(LitStr),
+}
+
+impl Elide {
+    fn from_lit(lit: Lit) -> SynResult<Option<Self>> {
+        match lit {
+            Lit::Bool(LitBool { value: false, .. }) => Ok(Some(Self::Never)),
+            Lit::Str(s) => Ok(Some(Self::Placeholder(s))),
+            lit => syn_err(lit, "syntaxfmt `elide` expects `false` or a placeholder string"),
+        }
+    }
+}
+
 impl TypeArgsNormal {
     #[inline]
     fn match_args(arg: &mut UnverifiedArg) -> bool {
         use UnverifiedArgKind::*;
         matches!(
             arg.kind,
-            Skip(_) | Eval(_) | EvalTypePath(_) | EvalClosure(_) | State(_) | StateBound(_)
+            Skip(_)
+                | Eval(_)
+                | EvalTypePath(_)
+                | EvalClosure(_)
+                | State(_)
+                | StateBound(_)
+                | Bounds(_)
+                | FieldSeps(_)
+                | Transparent(_)
+                | StateMut(_)
+                | Elide(_)
         )
     }
 }
@@ -172,7 +316,7 @@ impl TakeArgs for TypeArgsNormal {
         while i < args.args.len() {
             if Self::match_args(&mut args.args[i]) {
                 let arg = args.args.remove(i);
-                if !visited.insert(Self::classify_ident(&arg.ident)) {
+                if !visited.insert(Self::classify_arg(&arg)) {
                     return syn_err(
                         &arg.ident,
                         "syntaxfmt found duplicate or conflicting attribute argument",
@@ -184,6 +328,11 @@ impl TakeArgs for TypeArgsNormal {
                     Kind::EvalClosure(i) => self.eval = Eval::from_closure(i)?,
                     Kind::State(i) => self.state = Some(i),
                     Kind::StateBound(i) => self.state_bound = Some(i),
+                    Kind::Bounds(i) => self.bounds = Some(i),
+                    Kind::FieldSeps(i) => self.field_sep = Some(Strings::from_litstrs(i)?),
+                    Kind::Transparent(_) => self.transparent = true,
+                    Kind::StateMut(_) => self.common.state_mut = true,
+                    Kind::Elide(i) => self.elide = Elide::from_lit(i)?,
                     Kind::Skip(_) => self.skip = true,
                     _ => unreachable!("match_args should have matched all possibilities"),
                 }
@@ -235,6 +384,10 @@ impl WithCommon for TypeArgsElse {
 pub struct FieldArgsNormal {
     pub common: CommonArgs,
     pub eval: Option<Eval>,
+    pub bounds: Option<Punctuated<WherePredicate, Comma>>,
+    pub field_sep: Option<Strings>,
+    pub order: Option<i64>,
+    pub flatten: bool,
     skip: bool,
 }
 
@@ -244,7 +397,14 @@ impl FieldArgsNormal {
         use UnverifiedArgKind::*;
         matches!(
             arg.kind,
-            Skip(_) | Eval(_) | EvalTypePath(_) | EvalClosure(_)
+            Skip(_)
+                | Eval(_)
+                | EvalTypePath(_)
+                | EvalClosure(_)
+                | Bounds(_)
+                | FieldSeps(_)
+                | Order(..)
+                | Flatten(_)
         )
     }
 }
@@ -271,7 +431,7 @@ impl TakeArgs for FieldArgsNormal {
         while i < args.args.len() {
             if Self::match_args(&mut args.args[i]) {
                 let arg = args.args.remove(i);
-                if !visited.insert(Self::classify_ident(&arg.ident)) {
+                if !visited.insert(Self::classify_arg(&arg)) {
                     return syn_err(
                         &arg.ident,
                         "syntaxfmt found duplicate or conflicting attribute argument",
@@ -281,6 +441,13 @@ impl TakeArgs for FieldArgsNormal {
                     Kind::Eval(i) => self.eval = Eval::from_expr(i)?,
                     Kind::EvalTypePath(i) => self.eval = Eval::from_type_path(i)?,
                     Kind::EvalClosure(i) => self.eval = Eval::from_closure(i)?,
+                    Kind::Bounds(i) => self.bounds = Some(i),
+                    Kind::FieldSeps(i) => self.field_sep = Some(Strings::from_litstrs(i)?),
+                    Kind::Order(minus, i) => {
+                        let order: i64 = i.base10_parse()?;
+                        self.order = Some(if minus.is_some() { -order } else { order });
+                    }
+                    Kind::Flatten(_) => self.flatten = true,
                     Kind::Skip(_) => self.skip = true,
                     _ => unreachable!("match_args should have matched all possibilities"),
                 }
@@ -345,12 +512,17 @@ impl TypeArgs {
     #[must_use]
     pub fn from_attributes(input: &[Attribute]) -> SynResult<Self> {
         let (mut args, args_else) = UnverifiedArgs::collect_args(input)?;
+        check_exclusive(&args, &args_else, "transparent", |kind| {
+            use UnverifiedArgKind::*;
+            matches!(kind, Transparent(_) | State(_) | StateMut(_) | StateBound(_) | Bounds(_))
+        })?;
         let mut type_args = Self::default();
         type_args.args = type_args.args.take_args(&mut args, false)?;
         if let Some(mut args_else) = args_else {
             let have_eval = type_args.args.eval.is_some();
-            type_args.args_else =
-                Some(TypeArgsElse::default().take_args(&mut args_else, have_eval)?);
+            let mut type_args_else = TypeArgsElse::default().take_args(&mut args_else, have_eval)?;
+            type_args_else.common.state_mut = type_args.args.common.state_mut;
+            type_args.args_else = Some(type_args_else);
         }
         if let Some(t) = type_args.args.state.as_ref() {
             Self::type_path_lifetimes(&mut type_args.lifetimes, t);
@@ -415,23 +587,53 @@ pub struct FieldArgs {
 
 impl FieldArgs {
     #[must_use]
-    pub fn new(field_kind: FieldKind, _parent_common: &CommonArgs, input: &[Attribute]) -> SynResult<Self> {
+    pub fn new(field_kind: FieldKind, parent_common: &CommonArgs, input: &[Attribute]) -> SynResult<Self> {
         let (mut args, args_else) = UnverifiedArgs::collect_args(input)?;
+        check_exclusive(&args, &args_else, "flatten", |kind| {
+            use UnverifiedArgKind::*;
+            matches!(kind, Flatten(_) | Bounds(_) | Order(..))
+        })?;
+        // Variants share field arguments, but some only make sense in one place or the other
+        for arg in &args.args {
+            match (&field_kind, &arg.kind) {
+                (FieldKind::SelfValue, UnverifiedArgKind::Flatten(_) | UnverifiedArgKind::Order(..)) => {
+                    return syn_err(
+                        &arg.ident,
+                        format!("syntaxfmt `{}` can only be applied to fields", arg.ident),
+                    );
+                }
+                (FieldKind::Field(_), UnverifiedArgKind::FieldSeps(_)) => {
+                    return syn_err(
+                        &arg.ident,
+                        "syntaxfmt `field_sep` can only be applied to types and variants",
+                    );
+                }
+                _ => {}
+            }
+        }
         let mut field_args = Self::default();
         field_args.args.common.field_kind = field_kind;
 
         // If we need to inherit some properties from parents, that would be done here
+        field_args.args.common.state_mut = parent_common.state_mut;
 
         field_args.args = field_args.args.take_args(&mut args, false)?;
         if let Some(mut args_else) = args_else {
             let have_eval = field_args.args.eval.is_some();
-            field_args.args_else =
-                Some(FieldArgsElse::default().take_args(&mut args_else, have_eval)?);
+            let mut field_args_else = FieldArgsElse::default().take_args(&mut args_else, have_eval)?;
+            field_args_else.common.state_mut = parent_common.state_mut;
+            field_args.args_else = Some(field_args_else);
         }
         Ok(field_args)
     }
 }
 
+impl FieldArgs {
+    pub fn set_skipped(&mut self) {
+        self.args.skip = true;
+    }
+}
+
 impl Skipped for FieldArgs {
     fn skipped(&self) -> bool {
         self.args.skip
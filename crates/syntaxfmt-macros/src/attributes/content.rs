@@ -1,14 +1,17 @@
-use std::fmt::Debug;
+use std::{collections::HashSet, fmt::Debug};
 
 use proc_macro2::TokenStream as TokenStream2;
 use quote::{quote, ToTokens};
-use syn::{Expr, ExprClosure, Result as SynResult, TypePath};
+use syn::{
+    punctuated::Punctuated, spanned::Spanned, token::Comma, Expr, ExprArray, ExprClosure, ExprLit, Ident, Lit, Meta,
+    Path, Result as SynResult, TypePath,
+};
 
 use crate::{
     attributes::{
         args::CommonArgs,
-        context::{PopContext, PushContext},
-        eval::Eval, pretty::Newlines,
+        context::Decorations,
+        eval::Eval, grammar::special,
     },
     syn_err,
 };
@@ -31,6 +34,15 @@ pub trait WithConditional {
     type Else: WithCommon;
 
     fn conditional(&self) -> (&Self::Normal, &Option<Self::Else>);
+
+    // True when every branch replaces the default content, so the value itself is never formatted
+    fn replaces_content(&self) -> bool {
+        let (args, args_else) = self.conditional();
+        args.common().content.is_some()
+            && args_else
+                .as_ref()
+                .map_or(true, |a| a.common().content.is_some())
+    }
 }
 
 pub trait ToContentTokens {
@@ -44,33 +56,41 @@ where
     fn to_content_tokens(&self, default_content: &Content) -> TokenStream2 {
         let common = self.common();
 
-        let prefix = &common.prefix;
-        let suffix = &common.suffix;
-
-        let nl_beg = common.nl.into_beg();
-        let nl_pre = common.nl.into_pre();
-        let nl_cont = common.nl.into_cont();
-        let nl_suf = common.nl.into_suf();
-
-        let push_context = PushContext {
+        let decor = Decorations {
+            prefix: common.prefix.as_ref().map(|prefix| prefix.0.clone()),
+            suffix: common.suffix.as_ref().map(|suffix| suffix.0.clone()),
             sep: common.seps.clone(),
+            kv_sep: common.kv_sep.clone(),
+            last_sep: common.last_sep.clone(),
             indent: common.indent,
-            nl_sep: common.nl.has(Newlines::SEP),
+            nl: common.nl,
         };
-        let pop_context = PopContext;
 
-        let content = common
-            .content
-            .as_ref()
-            .unwrap_or(&default_content)
-            .to_tokens(&common.field_kind);
+        let content = match &common.content {
+            None if !common.transforms.is_empty() => common.transforms.to_tokens(&common.field_kind),
+            content => content.as_ref().unwrap_or(default_content).to_tokens(&common.field_kind, common.state_mut),
+        };
 
-        // Push and pop context has to be in non-symmetric location
-        // This is because indenting is non-symmetric
-        let pre = quote! { #nl_beg #prefix #push_context #nl_pre };
-        let post = quote! { #pop_context #nl_cont #suffix #nl_suf };
+        // Decorations are written by the runtime from a static, so each type or field is one call
+        let content = quote! {
+            #[allow(clippy::needless_update)]
+            static __SYNTAXFMT_DECOR: ::syntaxfmt::Decor = #decor;
+            f.decorated(&__SYNTAXFMT_DECOR, |f| {
+                #content
+                Ok(())
+            })?;
+        };
 
-        quote! { #pre #content #post }
+        // Scoped values are visible to everything the type or field writes
+        match &common.scope {
+            Some(scope) => quote! {
+                f.with_scope(#scope, |f| {
+                    #content
+                    Ok(())
+                })?;
+            },
+            None => quote! { { #content } },
+        }
     }
 }
 
@@ -120,6 +140,12 @@ pub enum Content {
     Expr(Expr),
     TypePath(TypePath),
     Closure(ExprClosure),
+    Quote(Ident),
+    Ident(Path),
+    IdentState,
+    Num(TokenStream2),
+    Sorted,
+    Iter { relative: bool, expr: Expr },
     Tokens(TokenStream2),
 }
 
@@ -163,10 +189,108 @@ impl Content {
     pub fn from_closure(value: ExprClosure) -> SynResult<Option<Self>> {
         Ok(Some(Self::Closure(value)))
     }
+
+    pub fn from_quote(style: Ident) -> SynResult<Option<Self>> {
+        let variant = match style.to_string().as_str() {
+            "rust" => "Rust",
+            "c" => "C",
+            "json" => "Json",
+            "python" => "Python",
+            "sql" => "Sql",
+            _ => {
+                return syn_err(
+                    style,
+                    "syntaxfmt unknown quote style, expected one of `rust`, `c`, `json`, `python`, `sql`",
+                );
+            }
+        };
+        Ok(Some(Self::Quote(Ident::new(variant, style.span()))))
+    }
+
+    // Built in tables are named by language, `state` defers to the formatter state, and any other
+    // path names a `ReservedWords` value
+    pub fn from_ident(words: Path) -> SynResult<Option<Self>> {
+        let table = match words.get_ident().map(ToString::to_string).as_deref() {
+            Some("state") => return Ok(Some(Self::IdentState)),
+            Some("rust") => "RUST",
+            Some("python") => "PYTHON",
+            Some("sql") => "SQL",
+            Some("kotlin") => "KOTLIN",
+            _ => return Ok(Some(Self::Ident(words))),
+        };
+        let table = Ident::new(table, words.span());
+        Ok(Some(Self::Ident(syn::parse_quote! { ::syntaxfmt::ReservedWords::#table })))
+    }
+
+    pub fn from_iter(relative: bool, expr: Expr) -> SynResult<Option<Self>> {
+        Ok(Some(Self::Iter { relative, expr }))
+    }
+
+    // Each argument maps onto a `NumFmt` builder method
+    pub fn from_num(args: Punctuated<Meta, Comma>) -> SynResult<Option<Self>> {
+        let mut visited = HashSet::new();
+        let mut calls = Vec::new();
+        for arg in args {
+            let name = arg.path().get_ident().map(ToString::to_string).unwrap_or_default();
+            // Radixes conflict with each other, so are visited under one name
+            let visit = match name.as_str() {
+                "bin" | "oct" | "hex" => "radix",
+                name => name,
+            };
+            if !visited.insert(visit.to_string()) {
+                return syn_err(arg, "syntaxfmt found duplicate or conflicting `num` argument");
+            }
+            let method = Ident::new(&name, arg.path().span());
+            calls.push(match (name.as_str(), &arg) {
+                ("bin" | "oct" | "hex" | "no_prefix" | "upper" | "point", Meta::Path(_)) => {
+                    quote! { .#method() }
+                }
+                ("exp", Meta::Path(_)) => quote! { .exp(::syntaxfmt::Exponent::Always) },
+                ("exp", Meta::NameValue(nv)) => {
+                    let exp = match &nv.value {
+                        Expr::Path(p) => p.path.get_ident().map(ToString::to_string),
+                        _ => None,
+                    };
+                    let variant = match exp.as_deref() {
+                        Some("never") => "Never",
+                        Some("always") => "Always",
+                        Some("auto") => "Auto",
+                        _ => {
+                            return syn_err(
+                                &nv.value,
+                                "syntaxfmt unknown exponent style, expected one of `never`, `always`, `auto`",
+                            );
+                        }
+                    };
+                    let variant = Ident::new(variant, nv.value.span());
+                    quote! { .exp(::syntaxfmt::Exponent::#variant) }
+                }
+                ("digits" | "group", Meta::NameValue(nv)) => match &nv.value {
+                    Expr::Lit(ExprLit { lit: Lit::Int(n), .. }) => quote! { .#method(#n) },
+                    value => return syn_err(value, "syntaxfmt expected integer literal"),
+                },
+                ("suffix", Meta::NameValue(nv)) => match &nv.value {
+                    Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) => quote! { .suffix(#s) },
+                    value => return syn_err(value, "syntaxfmt expected string literal"),
+                },
+                _ => {
+                    return syn_err(
+                        arg,
+                        "syntaxfmt unknown `num` argument, expected one of `bin`, `oct`, `hex`, \
+                         `no_prefix`, `upper`, `digits = N`, `group = N`, `point`, `exp`, \
+                         `exp = never|always|auto`, `suffix = \"...\"`",
+                    );
+                }
+            });
+        }
+        Ok(Some(Self::Num(quote! { ::syntaxfmt::NumFmt::new() #(#calls)* })))
+    }
 }
 
 impl Content {
-    pub fn to_tokens(&self, field: &impl ToTokens) -> TokenStream2 {
+    // Custom formatters only get mutable state in types with `state_mut`
+    pub fn to_tokens(&self, field: &impl ToTokens, state_mut: bool) -> TokenStream2 {
+        let cont_f = if state_mut { quote! { f } } else { quote! { f.as_immutable() } };
         match self {
             // Interpreted as items which implement SyntaxFmt
             Self::Expr(Expr::Binary(e)) => quote! { (#e).syntax_fmt(f)?; },
@@ -189,12 +313,70 @@ impl Content {
             // Interpreted as items which must be written directly as strings
             Self::Expr(Expr::Array(e)) => quote! { f.write_strs(#e)?; },
 
-            Self::TypePath(p) => quote! { (#p)(#field, f)?; },
-            Self::Closure(p) => quote! { (#p)(#field, f)?; },
+            Self::TypePath(p) => quote! { (#p)(#field, #cont_f)?; },
+            Self::Closure(p) => quote! { (#p)(#field, #cont_f)?; },
+            Self::Quote(q) => quote! {
+                ::syntaxfmt::Quoted(::syntaxfmt::Quote::#q, ::core::convert::AsRef::<str>::as_ref(#field))
+                    .syntax_fmt(f)?;
+            },
+            Self::Ident(p) => quote! {
+                #p.ident(::core::convert::AsRef::<str>::as_ref(#field)).syntax_fmt(f)?;
+            },
+            Self::Num(n) => quote! {
+                ::syntaxfmt::Num(#n, #field).syntax_fmt(f)?;
+            },
+            Self::Sorted => quote! { ::syntaxfmt::Sorted(#field).syntax_fmt(f)?; },
+            Self::Iter { relative: true, expr } => quote! {
+                ::syntaxfmt::iter(#field.#expr).syntax_fmt(f)?;
+            },
+            Self::Iter { relative: false, expr } => quote! {
+                ::syntaxfmt::iter(#expr).syntax_fmt(f)?;
+            },
+            Self::IdentState => quote! {
+                f.try_map_state(|f, s| {
+                    ::syntaxfmt::ReservedWordsSource::reserved_words(s)
+                        .ident(::core::convert::AsRef::<str>::as_ref(#field))
+                        .syntax_fmt(f)
+                })
+                .unwrap_or_else(|e| Err(f.error(e)))?;
+            },
 
             Self::Tokens(t) => t.clone(),
 
             _ => TokenStream2::new(),
         }
     }
+
+    pub fn to_grammar_tokens(&self) -> TokenStream2 {
+        match self {
+            // String literals are written as is
+            Self::Expr(Expr::Lit(ExprLit { lit: Lit::Str(s), .. })) => {
+                let value = s.value();
+                quote! { ::syntaxfmt::GrammarExpr::terminal(#value) }
+            }
+            Self::Expr(Expr::Lit(e)) => {
+                let value = e.to_token_stream().to_string();
+                quote! { ::syntaxfmt::GrammarExpr::terminal(#value) }
+            }
+
+            // Modal strings, where normal mode is the first element
+            Self::Expr(e @ Expr::Array(ExprArray { elems, .. })) => match elems.first() {
+                Some(Expr::Lit(ExprLit { lit: Lit::Str(s), .. })) => {
+                    let value = s.value();
+                    quote! { ::syntaxfmt::GrammarExpr::terminal(#value) }
+                }
+                _ => special(&e.to_token_stream().to_string()),
+            },
+
+            // Anything else can only be described by its source text
+            Self::Expr(e) => special(&e.to_token_stream().to_string()),
+            Self::TypePath(p) => special(&p.to_token_stream().to_string()),
+            Self::Quote(q) => special(&format!("{q} string")),
+            Self::Ident(_) | Self::IdentState => special("identifier"),
+            Self::Num(_) => special("number"),
+            Self::Sorted => special("sorted entries"),
+            Self::Iter { .. } => special("items"),
+            Self::Closure(_) | Self::Tokens(_) => special("custom"),
+        }
+    }
 }
@@ -1,8 +1,8 @@
-use proc_macro2::TokenStream as TokenStream2;
+use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::{ToTokens, quote};
 use syn::Ident;
 
-use crate::attributes::modal::Strings;
+use crate::attributes::{modal::Strings, pretty::Newlines};
 
 #[derive(Debug, Default, Clone)]
 pub enum FieldKind {
@@ -20,27 +20,54 @@ impl ToTokens for FieldKind {
     }
 }
 
+// Decorations of a type or field, as a `Decor` with only its non-default fields listed
 #[derive(Debug, Clone)]
-pub struct PushContext {
+pub struct Decorations {
+    pub prefix: Option<Strings>,
+    pub suffix: Option<Strings>,
     pub sep: Strings,
+    pub kv_sep: Option<Strings>,
+    pub last_sep: Option<Strings>,
     pub indent: bool,
-    pub nl_sep: bool,
+    pub nl: Newlines,
 }
 
-impl ToTokens for PushContext {
+impl ToTokens for Decorations {
     fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let sep = &self.sep;
-        let indent = self.indent;
-        let nl_sep = self.nl_sep;
-        tokens.extend(quote! { f.push_context(#sep, #indent, #nl_sep); });
-    }
-}
-
-#[derive(Debug, Default, Clone, Copy)]
-pub struct PopContext;
-
-impl ToTokens for PopContext {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        tokens.extend(quote! { f.pop_context(); });
+        let mut fields = Vec::new();
+        let strs = [
+            ("prefix", self.prefix.as_ref()),
+            ("suffix", self.suffix.as_ref()),
+            ("sep", Some(&self.sep).filter(|sep| sep.iter().any(|s| !s.is_empty()))),
+        ];
+        for (name, value) in strs {
+            if let Some(value) = value {
+                let name = Ident::new(name, Span::call_site());
+                fields.push(quote! { #name: #value });
+            }
+        }
+        for (name, value) in [("kv_sep", &self.kv_sep), ("last_sep", &self.last_sep)] {
+            if let Some(value) = value {
+                let name = Ident::new(name, Span::call_site());
+                fields.push(quote! { #name: ::core::option::Option::Some(#value) });
+            }
+        }
+        let flags = [
+            ("indent", self.indent),
+            ("nl_beg", self.nl.has(Newlines::BEG)),
+            ("nl_pre", self.nl.has(Newlines::PRE)),
+            ("nl_cont", self.nl.has(Newlines::CON)),
+            ("nl_suf", self.nl.has(Newlines::SUF)),
+            ("nl_sep", self.nl.has(Newlines::SEP)),
+        ];
+        for (name, value) in flags {
+            if value {
+                let name = Ident::new(name, Span::call_site());
+                fields.push(quote! { #name: true });
+            }
+        }
+        tokens.extend(quote! {
+            ::syntaxfmt::Decor { #(#fields,)* ..::syntaxfmt::Decor::NONE }
+        });
     }
 }
@@ -0,0 +1,170 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use syn::{GenericArgument, PathArguments, Type};
+
+use crate::attributes::{
+    args::CommonArgs,
+    content::{Content, WithCommon, WithConditional, WithEval},
+    modal::Strings,
+};
+
+#[must_use]
+pub fn empty() -> TokenStream2 {
+    quote! { ::syntaxfmt::GrammarExpr::Empty }
+}
+
+#[must_use]
+pub fn terminal(strs: &Strings) -> TokenStream2 {
+    // Grammar describes concrete syntax, so only normal mode literals are used
+    let normal = &strs[0];
+    quote! { ::syntaxfmt::GrammarExpr::terminal(#normal) }
+}
+
+#[must_use]
+pub fn special(text: &str) -> TokenStream2 {
+    quote! { ::syntaxfmt::GrammarExpr::Special(#text) }
+}
+
+#[must_use]
+pub fn seq(items: &[TokenStream2]) -> TokenStream2 {
+    quote! { ::syntaxfmt::GrammarExpr::seq([#(#items),*]) }
+}
+
+#[must_use]
+pub fn choice(items: &[TokenStream2]) -> TokenStream2 {
+    quote! { ::syntaxfmt::GrammarExpr::choice([#(#items),*]) }
+}
+
+#[must_use]
+pub fn optional(item: TokenStream2) -> TokenStream2 {
+    quote! { ::syntaxfmt::GrammarExpr::optional(#item) }
+}
+
+/// Interleaves items with a separator terminal, as `write_sep` does for fields
+#[must_use]
+pub fn separated(items: Vec<TokenStream2>, sep: &Strings) -> TokenStream2 {
+    let mut seq_items = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            seq_items.push(terminal(sep));
+        }
+        seq_items.push(item);
+    }
+    seq(&seq_items)
+}
+
+fn type_args(args: &PathArguments) -> impl Iterator<Item = &Type> {
+    let args = match args {
+        PathArguments::AngleBracketed(args) => Some(&args.args),
+        _ => None,
+    };
+    args.into_iter().flatten().filter_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+/// Describes the default formatting of a field type
+///
+/// Collections nested without an intermediate type share the separators of the enclosing
+/// context, so `sep` and `kv_sep` are passed down unchanged.
+#[must_use]
+pub fn type_grammar(ty: &Type, sep: &Strings, kv_sep: Option<&Strings>) -> TokenStream2 {
+    let inner_grammar = |ty| type_grammar(ty, sep, kv_sep);
+    match ty {
+        Type::Reference(r) => inner_grammar(&r.elem),
+        Type::Paren(p) => inner_grammar(&p.elem),
+        Type::Group(g) => inner_grammar(&g.elem),
+        Type::Slice(s) => repeat(inner_grammar(&s.elem), sep),
+        Type::Array(a) => repeat(inner_grammar(&a.elem), sep),
+        Type::Tuple(t) => separated(t.elems.iter().map(inner_grammar).collect(), sep),
+        Type::Path(p) if p.qself.is_none() => {
+            // Safety: Safe to unwrap because a parsed type path has at least one segment
+            let last = p.path.segments.last().unwrap();
+            let name = last.ident.to_string();
+            let mut type_args = type_args(&last.arguments);
+            match (name.as_str(), type_args.next(), type_args.next()) {
+                ("Option", Some(inner), _) => optional(inner_grammar(inner)),
+                ("Box" | "Rc" | "Arc" | "Cow", Some(inner), _) => inner_grammar(inner),
+                ("Vec" | "VecDeque" | "LinkedList" | "HashSet" | "BTreeSet", Some(inner), _) => {
+                    repeat(inner_grammar(inner), sep)
+                }
+                ("HashMap" | "BTreeMap", Some(key), Some(value)) => {
+                    let mut entry = vec![inner_grammar(key)];
+                    if let Some(kv_sep) = kv_sep {
+                        entry.push(terminal(kv_sep));
+                    }
+                    entry.push(inner_grammar(value));
+                    repeat(seq(&entry), sep)
+                }
+                ("PhantomData", _, _) => empty(),
+                _ => quote! { ::syntaxfmt::GrammarExpr::NonTerminal(#name) },
+            }
+        }
+        ty => special(&ty.to_token_stream().to_string()),
+    }
+}
+
+fn repeat(item: TokenStream2, sep: &Strings) -> TokenStream2 {
+    let sep = &sep[0];
+    quote! { ::syntaxfmt::GrammarExpr::repeat(#item, #sep) }
+}
+
+pub trait ToGrammarTokens {
+    fn to_grammar_tokens(&self, default_content: &dyn Fn(&CommonArgs) -> TokenStream2) -> TokenStream2;
+}
+
+impl<T> ToGrammarTokens for T
+where
+    T: WithCommon,
+{
+    fn to_grammar_tokens(&self, default_content: &dyn Fn(&CommonArgs) -> TokenStream2) -> TokenStream2 {
+        let common = self.common();
+
+        let mut items = Vec::new();
+        if let Some(prefix) = &common.prefix {
+            items.push(terminal(&prefix.0));
+        }
+        items.push(match &common.content {
+            // Sorting only reorders the default content
+            Some(Content::Sorted) | None => default_content(common),
+            Some(content) => content.to_grammar_tokens(),
+        });
+        if let Some(suffix) = &common.suffix {
+            items.push(terminal(&suffix.0));
+        }
+
+        seq(&items)
+    }
+}
+
+pub trait ToConditionalGrammarTokens {
+    fn to_conditional_grammar_tokens(
+        &self,
+        default_content: &dyn Fn(&CommonArgs) -> TokenStream2,
+    ) -> TokenStream2;
+}
+
+impl<T, N, E> ToConditionalGrammarTokens for T
+where
+    T: WithConditional<Normal = N, Else = E>,
+    N: ToGrammarTokens + WithEval,
+    E: ToGrammarTokens,
+{
+    fn to_conditional_grammar_tokens(
+        &self,
+        default_content: &dyn Fn(&CommonArgs) -> TokenStream2,
+    ) -> TokenStream2 {
+        let (args, args_else) = self.conditional();
+
+        let content = args.to_grammar_tokens(default_content);
+
+        match (args.eval(), args_else) {
+            (Some(_), Some(args_else)) => {
+                choice(&[content, args_else.to_grammar_tokens(default_content)])
+            }
+            (Some(_), None) => optional(content),
+            _ => content,
+        }
+    }
+}
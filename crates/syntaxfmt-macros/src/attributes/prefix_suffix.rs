@@ -1,5 +1,3 @@
-use proc_macro2::TokenStream as TokenStream2;
-use quote::{ToTokens, quote};
 use syn::{LitStr, Result as SynResult, punctuated::Punctuated, token::Comma};
 
 use crate::attributes::modal::Strings;
@@ -15,13 +13,6 @@ impl Prefix {
     }
 }
 
-impl ToTokens for Prefix {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let prefix = &self.0;
-        tokens.extend(quote! { f.write_strs(#prefix)?; });
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Suffix(pub Strings);
 
@@ -32,10 +23,3 @@ impl Suffix {
         Ok(Some(Self(Strings::from_litstrs(litstrs)?)))
     }
 }
-
-impl ToTokens for Suffix {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let suffix = &self.0;
-        tokens.extend(quote! { f.write_strs(#suffix)?; });
-    }
-}
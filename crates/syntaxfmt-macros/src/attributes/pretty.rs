@@ -1,7 +1,5 @@
 use std::ops::{BitOr, BitOrAssign};
 
-use proc_macro2::TokenStream as TokenStream2;
-use quote::{ToTokens, quote};
 use syn::{Ident, Result as SynResult, punctuated::Punctuated, token::Comma};
 
 use crate::syn_err;
@@ -48,30 +46,6 @@ impl Newlines {
     pub fn has(&self, nl: Self) -> bool {
         self.0 & nl.0 == nl.0
     }
-
-    #[must_use]
-    #[inline(always)]
-    pub fn into_beg(self) -> Option<WriteNewline> {
-        self.has(Self::BEG).then_some(WriteNewline)
-    }
-
-    #[must_use]
-    #[inline(always)]
-    pub fn into_pre(self) -> Option<WriteNewline> {
-        self.has(Self::PRE).then_some(WriteNewline)
-    }
-
-    #[must_use]
-    #[inline(always)]
-    pub fn into_cont(self) -> Option<WriteNewline> {
-        self.has(Self::CON).then_some(WriteNewline)
-    }
-
-    #[must_use]
-    #[inline(always)]
-    pub fn into_suf(self) -> Option<WriteNewline> {
-        self.has(Self::SUF).then_some(WriteNewline)
-    }
 }
 
 impl BitOr for Newlines {
@@ -87,12 +61,3 @@ impl BitOrAssign for Newlines {
         self.0 |= rhs.0;
     }
 }
-
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub struct WriteNewline;
-
-impl ToTokens for WriteNewline {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        tokens.extend(quote! { f.write_newline()?; });
-    }
-}
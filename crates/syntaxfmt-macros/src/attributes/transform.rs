@@ -0,0 +1,102 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ToTokens, quote};
+use syn::{Expr, ExprClosure, Result as SynResult};
+
+use crate::syn_err;
+
+#[derive(Debug, Clone)]
+pub enum Sort {
+    Ord,
+    By(Expr),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Transforms {
+    pub sort: Option<Sort>,
+    pub dedup: bool,
+    pub rev: bool,
+    pub filter: Option<ExprClosure>,
+}
+
+impl Transforms {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sort.is_none() && !self.dedup && !self.rev && self.filter.is_none()
+    }
+
+    pub fn set_filter(&mut self, closure: ExprClosure) -> SynResult<()> {
+        if !(1..=2).contains(&closure.inputs.len()) {
+            return syn_err(
+                closure,
+                "syntaxfmt `filter` closure must take an item, or an item and state",
+            );
+        }
+        self.filter = Some(closure);
+        Ok(())
+    }
+
+    // Applied in order filter, sort, dedup, rev. Items are only collected when sorting or
+    // deduplicating, otherwise the iterator is formatted lazily. Filters which take state are
+    // applied while collecting, with state borrowed until it's done, and otherwise lazily after
+    // reversing, which gives the same items since each is tested on its own
+    pub fn to_tokens(&self, field: &impl ToTokens) -> TokenStream2 {
+        let collect = self.sort.is_some() || self.dedup;
+        let state_filter = self.filter.as_ref().filter(|c| c.inputs.len() == 2);
+
+        let mut tokens = quote! {
+            let __syntaxfmt_items = ::core::iter::IntoIterator::into_iter(#field);
+        };
+        match (&self.filter, state_filter) {
+            (Some(closure), None) => tokens.extend(quote! {
+                let __syntaxfmt_items = __syntaxfmt_items.filter(#closure);
+            }),
+            // The closure's parameters are bound by `filter` so their types are inferred
+            (_, Some(closure)) if collect => {
+                let (item, state, body) = (&closure.inputs[0], &closure.inputs[1], &closure.body);
+                tokens.extend(quote! {
+                    let __syntaxfmt_state = f.try_state()?;
+                    let __syntaxfmt_items = __syntaxfmt_items.filter(|#item| {
+                        let #state = &*__syntaxfmt_state;
+                        #body
+                    });
+                });
+            }
+            _ => {}
+        }
+
+        if collect {
+            tokens.extend(quote! {
+                let mut __syntaxfmt_items: ::std::vec::Vec<_> = __syntaxfmt_items.collect();
+            });
+            if state_filter.is_some() {
+                tokens.extend(quote! { ::core::mem::drop(__syntaxfmt_state); });
+            }
+            match &self.sort {
+                Some(Sort::Ord) => tokens.extend(quote! { __syntaxfmt_items.sort(); }),
+                Some(Sort::By(compare)) => tokens.extend(quote! {
+                    __syntaxfmt_items.sort_by(|a, b| (#compare)(*a, *b));
+                }),
+                None => {}
+            }
+            if self.dedup {
+                tokens.extend(quote! { __syntaxfmt_items.dedup(); });
+            }
+            if self.rev {
+                tokens.extend(quote! { __syntaxfmt_items.reverse(); });
+            }
+        } else if self.rev {
+            tokens.extend(quote! { let __syntaxfmt_items = __syntaxfmt_items.rev(); });
+        }
+
+        match state_filter.filter(|_| !collect) {
+            Some(closure) => tokens.extend(quote! {
+                ::syntaxfmt::iter(__syntaxfmt_items).filter_state(#closure).syntax_fmt(f)?;
+            }),
+            None => tokens.extend(quote! {
+                ::syntaxfmt::iter(__syntaxfmt_items).syntax_fmt(f)?;
+            }),
+        }
+
+        quote! { { #tokens } }
+    }
+}
@@ -1,11 +1,11 @@
 use proc_macro2::TokenStream as TokenStream2;
 use quote::ToTokens;
 use syn::{
-    Attribute, Expr, ExprClosure, Ident, LitStr, Result as SynResult, TypePath, TypeTraitObject,
-    bracketed,
+    Attribute, Expr, ExprClosure, Ident, Lit, LitInt, LitStr, Meta, Path, Result as SynResult, TypePath, TypeTraitObject,
+    WherePredicate, bracketed, parenthesized,
     parse::{Parse, ParseStream},
     punctuated::Punctuated,
-    token::{Comma, Eq as SynEq, Or as SynOr},
+    token::{Comma, Dot, Eq as SynEq, Minus, Or as SynOr, Paren},
 };
 
 use crate::syn_err;
@@ -14,18 +14,38 @@ use crate::syn_err;
 pub enum UnverifiedArgKind {
     Indent(Ident),
     Skip(Ident),
+    Transparent(Ident),
+    StateMut(Ident),
+    Elide(Lit),
+    Flatten(Ident),
     Newlines(Punctuated<Ident, Comma>),
     Prefix(Punctuated<LitStr, Comma>),
     Suffix(Punctuated<LitStr, Comma>),
     Seps(Punctuated<LitStr, Comma>),
+    FieldSeps(Punctuated<LitStr, Comma>),
+    KvSeps(Punctuated<LitStr, Comma>),
+    LastSeps(Punctuated<LitStr, Comma>),
+    Order(Option<Minus>, LitInt),
     Eval(Expr),
     EvalTypePath(TypePath),
     EvalClosure(ExprClosure),
     Content(Expr),
     ContentTypePath(TypePath),
     ContentClosure(ExprClosure),
+    ContentQuote(Ident),
+    ContentIdent(Path),
+    ContentNum(Punctuated<Meta, Comma>),
+    ContentSorted(Ident),
+    ContentIter(Option<Dot>, Expr),
+    Sort(Ident),
+    SortBy(Expr),
+    Dedup(Ident),
+    Rev(Ident),
+    Filter(ExprClosure),
+    Scope(Expr),
     State(TypePath),
     StateBound(TypeTraitObject),
+    Bounds(Punctuated<WherePredicate, Comma>),
 }
 
 impl ToTokens for UnverifiedArgKind {
@@ -33,18 +53,44 @@ impl ToTokens for UnverifiedArgKind {
         match self {
             Self::Indent(i) => i.to_tokens(tokens),
             Self::Skip(i) => i.to_tokens(tokens),
+            Self::Transparent(i) => i.to_tokens(tokens),
+            Self::StateMut(i) => i.to_tokens(tokens),
+            Self::Elide(i) => i.to_tokens(tokens),
+            Self::Flatten(i) => i.to_tokens(tokens),
             Self::Newlines(i) => i.to_tokens(tokens),
             Self::Prefix(i) => i.to_tokens(tokens),
             Self::Suffix(i) => i.to_tokens(tokens),
             Self::Seps(i) => i.to_tokens(tokens),
+            Self::FieldSeps(i) => i.to_tokens(tokens),
+            Self::KvSeps(i) => i.to_tokens(tokens),
+            Self::LastSeps(i) => i.to_tokens(tokens),
+            Self::Order(m, i) => {
+                m.to_tokens(tokens);
+                i.to_tokens(tokens);
+            }
             Self::Eval(i) => i.to_tokens(tokens),
             Self::EvalTypePath(i) => i.to_tokens(tokens),
             Self::EvalClosure(i) => i.to_tokens(tokens),
             Self::Content(i) => i.to_tokens(tokens),
             Self::ContentTypePath(i) => i.to_tokens(tokens),
             Self::ContentClosure(i) => i.to_tokens(tokens),
+            Self::ContentQuote(i) => i.to_tokens(tokens),
+            Self::ContentIdent(i) => i.to_tokens(tokens),
+            Self::ContentNum(i) => i.to_tokens(tokens),
+            Self::ContentSorted(i) => i.to_tokens(tokens),
+            Self::Sort(i) => i.to_tokens(tokens),
+            Self::SortBy(i) => i.to_tokens(tokens),
+            Self::Dedup(i) => i.to_tokens(tokens),
+            Self::Rev(i) => i.to_tokens(tokens),
+            Self::Filter(i) => i.to_tokens(tokens),
+            Self::Scope(i) => i.to_tokens(tokens),
+            Self::ContentIter(d, i) => {
+                d.to_tokens(tokens);
+                i.to_tokens(tokens);
+            }
             Self::State(i) => i.to_tokens(tokens),
             Self::StateBound(i) => i.to_tokens(tokens),
+            Self::Bounds(i) => i.to_tokens(tokens),
         }
     }
 }
@@ -86,8 +132,8 @@ impl UnverifiedArgs {
         }
     }
 
-    pub fn collect_args<'a>(
-        input: &'a [Attribute],
+    pub fn collect_args(
+        input: &[Attribute],
     ) -> SynResult<(UnverifiedArgs, Option<UnverifiedArgs>)> {
         let mut args = Vec::new();
         let mut args_else = Vec::new();
@@ -141,6 +187,28 @@ impl Parse for UnverifiedArgs {
                     ident: ident.clone(),
                     kind: UnverifiedArgKind::Skip(ident),
                 });
+            } else if ident == "transparent" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::Transparent(ident),
+                });
+            } else if ident == "state_mut" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::StateMut(ident),
+                });
+            } else if ident == "elide" {
+                input.parse::<SynEq>()?;
+                let elide = input.parse::<Lit>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::Elide(elide),
+                });
+            } else if ident == "flatten" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::Flatten(ident),
+                });
             } else if ident == "nl" {
                 if input.peek(SynEq) {
                     input.parse::<SynEq>()?;
@@ -176,6 +244,86 @@ impl Parse for UnverifiedArgs {
                     ident,
                     kind: UnverifiedArgKind::Seps(strs),
                 });
+            } else if ident == "field_sep" {
+                input.parse::<SynEq>()?;
+                let strs = Self::parse_litstrs(&mut input)?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::FieldSeps(strs),
+                });
+            } else if ident == "last_sep" {
+                input.parse::<SynEq>()?;
+                let strs = Self::parse_litstrs(&mut input)?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::LastSeps(strs),
+                });
+            } else if ident == "kv_sep" {
+                input.parse::<SynEq>()?;
+                let strs = Self::parse_litstrs(&mut input)?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::KvSeps(strs),
+                });
+            } else if ident == "iter" {
+                input.parse::<SynEq>()?;
+                // A leading `.` continues from the field, or from `self` at type level
+                let dot = input.parse::<Option<Dot>>()?;
+                let expr = input.parse::<Expr>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::ContentIter(dot, expr),
+                });
+            } else if ident == "sort" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::Sort(ident),
+                });
+            } else if ident == "sort_by" {
+                input.parse::<SynEq>()?;
+                let compare = input.parse::<Expr>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::SortBy(compare),
+                });
+            } else if ident == "dedup" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::Dedup(ident),
+                });
+            } else if ident == "rev" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::Rev(ident),
+                });
+            } else if ident == "filter" {
+                input.parse::<SynEq>()?;
+                let closure = input.parse::<ExprClosure>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::Filter(closure),
+                });
+            } else if ident == "scope" {
+                input.parse::<SynEq>()?;
+                let expr = input.parse::<Expr>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::Scope(expr),
+                });
+            } else if ident == "sorted" {
+                attrs.push(UnverifiedArg {
+                    ident: ident.clone(),
+                    kind: UnverifiedArgKind::ContentSorted(ident),
+                });
+            } else if ident == "order" {
+                input.parse::<SynEq>()?;
+                // Negative orders are a minus token followed by the literal
+                let minus = input.parse::<Option<Minus>>()?;
+                let order = input.parse::<LitInt>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::Order(minus, order),
+                });
             } else if ident == "delim" {
                 return syn_err(&ident, "syntaxfmt `delim` has been replaced by `sep`. Please replace your references. Usage is the same.");
             } else if ident == "eval" {
@@ -222,20 +370,63 @@ impl Parse for UnverifiedArgs {
                         kind: UnverifiedArgKind::ContentTypePath(type_path),
                     });
                 }
-            } else if ident == "state" {
+            } else if ident == "quote" {
                 input.parse::<SynEq>()?;
-                let type_path = input.parse::<TypePath>()?;
+                let style = input.parse::<Ident>()?;
                 attrs.push(UnverifiedArg {
                     ident,
-                    kind: UnverifiedArgKind::State(type_path),
+                    kind: UnverifiedArgKind::ContentQuote(style),
                 });
-            } else if ident == "bound" {
+            } else if ident == "ident" {
                 input.parse::<SynEq>()?;
-                let trait_obj = input.parse::<TypeTraitObject>()?;
+                let words = input.parse::<Path>()?;
                 attrs.push(UnverifiedArg {
                     ident,
-                    kind: UnverifiedArgKind::StateBound(trait_obj),
+                    kind: UnverifiedArgKind::ContentIdent(words),
                 });
+            } else if ident == "num" {
+                let content;
+                parenthesized!(content in input);
+                let num_args = content.parse_terminated(Meta::parse, Comma)?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::ContentNum(num_args),
+                });
+            } else if ident == "state" {
+                input.parse::<SynEq>()?;
+                let type_path = input.parse::<TypePath>()?;
+                attrs.push(UnverifiedArg {
+                    ident,
+                    kind: UnverifiedArgKind::State(type_path),
+                });
+            } else if ident == "bound" {
+                if input.peek(Paren) {
+                    let content;
+                    parenthesized!(content in input);
+                    let predicates = content.parse_terminated(WherePredicate::parse, Comma)?;
+                    attrs.push(UnverifiedArg {
+                        ident,
+                        kind: UnverifiedArgKind::Bounds(predicates),
+                    });
+                } else {
+                    input.parse::<SynEq>()?;
+                    if input.peek(LitStr) {
+                        let litstr = input.parse::<LitStr>()?;
+                        let predicates = litstr.parse_with(
+                            Punctuated::<WherePredicate, Comma>::parse_terminated,
+                        )?;
+                        attrs.push(UnverifiedArg {
+                            ident,
+                            kind: UnverifiedArgKind::Bounds(predicates),
+                        });
+                    } else {
+                        let trait_obj = input.parse::<TypeTraitObject>()?;
+                        attrs.push(UnverifiedArg {
+                            ident,
+                            kind: UnverifiedArgKind::StateBound(trait_obj),
+                        });
+                    }
+                }
             } else {
                 return syn_err(ident, "syntaxfmt unexpected attribute argument");
             }
@@ -1,3 +1,4 @@
+pub mod bounds;
 pub mod fields;
 pub mod ty;
 pub mod variants;
@@ -0,0 +1,169 @@
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{
+    GenericArgument, GenericParam, Generics, Ident, PathArguments, ReturnType, Type, TypePath,
+    WherePredicate, parse_quote, punctuated::Punctuated, token::Comma,
+};
+
+use crate::{
+    attributes::{
+        args::FieldArgs,
+        content::{Skipped, WithConditional},
+    },
+    intermediate::fields::is_phantom_data,
+};
+
+/// Infers `SyntaxFmt` bounds for the type parameters used by formatted fields
+///
+/// Bounds are placed on type parameters rather than whole field types, because field type bounds
+/// overflow trait resolution for recursive types. Associated types of type parameters, such as
+/// `T::Item` or `<T as Trait>::Item`, are bounded themselves rather than their parameter.
+#[derive(Debug, Clone)]
+pub struct InferredBounds<'a> {
+    params: Vec<&'a Ident>,
+    used: Vec<&'a Ident>,
+    associated: Vec<TypePath>,
+    custom: Vec<WherePredicate>,
+}
+
+impl<'a> InferredBounds<'a> {
+    pub fn new(generics: &'a Generics) -> Self {
+        let params = generics
+            .params
+            .iter()
+            .filter_map(|param| {
+                if let GenericParam::Type(ty_param) = param {
+                    Some(&ty_param.ident)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Self {
+            params,
+            used: Vec::new(),
+            associated: Vec::new(),
+            custom: Vec::new(),
+        }
+    }
+
+    pub fn add_custom(&mut self, bounds: &Punctuated<WherePredicate, Comma>) {
+        self.custom.extend(bounds.iter().cloned());
+    }
+
+    /// Adds bounds for a field, returning false if the field's own types shouldn't be visited
+    pub fn add_args(&mut self, args: &FieldArgs) -> bool {
+        if args.skipped() {
+            return false;
+        }
+        if let Some(bounds) = &args.args.bounds {
+            self.add_custom(bounds);
+            return false;
+        }
+        !args.replaces_content()
+    }
+
+    pub fn add_field(&mut self, args: &FieldArgs, ty: &Type) {
+        if self.add_args(args) {
+            self.add_type(ty);
+        }
+    }
+
+    fn add_param(&mut self, ident: &Ident) {
+        if let Some(param) = self.params.iter().find(|p| *p == &ident) {
+            if !self.used.contains(param) {
+                self.used.push(param);
+            }
+        }
+    }
+
+    fn is_param(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Path(p) if p.qself.is_none() => {
+                p.path.get_ident().is_some_and(|ident| self.params.contains(&ident))
+            }
+            Type::Group(g) => self.is_param(&g.elem),
+            Type::Paren(p) => self.is_param(&p.elem),
+            _ => false,
+        }
+    }
+
+    // Returns true for `T::Item` or `<T as Trait>::Item` where `T` is a type parameter
+    fn is_associated(&self, p: &TypePath) -> bool {
+        match &p.qself {
+            Some(qself) => self.is_param(&qself.ty),
+            None => {
+                p.path.leading_colon.is_none()
+                    && p.path.segments.len() > 1
+                    && self.params.contains(&&p.path.segments[0].ident)
+            }
+        }
+    }
+
+    fn add_associated(&mut self, p: &TypePath) {
+        if !self.associated.contains(p) {
+            self.associated.push(p.clone());
+        }
+    }
+
+    fn add_path_arguments(&mut self, args: &PathArguments) {
+        match args {
+            PathArguments::AngleBracketed(args) => {
+                for arg in &args.args {
+                    if let GenericArgument::Type(ty) = arg {
+                        self.add_type(ty);
+                    }
+                }
+            }
+            PathArguments::Parenthesized(args) => {
+                args.inputs.iter().for_each(|ty| self.add_type(ty));
+                if let ReturnType::Type(_, ty) = &args.output {
+                    self.add_type(ty);
+                }
+            }
+            PathArguments::None => {}
+        }
+    }
+
+    fn add_type(&mut self, ty: &Type) {
+        match ty {
+            Type::Reference(r) => self.add_type(&r.elem),
+            Type::Paren(p) => self.add_type(&p.elem),
+            Type::Group(g) => self.add_type(&g.elem),
+            Type::Ptr(p) => self.add_type(&p.elem),
+            Type::Slice(s) => self.add_type(&s.elem),
+            Type::Array(a) => self.add_type(&a.elem),
+            Type::Tuple(t) => t.elems.iter().for_each(|e| self.add_type(e)),
+            Type::Path(p) if self.is_associated(p) => self.add_associated(p),
+            Type::Path(p) => {
+                if let Some(qself) = &p.qself {
+                    self.add_type(&qself.ty);
+                } else if let Some(ident) = p.path.get_ident() {
+                    self.add_param(ident);
+                }
+
+                // PhantomData formats nothing, so its parameters don't need bounds
+                if !is_phantom_data(ty) {
+                    for segment in &p.path.segments {
+                        self.add_path_arguments(&segment.arguments);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn predicates(&self, state: &TokenStream2, access: &TokenStream2) -> Vec<WherePredicate> {
+        let mut predicates: Vec<WherePredicate> = self
+            .used
+            .iter()
+            .map(|param| parse_quote! { #param: ::syntaxfmt::SyntaxFmt<#state, #access> })
+            .collect();
+        predicates.extend(
+            self.associated
+                .iter()
+                .map(|ty| parse_quote! { #ty: ::syntaxfmt::SyntaxFmt<#state, #access> }),
+        );
+        predicates.extend(self.custom.iter().cloned());
+        predicates
+    }
+}
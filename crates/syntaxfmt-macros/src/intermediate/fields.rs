@@ -1,26 +1,33 @@
 use proc_macro2::TokenStream as TokenStream2;
 use quote::{ToTokens, quote};
 use syn::{
-    Field, Fields, FieldsNamed, FieldsUnnamed, Ident, Result as SynResult,
+    ext::IdentExt, Field, Fields, FieldsNamed, FieldsUnnamed, Ident, Result as SynResult, Type,
     punctuated::Punctuated, spanned::Spanned, token::Comma,
 };
 
-use crate::attributes::{
-    args::{CommonArgs, FieldArgs},
-    content::{Content, Skipped, ToConditionalTokens}, context::FieldKind,
+use crate::{
+    attributes::{
+        args::{CommonArgs, FieldArgs},
+        content::{Content, Skipped, ToConditionalTokens},
+        context::FieldKind,
+        grammar::{ToConditionalGrammarTokens, empty, separated, type_grammar},
+        modal::Strings,
+    },
+    intermediate::bounds::InferredBounds,
 };
 
 #[derive(Debug, Clone)]
 pub struct SyntaxFieldNamed {
     pub args: FieldArgs,
     pub name: Ident,
+    pub ty: Type,
 }
 
 impl SyntaxFieldNamed {
     pub fn from_field(parent_common: &CommonArgs, input: &Field) -> SynResult<Self> {
         let name = input.ident.clone().unwrap();
         let args = FieldArgs::new(FieldKind::Field(name.clone()), parent_common, &input.attrs)?;
-        Ok(Self { args, name })
+        Ok(Self { args, name, ty: input.ty.clone() })
     }
 
     pub fn decl(&self) -> Ident {
@@ -30,6 +37,13 @@ impl SyntaxFieldNamed {
             self.name.clone()
         }
     }
+
+    pub fn grammar_tokens(&self) -> Option<TokenStream2> {
+        (!self.args.skipped()).then(|| {
+            self.args
+                .to_conditional_grammar_tokens(&|common| type_grammar(&self.ty, &common.seps, common.kv_sep.as_ref()))
+        })
+    }
 }
 
 impl ToTokens for SyntaxFieldNamed {
@@ -40,11 +54,15 @@ impl ToTokens for SyntaxFieldNamed {
 
         let name = &self.name;
 
-        let default_content = Content::Tokens(quote! { #name.syntax_fmt(f)?; });
-
-        let content = self.args.to_conditional_tokens(&default_content);
+        // Flattened fields join the parent's context, so they don't push their own
+        let content = if self.args.args.flatten {
+            quote! { #name.syntax_fmt_flat(f)?; }
+        } else {
+            let default_content = Content::Tokens(quote! { #name.syntax_fmt(f)?; });
+            self.args.to_conditional_tokens(&default_content)
+        };
 
-        tokens.extend(content);
+        tokens.extend(field_tokens(self.name.unraw().to_string(), content));
     }
 }
 
@@ -52,6 +70,7 @@ impl ToTokens for SyntaxFieldNamed {
 pub struct SyntaxFieldUnnamed {
     pub args: FieldArgs,
     pub name: Ident,
+    pub ty: Type,
 }
 
 impl SyntaxFieldUnnamed {
@@ -60,12 +79,20 @@ impl SyntaxFieldUnnamed {
         Ok(Self {
             args,
             name,
+            ty: input.ty.clone(),
         })
     }
 
     pub fn decl(&self) -> &Ident {
         &self.name
     }
+
+    pub fn grammar_tokens(&self) -> Option<TokenStream2> {
+        (!self.args.skipped()).then(|| {
+            self.args
+                .to_conditional_grammar_tokens(&|common| type_grammar(&self.ty, &common.seps, common.kv_sep.as_ref()))
+        })
+    }
 }
 
 impl ToTokens for SyntaxFieldUnnamed {
@@ -76,26 +103,39 @@ impl ToTokens for SyntaxFieldUnnamed {
 
         let name = &self.name;
 
-        let default_content = Content::Tokens(quote! { #name.syntax_fmt(f)?; });
-
-        let content = self.args.to_conditional_tokens(&default_content);
+        // Flattened fields join the parent's context, so they don't push their own
+        let content = if self.args.args.flatten {
+            quote! { #name.syntax_fmt_flat(f)?; }
+        } else {
+            let default_content = Content::Tokens(quote! { #name.syntax_fmt(f)?; });
+            self.args.to_conditional_tokens(&default_content)
+        };
 
-        tokens.extend(content);
+        tokens.extend(field_tokens(self.name.to_string().trim_start_matches('_').to_string(), content));
     }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct SyntaxFieldsNamed {
     pub fields: Vec<SyntaxFieldNamed>,
+    pub field_sep: Option<Strings>,
 }
 
 impl SyntaxFieldsNamed {
-    fn from_fields_named(parent_common: &CommonArgs, input: &FieldsNamed) -> SynResult<Self> {
+    fn from_fields_named(
+        parent_common: &CommonArgs,
+        field_sep: Option<Strings>,
+        input: &FieldsNamed,
+    ) -> SynResult<Self> {
         let mut fields = Vec::new();
         for field in &input.named {
             fields.push(SyntaxFieldNamed::from_field(parent_common, field)?);
         }
-        Ok(Self { fields })
+        Ok(Self { fields, field_sep })
+    }
+
+    pub fn ordered(&self) -> Vec<&SyntaxFieldNamed> {
+        ordered(&self.fields, |f| &f.args)
     }
 
     pub fn decl(&self) -> Punctuated<Ident, Comma> {
@@ -107,36 +147,57 @@ impl SyntaxFieldsNamed {
         }
         decls
     }
-}
 
-impl ToTokens for SyntaxFieldsNamed {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let mut i = 0;
+    pub fn grammar_tokens(&self, sep: &Strings) -> TokenStream2 {
+        let sep = self.field_sep.as_ref().unwrap_or(sep);
+        separated(self.ordered().into_iter().filter_map(|f| f.grammar_tokens()).collect(), sep)
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
         for field in &self.fields {
-            if !field.args.skipped() {
-                if i > 0 {
-                    tokens.extend(quote! { f.write_sep()?; });
-                }
-                i += 1;
+            bounds.add_field(&field.args, &field.ty);
+        }
+    }
+
+    pub fn make_transparent(&mut self) -> usize {
+        for field in &mut self.fields {
+            if is_phantom_data(&field.ty) {
+                field.args.set_skipped();
             }
-            field.to_tokens(tokens);
         }
+        self.fields.iter().filter(|f| !f.args.skipped()).count()
+    }
+}
+
+impl ToTokens for SyntaxFieldsNamed {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let fields = self.ordered().into_iter().map(|f| (&f.args, f.to_token_stream()));
+        tokens.extend(fields_tokens(fields, &self.field_sep));
     }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct SyntaxFieldsUnnamed {
     pub fields: Vec<SyntaxFieldUnnamed>,
+    pub field_sep: Option<Strings>,
 }
 
 impl SyntaxFieldsUnnamed {
-    pub fn from_fields_unnamed(parent_common: &CommonArgs, input: &FieldsUnnamed) -> SynResult<Self> {
+    pub fn from_fields_unnamed(
+        parent_common: &CommonArgs,
+        field_sep: Option<Strings>,
+        input: &FieldsUnnamed,
+    ) -> SynResult<Self> {
         let mut fields = Vec::new();
         for (i, field) in input.unnamed.iter().enumerate() {
             let name = Ident::new(&format!("_{i}"), field.span());
             fields.push(SyntaxFieldUnnamed::from_field(parent_common, name, field)?);
         }
-        Ok(Self { fields })
+        Ok(Self { fields, field_sep })
+    }
+
+    pub fn ordered(&self) -> Vec<&SyntaxFieldUnnamed> {
+        ordered(&self.fields, |f| &f.args)
     }
 
     pub fn decl(&self) -> Punctuated<Ident, Comma> {
@@ -146,20 +207,32 @@ impl SyntaxFieldsUnnamed {
         }
         decls
     }
-}
 
-impl ToTokens for SyntaxFieldsUnnamed {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let mut i = 0;
+    pub fn grammar_tokens(&self, sep: &Strings) -> TokenStream2 {
+        let sep = self.field_sep.as_ref().unwrap_or(sep);
+        separated(self.ordered().into_iter().filter_map(|f| f.grammar_tokens()).collect(), sep)
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
         for field in &self.fields {
-            if !field.args.skipped() {
-                if i > 0 {
-                    tokens.extend(quote! { f.write_sep()?; });
-                }
-                i += 1;
+            bounds.add_field(&field.args, &field.ty);
+        }
+    }
+
+    pub fn make_transparent(&mut self) -> usize {
+        for field in &mut self.fields {
+            if is_phantom_data(&field.ty) {
+                field.args.set_skipped();
             }
-            field.to_tokens(tokens);
         }
+        self.fields.iter().filter(|f| !f.args.skipped()).count()
+    }
+}
+
+impl ToTokens for SyntaxFieldsUnnamed {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let fields = self.ordered().into_iter().map(|f| (&f.args, f.to_token_stream()));
+        tokens.extend(fields_tokens(fields, &self.field_sep));
     }
 }
 
@@ -196,14 +269,19 @@ pub enum SyntaxFields {
 }
 
 impl SyntaxFields {
-    pub fn from_fields(parent_common: &CommonArgs, input: &Fields) -> SynResult<Self> {
+    pub fn from_fields(
+        parent_common: &CommonArgs,
+        field_sep: Option<Strings>,
+        input: &Fields,
+    ) -> SynResult<Self> {
         match &input {
             Fields::Named(fields_named) => Ok(Self::Named(SyntaxFieldsNamed::from_fields_named(
                 parent_common,
+                field_sep,
                 fields_named,
             )?)),
             Fields::Unnamed(fields_unnamed) => Ok(Self::Unnamed(
-                SyntaxFieldsUnnamed::from_fields_unnamed(parent_common, fields_unnamed)?,
+                SyntaxFieldsUnnamed::from_fields_unnamed(parent_common, field_sep, fields_unnamed)?,
             )),
             Fields::Unit => Ok(Self::Unit),
         }
@@ -216,6 +294,127 @@ impl SyntaxFields {
             SyntaxFields::Unit => SyntaxFieldsDecl::Unit,
         }
     }
+
+    pub fn grammar_tokens(&self, sep: &Strings) -> TokenStream2 {
+        match self {
+            SyntaxFields::Named(inner) => inner.grammar_tokens(sep),
+            SyntaxFields::Unnamed(inner) => inner.grammar_tokens(sep),
+            SyntaxFields::Unit => empty(),
+        }
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
+        match self {
+            SyntaxFields::Named(inner) => inner.infer_bounds(bounds),
+            SyntaxFields::Unnamed(inner) => inner.infer_bounds(bounds),
+            SyntaxFields::Unit => {}
+        }
+    }
+
+    /// Skips `PhantomData` fields, returning the number of fields left to format
+    pub fn make_transparent(&mut self) -> usize {
+        match self {
+            SyntaxFields::Named(inner) => inner.make_transparent(),
+            SyntaxFields::Unnamed(inner) => inner.make_transparent(),
+            SyntaxFields::Unit => 0,
+        }
+    }
+}
+
+// Orders fields by their `order` argument, with unordered fields keyed by declaration index
+fn ordered<T>(fields: &[T], args: impl Fn(&T) -> &FieldArgs) -> Vec<&T> {
+    let mut ordered: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (args(f).args.order.unwrap_or(i as i64), f))
+        .collect();
+    ordered.sort_by_key(|(order, _)| *order);
+    ordered.into_iter().map(|(_, f)| f).collect()
+}
+
+// Errors are given the field's name as they propagate, and the width is checked before each field
+fn field_tokens(name: String, content: TokenStream2) -> TokenStream2 {
+    quote! {
+        f.field(#name, |f| {
+            #content
+            Ok(())
+        })?;
+    }
+}
+
+fn fields_tokens<'a>(
+    fields: impl Iterator<Item = (&'a FieldArgs, TokenStream2)>,
+    field_sep: &Option<Strings>,
+) -> TokenStream2 {
+    let mut tokens = TokenStream2::new();
+    match field_sep {
+        // Context separator is written between every pair of formatted fields. Flattened fields
+        // may write nothing, so from the first one on the separator is deferred until something
+        // is written, and only if something was written before it
+        None => {
+            let mut i = 0;
+            let mut flat = false;
+            for (args, field) in fields {
+                if !args.skipped() {
+                    flat |= args.args.flatten;
+                    if flat && i > 0 {
+                        tokens.extend(quote! {
+                            if f.written_len() != __syntaxfmt_start {
+                                f.defer_context_sep();
+                            }
+                        });
+                    } else if i > 0 {
+                        tokens.extend(quote! { f.write_sep()?; });
+                    }
+                    i += 1;
+                }
+                tokens.extend(field);
+            }
+            if flat {
+                tokens = quote! {
+                    let __syntaxfmt_start = f.written_len();
+                    #tokens
+                    if f.written_len() != __syntaxfmt_start {
+                        f.clear_deferred_sep();
+                    }
+                };
+            }
+        }
+        // Field separator is deferred until the next field writes something, so it only
+        // appears between non-empty fields
+        Some(field_sep) => {
+            for (args, field) in fields {
+                if !args.skipped() {
+                    tokens.extend(quote! {
+                        let __syntaxfmt_mark = f.written_len();
+                        #field
+                        if f.written_len() != __syntaxfmt_mark {
+                            f.defer_sep(#field_sep);
+                        }
+                    });
+                }
+            }
+            tokens = quote! {
+                let __syntaxfmt_start = f.written_len();
+                #tokens
+                if f.written_len() != __syntaxfmt_start {
+                    f.clear_deferred_sep();
+                }
+            };
+        }
+    }
+    tokens
+}
+
+pub fn is_phantom_data(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "PhantomData"),
+        _ => false,
+    }
 }
 
 impl ToTokens for SyntaxFields {
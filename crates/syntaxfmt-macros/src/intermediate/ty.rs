@@ -4,15 +4,17 @@ use proc_macro2::TokenStream as TokenStream2;
 use quote::{ToTokens, quote};
 use syn::{
     Data, DeriveInput, GenericParam, Generics, Ident, LifetimeParam, Result as SynResult,
-    WhereClause, parse_quote, punctuated::Punctuated, token::Where,
+    WhereClause, ext::IdentExt, parse_quote, punctuated::Punctuated, token::Where,
 };
 
 use crate::{
     attributes::{
-        args::{CommonArgs, TypeArgs},
-        content::{Content, Skipped, ToConditionalTokens},
+        args::{Elide, TypeArgs},
+        content::{Content, Skipped, ToConditionalTokens, WithConditional},
+        grammar::{ToConditionalGrammarTokens, empty},
+        modal::Strings,
     },
-    intermediate::{fields::SyntaxFields, variants::SyntaxVariants},
+    intermediate::{bounds::InferredBounds, fields::SyntaxFields, variants::SyntaxVariants},
     syn_err,
 };
 
@@ -20,25 +22,48 @@ use crate::{
 pub enum SyntaxTypeKind {
     Struct(SyntaxFields),
     Enum(SyntaxVariants),
+    // Unions are only formatted through type-level content, so carry no fields
+    Union,
 }
 
 impl SyntaxTypeKind {
-    pub fn from_data(parent_common: &CommonArgs, input: &Data) -> SynResult<Self> {
+    pub fn from_data(args: &TypeArgs, input: &Data) -> SynResult<Self> {
+        let parent_common = &args.args.common;
         match input {
             Data::Struct(data_struct) => Ok(Self::Struct(SyntaxFields::from_fields(
                 parent_common,
+                args.args.field_sep.clone(),
                 &data_struct.fields,
             )?)),
             Data::Enum(data_enum) => Ok(Self::Enum(SyntaxVariants::from_variants(
                 parent_common,
+                &args.args.field_sep,
                 &data_enum.variants,
             )?)),
+            // The active field of a union can't be known, so it must be formatted by the user
+            Data::Union(_) if args.replaces_content() => Ok(Self::Union),
             Data::Union(data_union) => syn_err(
                 data_union.union_token,
-                "syntaxfmt cannot be derived for unions",
+                "syntaxfmt unions require a type-level `cont` or `cont_with` argument",
             ),
         }
     }
+
+    pub fn grammar_tokens(&self, sep: &Strings) -> TokenStream2 {
+        match self {
+            Self::Struct(inner) => inner.grammar_tokens(sep),
+            Self::Enum(inner) => inner.grammar_tokens(),
+            Self::Union => empty(),
+        }
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
+        match self {
+            Self::Struct(inner) => inner.infer_bounds(bounds),
+            Self::Enum(inner) => inner.infer_bounds(bounds),
+            Self::Union => {}
+        }
+    }
 }
 
 impl ToTokens for SyntaxTypeKind {
@@ -58,6 +83,7 @@ impl ToTokens for SyntaxTypeKind {
                     }
                 });
             }
+            Self::Union => {}
         }
     }
 }
@@ -73,7 +99,26 @@ pub struct SyntaxType<'a> {
 impl<'a> SyntaxType<'a> {
     pub fn from_derive_input(input: &'a DeriveInput) -> SynResult<Self> {
         let args = TypeArgs::from_attributes(&input.attrs)?;
-        let kind = SyntaxTypeKind::from_data(&args.args.common, &input.data)?;
+        let mut kind = SyntaxTypeKind::from_data(&args, &input.data)?;
+
+        if args.args.transparent {
+            match &mut kind {
+                SyntaxTypeKind::Struct(fields) => {
+                    if fields.make_transparent() != 1 {
+                        return syn_err(
+                            &input.ident,
+                            "syntaxfmt `transparent` requires exactly one non-skipped field",
+                        );
+                    }
+                }
+                _ => {
+                    return syn_err(
+                        &input.ident,
+                        "syntaxfmt `transparent` can only be applied to structs",
+                    );
+                }
+            }
+        }
 
         Ok(Self {
             args,
@@ -83,7 +128,7 @@ impl<'a> SyntaxType<'a> {
         })
     }
 
-    fn split_generics(&self) -> (TokenStream2, TokenStream2, TokenStream2, TokenStream2) {
+    fn split_generics(&self) -> (TokenStream2, TokenStream2, TokenStream2, TokenStream2, TokenStream2) {
         // Determine the state type: either from user-specified state attribute or default
         let state = self
             .args
@@ -93,9 +138,16 @@ impl<'a> SyntaxType<'a> {
             .map(|path| path.to_token_stream().clone())
             .unwrap_or(quote! { __SyntaxFmtState });
 
+        // Types with mutable state only implement for mutable formatters, others implement for both
+        let access = if self.args.args.common.state_mut {
+            quote! { ::syntaxfmt::Mutable }
+        } else {
+            quote! { __SyntaxFmtAccess }
+        };
+
         // Start with the original generics for both impl and type
         let mut impl_generics = self.generics.params.clone();
-        let type_generics = self.generics.params.clone();
+        let (_, type_generics, _) = self.generics.split_for_impl();
 
         // Initialize where clause (create empty one if it doesn't exist)
         let mut where_clause = self.generics.where_clause.clone().unwrap_or(WhereClause {
@@ -131,6 +183,9 @@ impl<'a> SyntaxType<'a> {
         if self.args.args.state.is_none() {
             impl_generics.push(parse_quote!(#state ));
         }
+        if !self.args.args.common.state_mut {
+            impl_generics.push(parse_quote!(#access));
+        }
 
         // Add state bound to where clause if specified
         if let Some(bound) = &self.args.args.state_bound {
@@ -139,20 +194,21 @@ impl<'a> SyntaxType<'a> {
                 .push(syn::parse_quote! {#state: #bound });
         }
 
-        // Add SyntaxFmt bounds for all field types
-        // COMMENTED OUT: This creates infinite recursion for recursive types
-        // for &field_ty in &self.types {
-        //     let span = field_ty.span();
-        //     where_clause.predicates.push(
-        //         syn::parse_quote! { #field_ty: ::syntaxfmt::SyntaxFmt<#state> },
-        //     );
-        // }
+        // Add SyntaxFmt bounds for type parameters used by formatted fields, unless overridden
+        let mut bounds = InferredBounds::new(self.generics);
+        if let Some(custom) = &self.args.args.bounds {
+            bounds.add_custom(custom);
+        } else if !self.args.skipped() && !self.args.replaces_content() {
+            self.kind.infer_bounds(&mut bounds);
+        }
+        where_clause.predicates.extend(bounds.predicates(&state, &access));
 
         // Only include where clause if it has predicates
         let where_clause = (!where_clause.predicates.is_empty()).then_some(where_clause);
 
         (
             state,
+            access,
             impl_generics.to_token_stream(),
             type_generics.to_token_stream(),
             where_clause.to_token_stream(),
@@ -164,22 +220,87 @@ impl<'a> ToTokens for SyntaxType<'a> {
     fn to_tokens(&self, tokens: &mut TokenStream2) {
         let name = self.name;
 
-        let (state, impl_gen, ty_gen, where_clause) = self.split_generics();
+        let (state, access, impl_gen, ty_gen, where_clause) = self.split_generics();
 
-        let default_content = Content::Tokens(self.kind.to_token_stream());
+        let kind_content = self.kind.to_token_stream();
 
-        let content = if !self.args.skipped() {
-            self.args.to_conditional_tokens(&default_content)
-        } else {
+        let default_content = Content::Tokens(kind_content.clone());
+
+        let content = if self.args.skipped() {
             TokenStream2::new()
+        } else if self.args.args.transparent {
+            // Transparent types delegate to their field without any context of their own
+            kind_content.clone()
+        } else {
+            let content = self.args.to_conditional_tokens(&default_content);
+            // Each type is a node of the tree, which may be elided beyond the maximum depth
+            match &self.args.args.elide {
+                Some(Elide::Never) => content,
+                Some(Elide::Placeholder(elision)) => quote! {
+                    f.node_with(#elision, |f| {
+                        #content
+                        Ok(())
+                    })?;
+                },
+                None => quote! {
+                    f.node(|f| {
+                        #content
+                        Ok(())
+                    })?;
+                },
+            }
+        };
+
+        // Plain structs can be flattened into a parent by writing just their fields
+        let is_flattenable = matches!(self.kind, SyntaxTypeKind::Struct(_))
+            && !self.args.skipped()
+            && !self.args.args.transparent
+            && self.args.args.eval.is_none()
+            && self.args.args.common.content.is_none();
+        let content_flat = is_flattenable.then(|| {
+            quote! {
+                fn syntax_fmt_flat(&self, f: &mut ::syntaxfmt::SyntaxFormatter<#state, #access>) -> ::std::fmt::Result {
+                    #kind_content
+                    Ok(())
+                }
+            }
+        });
+
+        // Errors are given the type's name as they propagate
+        let name_str = name.unraw().to_string();
+        let content = quote! {
+            f.breadcrumb(::syntaxfmt::PathSegment::Type(#name_str), |f| {
+                #content
+                Ok(())
+            })?;
         };
 
         tokens.extend(quote! {
-            impl <#impl_gen> ::syntaxfmt::SyntaxFmt<#state> for #name<#ty_gen> #where_clause {
-                fn syntax_fmt(&self, f: &mut ::syntaxfmt::SyntaxFormatter<#state>) -> ::std::fmt::Result {
+            impl <#impl_gen> ::syntaxfmt::SyntaxFmt<#state, #access> for #name #ty_gen #where_clause {
+                fn syntax_fmt(&self, f: &mut ::syntaxfmt::SyntaxFormatter<#state, #access>) -> ::std::fmt::Result {
                     #content
                     Ok(())
                 }
+
+                #content_flat
+            }
+        });
+
+        // Grammar uses the type's own generics, since it doesn't depend on state
+        let production = name_str;
+        let grammar = if !self.args.skipped() {
+            self.args
+                .to_conditional_grammar_tokens(&|common| self.kind.grammar_tokens(&common.seps))
+        } else {
+            empty()
+        };
+        let (impl_gen, ty_gen, where_clause) = self.generics.split_for_impl();
+
+        tokens.extend(quote! {
+            impl #impl_gen ::syntaxfmt::SyntaxGrammar for #name #ty_gen #where_clause {
+                fn syntax_grammar() -> ::syntaxfmt::Grammar {
+                    ::syntaxfmt::Grammar::from(::syntaxfmt::Production::new(#production, #grammar))
+                }
             }
         });
     }
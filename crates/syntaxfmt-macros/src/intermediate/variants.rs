@@ -1,14 +1,19 @@
 use proc_macro2::TokenStream as TokenStream2;
 use quote::{quote, ToTokens};
-use syn::{punctuated::Punctuated, token::Comma, Ident, Result as SynResult, Variant};
+use syn::{ext::IdentExt, punctuated::Punctuated, token::Comma, Ident, Result as SynResult, Variant};
 
 use crate::{
     attributes::{
         args::{CommonArgs, FieldArgs},
         content::{Content, Skipped, ToConditionalTokens},
         context::FieldKind,
+        grammar::{ToConditionalGrammarTokens, choice},
+        modal::Strings,
+    },
+    intermediate::{
+        bounds::InferredBounds,
+        fields::{SyntaxFields, SyntaxFieldsDecl},
     },
-    intermediate::fields::{SyntaxFields, SyntaxFieldsDecl},
 };
 
 #[derive(Debug, Clone)]
@@ -31,16 +36,34 @@ pub struct SyntaxVariant {
 }
 
 impl SyntaxVariant {
-    pub fn from_variant(parent_common: &CommonArgs, input: &Variant) -> SynResult<Self> {
+    pub fn from_variant(
+        parent_common: &CommonArgs,
+        parent_field_sep: &Option<Strings>,
+        input: &Variant,
+    ) -> SynResult<Self> {
         let name = input.ident.clone();
         let args = FieldArgs::new(FieldKind::SelfValue, parent_common, &input.attrs)?;
-        let fields = SyntaxFields::from_fields(&args.args.common, &input.fields)?;
+        let field_sep = args.args.field_sep.clone().or_else(|| parent_field_sep.clone());
+        let fields = SyntaxFields::from_fields(&args.args.common, field_sep, &input.fields)?;
         Ok(Self { args, fields, name })
     }
 
     pub fn decl(&self) -> SyntaxVariantDecl {
         SyntaxVariantDecl(self.name.clone(), self.fields.decl())
     }
+
+    pub fn grammar_tokens(&self) -> Option<TokenStream2> {
+        (!self.args.skipped()).then(|| {
+            self.args
+                .to_conditional_grammar_tokens(&|common| self.fields.grammar_tokens(&common.seps))
+        })
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
+        if bounds.add_args(&self.args) {
+            self.fields.infer_bounds(bounds);
+        }
+    }
 }
 
 impl ToTokens for SyntaxVariant {
@@ -55,7 +78,15 @@ impl ToTokens for SyntaxVariant {
 
         let content = self.args.to_conditional_tokens(&default_content);
 
-        tokens.extend(quote! { #decl => { #content }});
+        let name = self.name.unraw().to_string();
+        tokens.extend(quote! {
+            #decl => {
+                f.breadcrumb(::syntaxfmt::PathSegment::Variant(#name), |f| {
+                    #content
+                    Ok(())
+                })?;
+            }
+        });
     }
 }
 
@@ -67,14 +98,26 @@ pub struct SyntaxVariants {
 impl SyntaxVariants {
     pub fn from_variants(
         parent_common: &CommonArgs,
+        parent_field_sep: &Option<Strings>,
         input: &Punctuated<Variant, Comma>,
     ) -> SynResult<Self> {
         let mut variants = Vec::new();
         for variant in input {
-            variants.push(SyntaxVariant::from_variant(parent_common, variant)?);
+            variants.push(SyntaxVariant::from_variant(parent_common, parent_field_sep, variant)?);
         }
         Ok(Self { variants })
     }
+
+    pub fn grammar_tokens(&self) -> TokenStream2 {
+        let variants: Vec<_> = self.variants.iter().filter_map(|v| v.grammar_tokens()).collect();
+        choice(&variants)
+    }
+
+    pub fn infer_bounds(&self, bounds: &mut InferredBounds) {
+        for variant in &self.variants {
+            variant.infer_bounds(bounds);
+        }
+    }
 }
 
 impl ToTokens for SyntaxVariants {
@@ -1,3 +1,6 @@
+// Parsing helpers are marked `#[must_use]` even where they return `Result`
+#![allow(clippy::double_must_use)]
+
 use std::fmt::Display;
 
 use proc_macro::TokenStream;
@@ -1,8 +1,16 @@
 #![allow(unused)]
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::marker::PhantomData;
 
-use syntaxfmt::{Mode, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+use syntaxfmt::{
+    Entries, Exponent, Grammar, GrammarExpr, GrammarStyle, IdentEscape, Immutable, Mode, Mutable,
+    Num, NumFmt, PathSegment, Production, Quote, Quoted, ReservedWords, ReservedWordsSource,
+    Sorted, StateComponents, StateError, StateMap, SyntaxError, SyntaxFmt, SyntaxFormatter,
+    SyntaxGrammar, TokenClass, syntax_fmt,
+};
+use syntaxfmt::{assert_syntax_eq, assert_syntax_snapshot};
+use syntaxfmt::testing::{RoundTrip, RoundTripError, Snapshot};
 use syntaxfmt_macros::SyntaxFmt as SyntaxFmtDerive;
 
 // =============================================================================
@@ -152,6 +160,32 @@ fn test_outer_indent_pretty() {
     assert_eq!(format!("{}", syntax_fmt(&s).pretty()), "\n    foo");
 }
 
+#[derive(SyntaxFmtDerive)]
+struct WithAllDecorations {
+    #[syntax(
+        pre = "{",
+        suf = "}",
+        sep = [", ", ","],
+        kv_sep = ": ",
+        last_sep = [" and ", ","],
+        ind,
+        nl = [beg, pre, cont, suf, sep]
+    )]
+    entries: BTreeMap<&'static str, i32>,
+}
+
+#[test]
+fn test_all_decorations_pretty() {
+    let s = WithAllDecorations {
+        entries: BTreeMap::from([("a", 1), ("b", 2), ("c", 3)]),
+    };
+    assert_eq!(format!("{}", syntax_fmt(&s)), "{a: 1, b: 2 and c: 3}");
+    assert_eq!(
+        format!("{}", syntax_fmt(&s).pretty()),
+        "\n{\n    a: 1,\n    b: 2,\n    c: 3\n}\n"
+    );
+}
+
 // =============================================================================
 // prefix and suffix
 // =============================================================================
@@ -619,13 +653,16 @@ impl Counter for TestCounter {
     }
 }
 
-fn counting_formatter(field: &str, f: &mut SyntaxFormatter<TestCounter>) -> std::fmt::Result {
+fn counting_formatter(
+    field: &str,
+    f: &mut SyntaxFormatter<TestCounter, Mutable>,
+) -> std::fmt::Result {
     let count = f.state_mut().post_inc();
     write!(f, "{}#{}", field, count)
 }
 
 #[derive(SyntaxFmtDerive)]
-#[syntax(state = TestCounter)]
+#[syntax(state = TestCounter, state_mut)]
 struct WithMutableState {
     #[syntax(cont_with = counting_formatter)]
     name: &'static str,
@@ -651,14 +688,14 @@ fn test_mutable_state() {
 
 fn counting_formatter_bounded<S: Counter>(
     field: &str,
-    f: &mut SyntaxFormatter<S>,
+    f: &mut SyntaxFormatter<S, Mutable>,
 ) -> std::fmt::Result {
     let count = f.state_mut().post_inc();
     write!(f, "{}#{}", field, count)
 }
 
 #[derive(SyntaxFmtDerive)]
-#[syntax(bound = Counter)]
+#[syntax(bound = Counter, state_mut)]
 struct WithMutableStateBounded {
     #[syntax(cont_with = counting_formatter_bounded)]
     name: &'static str,
@@ -792,7 +829,7 @@ fn test_map_state() {
 
 fn map_state_mut_formatter<S: Counter>(
     field: &str,
-    f: &mut SyntaxFormatter<S>,
+    f: &mut SyntaxFormatter<S, Mutable>,
 ) -> std::fmt::Result {
     f.map_state_mut(|f, state| {
         let count = state.post_inc();
@@ -801,7 +838,7 @@ fn map_state_mut_formatter<S: Counter>(
 }
 
 #[derive(SyntaxFmtDerive)]
-#[syntax(bound = Counter)]
+#[syntax(bound = Counter, state_mut)]
 struct WithMapStateMut {
     #[syntax(cont_with = map_state_mut_formatter)]
     name: &'static str,
@@ -826,27 +863,88 @@ fn test_map_state_mut() {
 }
 
 // =============================================================================
-// access mutable state in immutable context (should panic)
+// mutable state access
 // =============================================================================
 
+// Readers are generic over access, so work in types with and without mutable state
+fn reading_formatter<S: Counter, A>(field: &str, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+    write!(f, "{field}")
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = TestCounter, state_mut, sep = " ")]
+struct MutableWithReader {
+    #[syntax(cont_with = reading_formatter)]
+    label: &'static str,
+    #[syntax(cont_with = counting_formatter)]
+    name: &'static str,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound = Counter)]
+struct ImmutableReader {
+    #[syntax(cont_with = reading_formatter)]
+    label: &'static str,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound = Counter, sep = ", ")]
+struct MutableParent<T> {
+    first: ImmutableReader,
+    #[syntax(sep = "; ")]
+    items: Vec<T>,
+    #[syntax(cont_with = |n: &usize, f: &mut SyntaxFormatter<_>| write!(f, "{n}"))]
+    count: usize,
+}
+
 #[test]
-#[should_panic(expected = "StateRef: state is immutable")]
-fn test_immutable_context_mut_access_panics() {
-    fn bad_formatter(field: &str, f: &mut SyntaxFormatter<TestCounter>) -> std::fmt::Result {
-        f.state_mut().post_inc(); // This should panic!
-        write!(f, "{}", field)
-    }
+fn test_mutable_state_nested() {
+    let mut counter = TestCounter { count: 0 };
+    let parent = MutableParent {
+        first: ImmutableReader { label: "first" },
+        items: vec![
+            MutableWithReader { label: "a", name: "x" },
+            MutableWithReader { label: "b", name: "y" },
+        ],
+        count: 2,
+    };
+    let display = syntax_fmt(&parent).state_mut(&mut counter);
+    assert_eq!(format!("{display}"), "first, a x#0; b y#1, 2");
+    assert_eq!(counter.count, 2);
 
-    #[derive(SyntaxFmtDerive)]
-    #[syntax(state = TestCounter)]
-    struct Bad {
-        #[syntax(cont_with = bad_formatter)]
-        name: &'static str,
+    // Types which don't mutate state can still be formatted with immutable state
+    let reader = ImmutableReader { label: "read" };
+    assert_eq!(format!("{}", syntax_fmt(&reader).state(&counter)), "read");
+    assert_eq!(format!("{}", syntax_fmt(&reader).state_mut(&mut counter)), "read");
+}
+
+struct ManualCounter;
+
+impl<S: Counter> SyntaxFmt<S, Mutable> for ManualCounter {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, Mutable>) -> std::fmt::Result {
+        let count = f.state_mut().post_inc();
+        reading_formatter("manual", f.as_immutable())?;
+        write!(f, "#{count}")
     }
+}
 
-    let counter = TestCounter { count: 0 };
-    let s = Bad { name: "test" };
-    let _ = format!("{}", syntax_fmt(&s).state(&counter));
+#[test]
+fn test_mutable_state_manual() {
+    let mut counter = TestCounter { count: 5 };
+    let items = (ManualCounter, Some(ManualCounter));
+    assert_eq!(format!("{}", syntax_fmt(&items).state_mut(&mut counter)), "manual#5manual#6");
+    assert_eq!(counter.count, 7);
+}
+
+// `as_immutable` relies on the layout not depending on the access parameter
+#[test]
+fn test_formatter_layout() {
+    use std::mem::{align_of, size_of};
+    type Formatter<A> = SyntaxFormatter<'static, 'static, 'static, 'static, TestCounter, A>;
+    assert_eq!(size_of::<Formatter<Mutable>>(), size_of::<Formatter<Immutable>>());
+    assert_eq!(align_of::<Formatter<Mutable>>(), align_of::<Formatter<Immutable>>());
+    assert_eq!(size_of::<PhantomData<Mutable>>(), 0);
+    assert_eq!(align_of::<PhantomData<Mutable>>(), 1);
 }
 
 // =============================================================================
@@ -931,22 +1029,2009 @@ fn test_mutual_recursion_enum() {
 }
 
 // =============================================================================
-// macro wrapped
+// generic bound inference
 // =============================================================================
 
-// This test mainly ensures the type compiles when wrapped in a macro
-// Had a weird error where self values were getting resolved incorrectly but only in
-// macros - presumably related to macro hygeine.
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "[", suf = "]")]
+struct GenericList<T> {
+    #[syntax(sep = ", ")]
+    items: Vec<T>,
+}
 
-macro_rules! impl_in_macro {
-    ($name:ident) => {
-        #[derive(SyntaxFmtDerive)]
-        struct $name(i32);
+#[test]
+fn test_generic_inferred_bound() {
+    let list = GenericList { items: vec![1, 2, 3] };
+    assert_eq!(format!("{}", syntax_fmt(&list)), "[1, 2, 3]");
+}
+
+struct NotSyntaxFmt;
+
+#[derive(SyntaxFmtDerive)]
+struct GenericIgnoredParams<T, U, V> {
+    value: Option<T>,
+    _marker: PhantomData<U>,
+    #[syntax(skip)]
+    _skipped: V,
+}
+
+#[test]
+fn test_generic_unused_params_unbounded() {
+    let value = GenericIgnoredParams {
+        value: Some("value"),
+        _marker: PhantomData::<NotSyntaxFmt>,
+        _skipped: NotSyntaxFmt,
     };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "value");
+}
+
+trait Language {
+    type Keyword;
+}
+
+struct Lang;
+
+impl Language for Lang {
+    type Keyword = &'static str;
+}
+
+// Associated types are bounded instead of their parameter, which isn't `SyntaxFmt`
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+struct GenericAssociated<L: Language> {
+    #[syntax(sep = ",")]
+    keywords: Vec<L::Keyword>,
+    last: Option<<L as Language>::Keyword>,
 }
-impl_in_macro!(InMacro);
 
 #[test]
-fn test_in_macro() {
-    let _ = format!("{}", syntax_fmt(&InMacro(123)));
+fn test_generic_associated_bound() {
+    let value = GenericAssociated::<Lang> { keywords: vec!["fn", "let"], last: Some("mut") };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "fn,let mut");
+}
+
+#[derive(SyntaxFmtDerive)]
+enum GenericTree<T> {
+    #[syntax(pre = "(", suf = ")")]
+    Node(#[syntax(sep = " ")] Vec<GenericTree<T>>),
+    Leaf(T),
+}
+
+#[test]
+fn test_generic_recursive() {
+    let tree = GenericTree::Node(vec![
+        GenericTree::Leaf('a'),
+        GenericTree::Node(vec![GenericTree::Leaf('b')]),
+    ]);
+    assert_eq!(format!("{}", syntax_fmt(&tree)), "(a (b))");
+}
+
+struct Opaque<T>(PhantomData<T>);
+
+impl<S, A, T> SyntaxFmt<S, A> for Opaque<T> {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+        write!(f, "opaque")
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound = "")]
+struct GenericNoBound<T> {
+    value: Opaque<T>,
+}
+
+#[test]
+fn test_generic_bound_disabled() {
+    let value = GenericNoBound::<NotSyntaxFmt> { value: Opaque(PhantomData) };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "opaque");
+}
+
+fn display_formatter<S, T: std::fmt::Display>(
+    value: &T,
+    f: &mut SyntaxFormatter<S>,
+) -> std::fmt::Result {
+    write!(f, "<{value}>")
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(sep = " ")]
+struct GenericFieldBound<K, V> {
+    key: K,
+    #[syntax(bound(V: std::fmt::Display), cont_with = display_formatter)]
+    value: V,
+}
+
+#[test]
+fn test_generic_field_bound() {
+    let value = GenericFieldBound { key: "key", value: 1.5 };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "key <1.5>");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound(T: SyntaxFmt<__SyntaxFmtState, __SyntaxFmtAccess> + Copy))]
+struct GenericTypeBound<T> {
+    value: T,
+}
+
+#[test]
+fn test_generic_type_bound() {
+    let value = GenericTypeBound { value: 'x' };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "x");
+}
+
+// =============================================================================
+// transparent and flatten
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(transparent)]
+struct TransparentIdent(&'static str);
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(transparent)]
+struct TransparentSpanned<T> {
+    node: T,
+    #[syntax(skip)]
+    _span: (usize, usize),
+    _marker: PhantomData<u8>,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "(", suf = ")", sep = ", ")]
+struct TransparentOuter {
+    #[syntax(sep = " ")]
+    idents: Vec<TransparentIdent>,
+    spanned: TransparentSpanned<TransparentIdent>,
+}
+
+#[test]
+fn test_transparent() {
+    let outer = TransparentOuter {
+        idents: vec![TransparentIdent("a"), TransparentIdent("b")],
+        spanned: TransparentSpanned {
+            node: TransparentIdent("c"),
+            _span: (0, 1),
+            _marker: PhantomData,
+        },
+    };
+    assert_eq!(format!("{}", syntax_fmt(&outer)), "(a b, c)");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "{", suf = "}", sep = ", ")]
+struct FlattenInner {
+    a: i32,
+    b: i32,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "[", suf = "]", sep = "; ")]
+struct FlattenOuter {
+    first: i32,
+    #[syntax(flatten)]
+    inner: FlattenInner,
+    #[syntax(flatten)]
+    boxed: Box<FlattenInner>,
+    last: i32,
+}
+
+#[test]
+fn test_flatten() {
+    let outer = FlattenOuter {
+        first: 0,
+        inner: FlattenInner { a: 1, b: 2 },
+        boxed: Box::new(FlattenInner { a: 3, b: 4 }),
+        last: 5,
+    };
+    assert_eq!(format!("{}", syntax_fmt(&outer)), "[0; 1; 2; 3; 4; 5]");
+    assert_eq!(format!("{}", syntax_fmt(&outer.inner)), "{1, 2}");
+}
+
+#[derive(SyntaxFmtDerive)]
+struct FlattenOptional {
+    #[syntax(eval = value.is_some())]
+    value: Option<i32>,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "[", suf = "]", sep = ";", nl = [pre, sep, cont], ind)]
+struct FlattenSparse {
+    #[syntax(flatten)]
+    first: FlattenOptional,
+    middle: i32,
+    #[syntax(flatten)]
+    last: FlattenOptional,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+struct FlattenOuterSep {
+    first: i32,
+    #[syntax(flatten)]
+    inner: FlattenOptional,
+    last: i32,
+}
+
+#[test]
+fn test_flatten_empty() {
+    let empty = FlattenSparse {
+        first: FlattenOptional { value: None },
+        middle: 1,
+        last: FlattenOptional { value: None },
+    };
+    assert_eq!(format!("{}", syntax_fmt(&empty)), "[1]");
+    assert_eq!(format!("{}", syntax_fmt(&empty).pretty()), "[\n    1\n]");
+    let full = FlattenSparse {
+        first: FlattenOptional { value: Some(0) },
+        middle: 1,
+        last: FlattenOptional { value: Some(2) },
+    };
+    assert_eq!(format!("{}", syntax_fmt(&full)), "[0;1;2]");
+    assert_eq!(format!("{}", syntax_fmt(&full).pretty()), "[\n    0;\n    1;\n    2\n]");
+
+    // With a field separator, empty flattened fields are skipped like any other empty field
+    let outer = FlattenOuterSep { first: 0, inner: FlattenOptional { value: None }, last: 5 };
+    assert_eq!(format!("{}", syntax_fmt(&outer)), "0 5");
+}
+
+// =============================================================================
+// field order and field separators
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+struct OrderedFnDecl {
+    #[syntax(pre = "fn ", suf = "()", order = 2)]
+    name: &'static str,
+    #[syntax(cont = "pub", eval = *is_pub, order = 0)]
+    is_pub: bool,
+    #[syntax(cont = "async", eval = *is_async, order = 1)]
+    is_async: bool,
+}
+
+#[test]
+fn test_order_and_field_sep() {
+    let decl = OrderedFnDecl { name: "name", is_pub: true, is_async: false };
+    assert_eq!(format!("{}", syntax_fmt(&decl)), "pub fn name()");
+
+    let decl = OrderedFnDecl { name: "name", is_pub: false, is_async: true };
+    assert_eq!(format!("{}", syntax_fmt(&decl)), "async fn name()");
+
+    let decl = OrderedFnDecl { name: "name", is_pub: false, is_async: false };
+    assert_eq!(format!("{}", syntax_fmt(&decl)), "fn name()");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+struct OrderedNegative {
+    name: &'static str,
+    #[syntax(order = -1)]
+    keyword: &'static str,
+    #[syntax(order = -2)]
+    visibility: &'static str,
+}
+
+#[test]
+fn test_order_negative() {
+    let decl = OrderedNegative { name: "f", keyword: "fn", visibility: "pub" };
+    assert_eq!(format!("{}", syntax_fmt(&decl)), "pub fn f");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "(", suf = ")", field_sep = [",", ", "])]
+struct FieldSepOptional(Option<i32>, Option<i32>, Option<i32>);
+
+#[test]
+fn test_field_sep_skips_empty() {
+    let value = FieldSepOptional(None, Some(1), None);
+    assert_eq!(format!("{}", syntax_fmt(&value)), "(1)");
+
+    let value = FieldSepOptional(Some(1), None, Some(3));
+    assert_eq!(format!("{}", syntax_fmt(&value)), "(1,3)");
+    assert_eq!(format!("{}", syntax_fmt(&value).pretty()), "(1, 3)");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "[", suf = "]", field_sep = " ")]
+struct FieldSepNested {
+    before: Option<&'static str>,
+    inner: FieldSepOptional,
+    #[syntax(eval = after.is_some())]
+    after: Option<&'static str>,
+}
+
+#[test]
+fn test_field_sep_nested() {
+    let value = FieldSepNested {
+        before: Some("a"),
+        inner: FieldSepOptional(None, None, None),
+        after: None,
+    };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "[a ()]");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+enum FieldSepEnum {
+    Pair(#[syntax(order = 2)] &'static str, &'static str),
+    #[syntax(field_sep = "-")]
+    Dashed(&'static str, Option<&'static str>, &'static str),
+}
+
+#[test]
+fn test_field_sep_enum() {
+    assert_eq!(format!("{}", syntax_fmt(&FieldSepEnum::Pair("a", "b"))), "b a");
+    assert_eq!(
+        format!("{}", syntax_fmt(&FieldSepEnum::Dashed("a", None, "c"))),
+        "a-c"
+    );
+}
+
+// Formatted through its `Display` impl, like a type from another crate
+struct DisplayName(&'static str);
+
+impl std::fmt::Display for DisplayName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.0)
+    }
+}
+
+impl<S, A> SyntaxFmt<S, A> for DisplayName {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(field_sep = " ")]
+struct FieldSepDisplay(i32, DisplayName, DisplayName, Option<DisplayName>, DisplayName);
+
+#[test]
+fn test_field_sep_display() {
+    let value = FieldSepDisplay(1, DisplayName("y"), DisplayName("zz"), None, DisplayName(""));
+    assert_eq!(format!("{}", syntax_fmt(&value)), "1 y zz");
+}
+
+// Reports the flags of the inner formatter
+struct Flags;
+
+impl<S, A> SyntaxFmt<S, A> for Flags {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+        let flags = (f.fill(), f.width(), f.precision(), f.alternate(), f.sign_plus(), f.sign_aware_zero_pad());
+        write!(f, "{flags:?}")
+    }
+}
+
+#[test]
+fn test_formatter_flags() {
+    assert_eq!(format!("{}", syntax_fmt(&Flags)), "(' ', None, None, false, false, false)");
+    assert_eq!(format!("{:*<+#05.2}", syntax_fmt(&Flags)), "('*', Some(5), Some(2), true, true, true)");
+}
+
+// =============================================================================
+// token joining
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+struct JoinedDecl {
+    #[syntax(cont = "pub", eval = *is_pub)]
+    is_pub: bool,
+    #[syntax(nl = beg, pre = "fn")]
+    name: &'static str,
+    #[syntax(pre = "(", suf = ")")]
+    args: &'static str,
+}
+
+#[test]
+fn test_join_tokens() {
+    let decl = JoinedDecl { is_pub: true, name: "main", args: "" };
+    assert_eq!(format!("{}", syntax_fmt(&decl)), "pubfnmain()");
+    assert_eq!(
+        format!("{}", syntax_fmt(&decl).join_tokens(TokenClass::DEFAULT)),
+        "pub fn main()"
+    );
+    // Newlines break tokens, so nothing is inserted after them
+    assert_eq!(
+        format!("{}", syntax_fmt(&decl).pretty().join_tokens(TokenClass::DEFAULT)),
+        "pub\nfn main()"
+    );
+}
+
+const HASH_CLASS: &[TokenClass] = &[TokenClass(|c| c == '#')];
+
+#[test]
+fn test_join_tokens_custom_class() {
+    let items = ("#", "#", "a", "b");
+    assert_eq!(format!("{}", syntax_fmt(&items).join_tokens(HASH_CLASS)), "# #ab");
+}
+
+#[derive(SyntaxFmtDerive)]
+struct JoinedLet(#[syntax(pre = "let")] DisplayName, #[syntax(pre = "=")] i32);
+
+// Writes a token in pieces which would join if written separately
+struct Pieces(&'static [&'static str]);
+
+impl<S, A> SyntaxFmt<S, A> for Pieces {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+        f.write_atomic(|f| self.0.iter().try_for_each(|piece| f.write_str(piece)))
+    }
+}
+
+#[test]
+fn test_join_tokens_display_and_atomic() {
+    let stmt = JoinedLet(DisplayName("x"), -1);
+    assert_eq!(
+        format!("{}", syntax_fmt(&stmt).join_tokens(TokenClass::DEFAULT)),
+        "let x= -1"
+    );
+
+    // Only joined before the first piece
+    let pieces = ("a", Pieces(&["b", "c", "-", "-"]), "-");
+    assert_eq!(
+        format!("{}", syntax_fmt(&pieces).join_tokens(TokenClass::DEFAULT)),
+        "a bc-- -"
+    );
+}
+
+// =============================================================================
+// string quoting
+// =============================================================================
+
+#[test]
+fn test_quote_rust() {
+    assert_eq!(Quote::Rust.quote("plain"), r#""plain""#);
+    assert_eq!(Quote::Rust.quote(r"C:\dir"), r#"r"C:\dir""#);
+    assert_eq!(Quote::Rust.quote(r#"say "hi""#), r##"r#"say "hi""#"##);
+    assert_eq!(Quote::Rust.quote(r##"a"#b"##), r###"r##"a"#b"##"###);
+    // Control characters rule out raw strings
+    assert_eq!(Quote::Rust.quote("\"a\"\n\x01"), r#""\"a\"\n\u{1}""#);
+}
+
+#[test]
+fn test_quote_c() {
+    assert_eq!(Quote::C.quote("a\"b\\\n"), r#""a\"b\\\n""#);
+    assert_eq!(Quote::C.quote("\x011"), r#""\0011""#);
+    assert_eq!(Quote::C.quote("é😀"), r#""\u00e9\U0001f600""#);
+    assert_eq!(Quote::C.quote("??="), r#""?\?=""#);
+    // C1 controls can't be universal character names
+    assert_eq!(Quote::C.quote("\u{85}\u{9f}\u{a0}"), r#""\302\205\302\237\u00a0""#);
+}
+
+#[test]
+fn test_quote_json() {
+    assert_eq!(Quote::Json.quote("a\"b\\\t\x1f"), r#""a\"b\\\t\u001f""#);
+    assert_eq!(Quote::Json.quote("é"), "\"é\"");
+}
+
+#[test]
+fn test_quote_python() {
+    assert_eq!(Quote::Python.quote("it's"), r#""it's""#);
+    assert_eq!(Quote::Python.quote(r#"it's "x""#), r#"'it\'s "x"'"#);
+    assert_eq!(Quote::Python.quote("a\x7f\u{85}"), r"'a\x7f\x85'");
+}
+
+#[test]
+fn test_quote_sql() {
+    assert_eq!(Quote::Sql.quote("O'Brien"), "'O''Brien'");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "{", suf = "}", sep = ":")]
+struct QuotedJsonPair {
+    #[syntax(quote = json)]
+    key: &'static str,
+    #[syntax(quote = json)]
+    value: String,
+}
+
+#[test]
+fn test_quote_attribute() {
+    let pair = QuotedJsonPair { key: "k", value: "line\n".into() };
+    assert_eq!(format!("{}", syntax_fmt(&pair)), r#"{"k":"line\n"}"#);
+    assert_eq!(
+        format!("{}", syntax_fmt(&(Quoted(Quote::Sql, "a"), Quoted(Quote::Rust, "b")))),
+        r#"'a'"b""#
+    );
+}
+
+#[test]
+fn test_quote_joined() {
+    // Escapes run into the following text, but are part of the literal
+    let pair = QuotedJsonPair { key: "k", value: "a\nb".into() };
+    assert_eq!(
+        format!("{}", syntax_fmt(&pair).join_tokens(TokenClass::DEFAULT)),
+        r#"{"k":"a\nb"}"#
+    );
+    let items = ("f", Quoted(Quote::Python, "x\ty"), "z");
+    assert_eq!(
+        format!("{}", syntax_fmt(&items).join_tokens(TokenClass::DEFAULT)),
+        r"f'x\ty'z"
+    );
+}
+
+// =============================================================================
+// identifier escaping
+// =============================================================================
+
+#[test]
+fn test_ident_builtin_tables() {
+    assert_eq!(ReservedWords::RUST.ident("type").to_string(), "r#type");
+    assert_eq!(ReservedWords::RUST.ident("kind").to_string(), "kind");
+    assert_eq!(ReservedWords::PYTHON.ident("class").to_string(), "class_");
+    assert_eq!(ReservedWords::SQL.ident("select").to_string(), "\"select\"");
+    assert_eq!(ReservedWords::SQL.ident("name").to_string(), "name");
+    assert_eq!(ReservedWords::KOTLIN.ident("when").to_string(), "`when`");
+}
+
+const LISP_WORDS: ReservedWords =
+    ReservedWords::new(&["nil", "t"], IdentEscape::Wrap("|", "|"));
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "let ", sep = " = ")]
+struct RustLet {
+    #[syntax(ident = rust)]
+    name: String,
+    #[syntax(ident = rust)]
+    value: &'static str,
+}
+
+#[derive(SyntaxFmtDerive)]
+struct LispSymbol {
+    #[syntax(ident = LISP_WORDS)]
+    name: &'static str,
+}
+
+#[test]
+fn test_ident_attribute() {
+    let binding = RustLet { name: "match".into(), value: "x" };
+    assert_eq!(format!("{}", syntax_fmt(&binding)), "let r#match = x");
+    assert_eq!(format!("{}", syntax_fmt(&LispSymbol { name: "nil" })), "|nil|");
+}
+
+#[test]
+fn test_ident_joined() {
+    let items = ("def", ReservedWords::PYTHON.ident("class"), ReservedWords::RUST.ident("type"));
+    assert_eq!(
+        format!("{}", syntax_fmt(&items).join_tokens(TokenClass::DEFAULT)),
+        "def class_ r#type"
+    );
+    let binding = RustLet { name: "match".into(), value: "fn" };
+    assert_eq!(
+        format!("{}", syntax_fmt(&binding).join_tokens(TokenClass::DEFAULT)),
+        "let r#match = r#fn"
+    );
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound = syntaxfmt::ReservedWordsSource, sep = ".")]
+struct QualifiedPart {
+    #[syntax(ident = state)]
+    table: &'static str,
+    #[syntax(ident = state)]
+    column: &'static str,
+}
+
+#[test]
+fn test_ident_from_state() {
+    let name = QualifiedPart { table: "order", column: "id" };
+    assert_eq!(
+        format!("{}", syntax_fmt(&name).state(&ReservedWords::SQL)),
+        "\"order\".id"
+    );
+    assert_eq!(
+        format!("{}", syntax_fmt(&name).state(&ReservedWords::PYTHON)),
+        "order.id"
+    );
+}
+
+// =============================================================================
+// numeric literals
+// =============================================================================
+
+fn num<T: syntaxfmt::NumLit>(fmt: NumFmt, value: T) -> String {
+    Num(fmt, value).to_string()
+}
+
+#[test]
+fn test_num_integers() {
+    assert_eq!(num(NumFmt::new(), -42i32), "-42");
+    assert_eq!(num(NumFmt::new().hex(), 255u8), "0xff");
+    assert_eq!(num(NumFmt::new().hex().upper(), -255i16), "-0xFF");
+    assert_eq!(num(NumFmt::new().bin().digits(8).group(4), 5u8), "0b0000_0101");
+    assert_eq!(num(NumFmt::new().oct().no_prefix(), 8u32), "10");
+    assert_eq!(num(NumFmt::new().group(3), i64::MIN), "-9_223_372_036_854_775_808");
+    assert_eq!(num(NumFmt::new().point().suffix("f64"), 3u8), "3.0f64");
+    assert_eq!(num(NumFmt::new().hex().point(), 3u8), "0x3");
+}
+
+#[test]
+fn test_num_floats() {
+    assert_eq!(num(NumFmt::new(), 1.0f64), "1");
+    assert_eq!(num(NumFmt::new().point(), 1.0f64), "1.0");
+    assert_eq!(num(NumFmt::new().point(), -0.0f64), "-0.0");
+    assert_eq!(num(NumFmt::new().point(), 0.1f32), "0.1");
+    assert_eq!(num(NumFmt::new(), 0.1f64 + 0.2), "0.30000000000000004");
+    assert_eq!(num(NumFmt::new().group(3), 1234567.5f64), "1_234_567.5");
+    assert_eq!(num(NumFmt::new().exp(Exponent::Always), 1500.0f64), "1.5e3");
+    assert_eq!(num(NumFmt::new().exp(Exponent::Always).point().upper(), 2.0f64), "2.0E0");
+    assert_eq!(num(NumFmt::new().exp(Exponent::Auto), 1e20f64), "1e20");
+    assert_eq!(num(NumFmt::new().exp(Exponent::Auto), 1e-7f64), "1e-7");
+    assert_eq!(num(NumFmt::new().exp(Exponent::Auto), 123.0f64), "123");
+    assert_eq!(num(NumFmt::new().point(), f64::INFINITY), "inf");
+    assert_eq!(num(NumFmt::new().hex(), 10.5f64), "10.5");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(sep = " ")]
+struct NumLiterals {
+    #[syntax(num(hex, upper, digits = 4, group = 2))]
+    mask: u32,
+    #[syntax(num(point, exp = auto, suffix = "f32"))]
+    scale: f32,
+    #[syntax(num(bin, no_prefix))]
+    flags: u8,
+}
+
+#[test]
+fn test_num_attribute() {
+    let value = NumLiterals { mask: 0xab, scale: 2.0, flags: 0 };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "0x00_AB 2.0f32 0");
+    let value = NumLiterals { mask: 0x12345, scale: 1e-9, flags: 6 };
+    assert_eq!(format!("{}", syntax_fmt(&value)), "0x1_23_45 1.0e-9f32 110");
+}
+
+#[derive(SyntaxFmtDerive)]
+struct NumMask {
+    #[syntax(pre = "mask=", num(hex, group = 2))]
+    mask: u32,
+}
+
+#[test]
+fn test_num_joined() {
+    let value = NumMask { mask: 0xdeadbeef };
+    assert_eq!(
+        format!("{}", syntax_fmt(&value).join_tokens(TokenClass::DEFAULT)),
+        "mask=0xde_ad_be_ef"
+    );
+    let items = ("x", Num(NumFmt::new().point().suffix("f32"), -1.5f32), "y");
+    assert_eq!(
+        format!("{}", syntax_fmt(&items).join_tokens(TokenClass::DEFAULT)),
+        "x-1.5f32 y"
+    );
+}
+
+// =============================================================================
+// maps and sets
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "[", suf = "]")]
+struct AttrTable {
+    #[syntax(sep = ", ", kv_sep = "=")]
+    attrs: BTreeMap<&'static str, i32>,
+}
+
+#[test]
+fn test_btree_map_kv_sep() {
+    let table = AttrTable { attrs: BTreeMap::from([("width", 2), ("height", 1)]) };
+    assert_eq!(format!("{}", syntax_fmt(&table)), "[height=1, width=2]");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "{", suf = "}", kv_sep = [": ", ":"])]
+struct ObjectLiteral {
+    #[syntax(sorted, sep = [", ", ","])]
+    fields: HashMap<String, ObjectValue>,
+}
+
+#[derive(SyntaxFmtDerive)]
+enum ObjectValue {
+    Int(i32),
+    Object(ObjectLiteral),
+}
+
+#[test]
+fn test_sorted_hash_map() {
+    let inner = ObjectLiteral {
+        fields: HashMap::from([("z".into(), ObjectValue::Int(0)), ("y".into(), ObjectValue::Int(1))]),
+    };
+    let outer = ObjectLiteral {
+        fields: HashMap::from([
+            ("b".into(), ObjectValue::Object(inner)),
+            ("a".into(), ObjectValue::Int(2)),
+            ("c".into(), ObjectValue::Int(3)),
+        ]),
+    };
+    assert_eq!(format!("{}", syntax_fmt(&outer)), "{a: 2, b: {y: 1, z: 0}, c: 3}");
+    assert_eq!(format!("{}", syntax_fmt(&outer).pretty()), "{a:2,b:{y:1,z:0},c:3}");
+}
+
+#[derive(SyntaxFmtDerive)]
+struct Collections {
+    #[syntax(sep = " ")]
+    deque: VecDeque<i32>,
+    #[syntax(pre = "|", sep = " ")]
+    btree: BTreeSet<char>,
+    #[syntax(pre = "|", sep = " ", sorted)]
+    hash: HashSet<u8>,
+}
+
+#[test]
+fn test_sets_and_deques() {
+    let collections = Collections {
+        deque: VecDeque::from([3, 1, 2]),
+        btree: BTreeSet::from(['b', 'a']),
+        hash: HashSet::from([9, 7, 8]),
+    };
+    assert_eq!(format!("{}", syntax_fmt(&collections)), "3 1 2|a b|7 8 9");
+}
+
+#[test]
+fn test_entries_and_sorted_wrappers() {
+    let pairs = [("x", 1), ("y", 2)];
+    let entries = Entries(pairs.iter().copied());
+    // No kv_sep or sep without an enclosing context
+    assert_eq!(format!("{}", syntax_fmt(&entries)), "x1y2");
+
+    let map = HashMap::from([(2, 'b'), (1, 'a')]);
+    assert_eq!(format!("{}", syntax_fmt(&Sorted(&map))), "1a2b");
+}
+
+#[test]
+fn test_map_grammar() {
+    assert_eq!(
+        AttrTable::syntax_grammar().to_string(),
+        "AttrTable ::= \"[\" ( ( str \"=\" i32 ) ( \", \" ( str \"=\" i32 ) )* )? \"]\"\n"
+    );
+}
+
+// =============================================================================
+// lazy iterators
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+struct IterChild {
+    name: &'static str,
+    #[syntax(skip)]
+    hidden: bool,
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "(", suf = ")", sep = " ", iter = .children.iter().filter(|c| !c.hidden))]
+struct IterTypeLevel {
+    children: Vec<IterChild>,
+}
+
+#[derive(SyntaxFmtDerive)]
+enum IterVariant {
+    #[syntax(pre = "sum ")]
+    Sum(#[syntax(sep = " + ", iter = .iter().map(|n| n * 2))] Vec<i32>),
+    #[syntax(pre = "range ")]
+    Range(#[syntax(sep = ",", iter = 0..3)] ()),
+}
+
+#[test]
+fn test_iter_attribute() {
+    let node = IterTypeLevel {
+        children: vec![
+            IterChild { name: "a", hidden: false },
+            IterChild { name: "b", hidden: true },
+            IterChild { name: "c", hidden: false },
+        ],
+    };
+    assert_eq!(format!("{}", syntax_fmt(&node)), "(a c)");
+    assert_eq!(format!("{}", syntax_fmt(&IterVariant::Sum(vec![1, 2, 3]))), "sum 2 + 4 + 6");
+    assert_eq!(format!("{}", syntax_fmt(&IterVariant::Range(()))), "range 0,1,2");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "<", suf = ">")]
+struct GenericParams {
+    #[syntax(sep = ", ", cont = syntaxfmt::iter(self.names.iter()).with_index(
+        |i, name, f| if i == 0 { write!(f, "{name}") } else { write!(f, "{name}: 'a") }
+    ))]
+    names: Vec<&'static str>,
+}
+
+#[test]
+fn test_iter_with_index() {
+    let params = GenericParams { names: vec!["'a", "'b", "'c"] };
+    assert_eq!(format!("{}", syntax_fmt(&params)), "<'a, 'b: 'a, 'c: 'a>");
+    let empty = syntaxfmt::iter(Vec::<i32>::new());
+    assert_eq!(format!("{}", syntax_fmt(&empty)), "");
+}
+
+// =============================================================================
+// sequence positions
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+struct EnglishList {
+    #[syntax(sep = ", ", last_sep = " and ")]
+    items: Vec<&'static str>,
+}
+
+#[test]
+fn test_last_sep() {
+    let list = |items| EnglishList { items };
+    assert_eq!(format!("{}", syntax_fmt(&list(vec!["a", "b", "c"]))), "a, b and c");
+    assert_eq!(format!("{}", syntax_fmt(&list(vec!["a", "b"]))), "a and b");
+    assert_eq!(format!("{}", syntax_fmt(&list(vec!["a"]))), "a");
+}
+
+fn position_formatter<S>(value: &PositionedItem, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+    let position = f.position().expect("formatted within a sequence");
+    let first = if position.is_first() { "first " } else { "" };
+    let last = if position.is_last() { " last" } else { "" };
+    write!(f, "{first}{}:{}{:?}{last}", value.0, position.index(), position.len())
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(cont_with = position_formatter)]
+struct PositionedItem(char);
+
+#[derive(SyntaxFmtDerive)]
+struct PositionedItems {
+    #[syntax(sep = " | ")]
+    slice: Vec<PositionedItem>,
+    #[syntax(pre = " / ", sep = " | ", iter = .iter().filter(|i| i.0 != 'x'))]
+    filtered: Vec<PositionedItem>,
+}
+
+#[test]
+fn test_position() {
+    let items = PositionedItems {
+        slice: vec![PositionedItem('a'), PositionedItem('b')],
+        filtered: vec![PositionedItem('c'), PositionedItem('x')],
+    };
+    assert_eq!(
+        format!("{}", syntax_fmt(&items)),
+        "first a:0Some(2) | b:1Some(2) last / first c:0None last"
+    );
+    let tuple = (PositionedItem('t'), 1, PositionedItem('u'));
+    assert_eq!(format!("{}", syntax_fmt(&tuple)), "first t:0Some(3)1u:2Some(3) last");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(sep = ", ")]
+struct LastSepPair {
+    #[syntax(sep = "-")]
+    left: Vec<i32>,
+    right: i32,
+}
+
+#[derive(SyntaxFmtDerive)]
+struct LastSepOuter {
+    #[syntax(sep = "; ", last_sep = "; or ")]
+    pairs: Vec<LastSepPair>,
+}
+
+#[test]
+fn test_last_sep_nested() {
+    // Only the sequence in the context which set `last_sep` uses it
+    let outer = LastSepOuter {
+        pairs: vec![
+            LastSepPair { left: vec![1, 2], right: 3 },
+            LastSepPair { left: vec![4, 5], right: 6 },
+        ],
+    };
+    assert_eq!(format!("{}", syntax_fmt(&outer)), "1-2, 3; or 4-5, 6");
+}
+
+// =============================================================================
+// collection transforms
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "use ", suf = ";")]
+struct Import(&'static str);
+
+impl PartialEq for Import {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+fn by_path(a: &Import, b: &Import) -> std::cmp::Ordering {
+    a.0.cmp(b.0)
+}
+
+#[derive(SyntaxFmtDerive)]
+struct Imports {
+    #[syntax(sep = " ", sort_by = by_path, dedup)]
+    sorted: Vec<Import>,
+    #[syntax(pre = " |", sep = " ", filter = |i| i.0.starts_with("std"), rev)]
+    std_rev: Vec<Import>,
+}
+
+#[test]
+fn test_transforms() {
+    let imports = vec![Import("b"), Import("std::a"), Import("b"), Import("a"), Import("std::c")];
+    let imports = Imports {
+        sorted: imports.iter().map(|i| Import(i.0)).collect(),
+        std_rev: imports,
+    };
+    assert_eq!(
+        format!("{}", syntax_fmt(&imports)),
+        "use a; use b; use std::a; use std::c; |use std::c; use std::a;"
+    );
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = i32)]
+struct Numbers {
+    #[syntax(sep = ",", sort, rev, dedup)]
+    desc: Vec<i32>,
+    #[syntax(pre = " ", sep = ",", filter = |n, min: &i32| *n >= min)]
+    lazy: Vec<i32>,
+    #[syntax(pre = " ", sep = ",", filter = |n, min: &i32| *n >= min, sort)]
+    sorted: Vec<i32>,
+}
+
+#[test]
+fn test_transforms_with_state() {
+    let numbers = Numbers { desc: vec![1, 3, 2, 3], lazy: vec![5, 1, 4], sorted: vec![5, 1, 4] };
+    assert_eq!(format!("{}", syntax_fmt(&numbers).state(&4)), "3,2,1 5,4 4,5");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = i32)]
+struct Deduped {
+    #[syntax(sep = ",", filter = |n| **n != 2, dedup)]
+    plain: Vec<i32>,
+    #[syntax(pre = " ", sep = ",", filter = |n, skip: &i32| *n != skip, dedup)]
+    with_state: Vec<i32>,
+}
+
+// Filtering runs first, so duplicates it brings together are removed
+#[test]
+fn test_filter_then_dedup() {
+    let deduped = Deduped { plain: vec![1, 2, 1], with_state: vec![3, 4, 3] };
+    assert_eq!(format!("{}", syntax_fmt(&deduped).state(&4)), "1 3");
+}
+
+type Log = std::cell::RefCell<Vec<String>>;
+
+#[derive(Clone)]
+struct Logged(&'static str);
+
+impl<A> SyntaxFmt<Log, A> for Logged {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<Log, A>) -> std::fmt::Result {
+        f.state().borrow_mut().push(format!("fmt {}", self.0));
+        f.write_str(self.0)
+    }
+}
+
+// Each item is tested after the previous one is written, unless the last one must be found
+#[test]
+fn test_filter_state_order() {
+    let items = syntaxfmt::iter([Logged("a"), Logged("b"), Logged("c")]);
+    let items = items.filter_state(|item: &Logged, log: &Log| {
+        log.borrow_mut().push(format!("test {}", item.0));
+        item.0 != "c"
+    });
+    let log = Log::default();
+    assert_eq!(format!("{}", syntax_fmt(&items).state(&log)), "ab");
+    assert_eq!(log.into_inner(), ["test a", "fmt a", "test b", "fmt b", "test c"]);
+
+    let log = Log::default();
+    let list = LastSepLogged(vec![Logged("a"), Logged("b"), Logged("c")]);
+    assert_eq!(format!("{}", syntax_fmt(&list).state(&log)), "a and b");
+    assert_eq!(log.into_inner(), ["test a", "test b", "fmt a", "test c", "fmt b"]);
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = Log)]
+struct LastSepLogged(
+    #[syntax(sep = ", ", last_sep = " and ", filter = |item, log: &Log| {
+        log.borrow_mut().push(format!("test {}", item.0));
+        item.0 != "c"
+    })]
+    Vec<Logged>,
+);
+
+fn bump(value: &i32, f: &mut SyntaxFormatter<i32, Mutable>) -> std::fmt::Result {
+    *f.state_mut() += 10;
+    write!(f, "{value}")
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = i32, state_mut)]
+struct Bump(#[syntax(cont_with = bump)] i32);
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = i32, state_mut)]
+struct Bumps(#[syntax(sep = ",", filter = |b, min: &i32| b.0 > *min)] Vec<Bump>);
+
+#[test]
+fn test_filter_state_sees_mutations() {
+    let mut state = 0;
+    let bumps = Bumps(vec![Bump(5), Bump(8), Bump(25)]);
+    assert_eq!(format!("{}", syntax_fmt(&bumps).state_mut(&mut state)), "5,25");
+    assert_eq!(state, 20);
+}
+
+// =============================================================================
+// depth limits
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+enum DepthExpr {
+    #[syntax(pre = "(", suf = ")", sep = " + ")]
+    Add(Box<DepthExpr>, Box<DepthExpr>),
+    #[syntax(pre = "{", suf = "}")]
+    Block(DepthBlock),
+    Lit(i32),
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(elide = "{...}")]
+struct DepthBlock(Box<DepthExpr>);
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(elide = false)]
+struct DepthName(&'static str);
+
+fn depth_formatter<S>(_: &DepthProbe, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+    write!(f, "d{}", f.depth())
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(cont_with = depth_formatter)]
+struct DepthProbe;
+
+fn add(a: DepthExpr, b: DepthExpr) -> DepthExpr {
+    DepthExpr::Add(Box::new(a), Box::new(b))
+}
+
+#[test]
+fn test_max_depth() {
+    let expr = add(DepthExpr::Lit(1), add(DepthExpr::Lit(2), DepthExpr::Lit(3)));
+    assert_eq!(format!("{}", syntax_fmt(&expr)), "(1 + (2 + 3))");
+    assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(2)), "(1 + (... + ...))");
+    assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(1).elision("…")), "(… + …)");
+    assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(0)), "...");
+}
+
+#[test]
+fn test_max_depth_placeholders() {
+    let block = DepthExpr::Block(DepthBlock(Box::new(DepthExpr::Lit(1))));
+    assert_eq!(format!("{}", syntax_fmt(&block).max_depth(3)), "{1}");
+    assert_eq!(format!("{}", syntax_fmt(&block).max_depth(1)), "{{...}}");
+
+    // Types which opt out are never elided, and don't count towards depth
+    let names = (DepthName("a"), DepthProbe);
+    assert_eq!(format!("{}", syntax_fmt(&names).max_depth(0)), "a...");
+    assert_eq!(format!("{}", syntax_fmt(&DepthName("b")).max_depth(0)), "b");
+    assert_eq!(format!("{}", syntax_fmt(&names)), "ad1");
+}
+
+#[test]
+fn test_max_depth_bounds_recursion() {
+    let mut expr = DepthExpr::Lit(0);
+    for i in 1..100_000 {
+        expr = add(expr, DepthExpr::Lit(i));
+    }
+    let text = format!("{}", syntax_fmt(&expr).max_depth(3));
+    assert_eq!(text, "(((... + ...) + 99998) + 99999)");
+    // Dropping a tree this deep would overflow the stack
+    std::mem::forget(expr);
+}
+
+// =============================================================================
+// width limits
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+struct WidthCall {
+    name: &'static str,
+    #[syntax(pre = "(", suf = ")", sep = ", ")]
+    args: Vec<WidthArg>,
+}
+
+#[derive(SyntaxFmtDerive)]
+enum WidthArg {
+    Name(&'static str),
+    Call(WidthCall),
+    #[syntax(pre = "[", suf = "]")]
+    List(#[syntax(sep = ", ")] Vec<&'static str>),
+}
+
+fn width_call(name: &'static str, args: Vec<WidthArg>) -> WidthCall {
+    WidthCall { name, args }
+}
+
+#[test]
+fn test_max_width() {
+    let call = width_call("f", vec![WidthArg::Name("alpha"), WidthArg::Name("beta")]);
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(100)), "f(alpha, beta)");
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(6)), "f(alpha, …");
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(0)), "…");
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(0).balanced()), "…");
+}
+
+#[test]
+fn test_max_width_balanced() {
+    let inner = width_call("g", vec![WidthArg::List(vec!["x", "y", "z"]), WidthArg::Name("w")]);
+    let call = width_call("f", vec![WidthArg::Call(inner), WidthArg::Name("v")]);
+    assert_eq!(format!("{}", syntax_fmt(&call)), "f(g([x, y, z], w), v)");
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(7)), "f(g([x, …");
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(7).balanced()), "f(g([x, …]))");
+    assert_eq!(
+        format!("{}", syntax_fmt(&call).max_width(7).balanced().ellipsis("..")),
+        "f(g([x, ..]))"
+    );
+}
+
+#[test]
+fn test_max_width_counts_chars() {
+    let call = width_call("f", vec![WidthArg::Name("ü"), WidthArg::Name("é"), WidthArg::Name("ö")]);
+    assert_eq!(format!("{}", syntax_fmt(&call).max_width(6).balanced()), "f(ü, é, …)");
+}
+
+#[test]
+fn test_max_width_with_depth() {
+    let inner = width_call("g", vec![WidthArg::Name("x")]);
+    let call = width_call("f", vec![WidthArg::Call(inner), WidthArg::Name("long_name")]);
+    let display = syntax_fmt(&call).max_depth(3).max_width(12).balanced();
+    assert_eq!(format!("{display}"), "f(g(...), long_name)");
+    let display = syntax_fmt(&call).max_depth(3).max_width(8).balanced();
+    assert_eq!(format!("{display}"), "f(g(...), …)");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "<", suf = ">")]
+struct WidthDisplay(DisplayName, #[syntax(pre = " ")] DisplayName, #[syntax(pre = " ")] DisplayName);
+
+#[test]
+fn test_max_width_display_fields() {
+    let value = WidthDisplay(DisplayName("123456"), DisplayName("yyyy"), DisplayName("zzzz"));
+    assert_eq!(format!("{}", syntax_fmt(&value).max_width(100)), "<123456 yyyy zzzz>");
+    assert_eq!(format!("{}", syntax_fmt(&value).max_width(8)), "<123456 yyyy…");
+    assert_eq!(format!("{}", syntax_fmt(&value).max_width(4).balanced()), "<123456…>");
+    let names = [DisplayName("123456"), DisplayName("yyyy"), DisplayName("zzzz")];
+    assert_eq!(format!("{}", syntax_fmt(&names).max_width(8)), "123456yyyy…");
+}
+
+// Spaces inserted between joined tokens count towards the width
+#[test]
+fn test_max_width_join_tokens() {
+    let items = ["a", "b", "c", "d", "e"];
+    assert_eq!(format!("{}", syntax_fmt(&items).join_tokens(TokenClass::DEFAULT)), "a b c d e");
+    let display = syntax_fmt(&items).join_tokens(TokenClass::DEFAULT).max_width(3);
+    assert_eq!(format!("{display}"), "a b…");
+    // Checked between tokens written by one node too
+    let stmt = JoinedLet(DisplayName("x"), -1);
+    let display = syntax_fmt(&stmt).join_tokens(TokenClass::DEFAULT).max_width(3);
+    assert_eq!(format!("{display}"), "let…");
+}
+
+// =============================================================================
+// state components
+// =============================================================================
+
+// A reusable type which only needs the components it reads
+#[derive(SyntaxFmtDerive)]
+#[syntax(bound = StateComponents, state_mut)]
+struct NamedTemp(#[syntax(cont_with = named_temp)] &'static str);
+
+struct TempPrefix(&'static str);
+
+#[derive(Debug, PartialEq)]
+struct TempCount(usize);
+
+fn named_temp<S: StateComponents>(name: &str, f: &mut SyntaxFormatter<S, Mutable>) -> std::fmt::Result {
+    let prefix = f.get::<TempPrefix>().0;
+    let id = {
+        let mut count = f.get_mut::<TempCount>();
+        count.0 += 1;
+        count.0
+    };
+    write!(f, "{prefix}{name}{id}")
+}
+
+// Application state stored alongside the reusable type's components
+struct Mangle(bool);
+
+fn mangled<S: StateComponents, A>(name: &str, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+    if f.get::<Mangle>().0 {
+        write!(f, "_Z{name}")
+    } else {
+        f.write_str(name)
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "call ", bound = StateComponents, state_mut)]
+struct TempCall {
+    #[syntax(cont_with = mangled)]
+    func: &'static str,
+    #[syntax(pre = "(", suf = ")", sep = ", ")]
+    args: Vec<NamedTemp>,
+}
+
+#[test]
+fn test_state_components() {
+    let call = TempCall {
+        func: "f",
+        args: vec![NamedTemp("a"), NamedTemp("b")],
+    };
+    let mut state = StateMap::new().with(TempPrefix("%")).with(TempCount(0)).with(Mangle(true));
+    assert_eq!(format!("{}", syntax_fmt(&call).state_mut(&mut state)), "call _Zf(%a1, %b2)");
+    assert_eq!(state.insert(Mangle(false)).map(|m| m.0), Some(true));
+    assert_eq!(format!("{}", syntax_fmt(&call).state_mut(&mut state)), "call f(%a3, %b4)");
+    assert_eq!(state.remove::<TempCount>(), Some(TempCount(4)));
+    assert!(!state.contains::<TempCount>());
+}
+
+#[test]
+#[should_panic(expected = "syntaxfmt state has no component")]
+fn test_state_component_missing() {
+    let mut state = StateMap::new().with(TempPrefix("%"));
+    let _ = format!("{}", syntax_fmt(&NamedTemp("a")).state_mut(&mut state));
+}
+
+// =============================================================================
+// state borrows
+// =============================================================================
+
+const BORROW_WORDS: ReservedWords = ReservedWords::new(&["fn"], IdentEscape::Prefix("r#"));
+
+struct BorrowState {
+    count: usize,
+}
+
+impl ReservedWordsSource for BorrowState {
+    fn reserved_words(&self) -> &ReservedWords {
+        &BORROW_WORDS
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = BorrowState)]
+struct BorrowIdent(#[syntax(ident = state)] &'static str);
+
+// Formats a child while holding mutable state
+struct Holding<T>(T);
+
+impl<T: SyntaxFmt<BorrowState>> SyntaxFmt<BorrowState, Mutable> for Holding<T> {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<BorrowState, Mutable>) -> std::fmt::Result {
+        f.try_map_state_mut(|f, s| {
+            s.count += 1;
+            self.0.syntax_fmt(f.as_immutable())
+        })?
+    }
+}
+
+fn try_write<T: std::fmt::Display>(value: T) -> Result<String, std::fmt::Error> {
+    use std::fmt::Write;
+    let mut out = String::new();
+    write!(out, "{value}").map(|_| out)
+}
+
+#[test]
+fn test_state_borrow_errors() {
+    let mut state = BorrowState { count: 0 };
+    assert_eq!(try_write(syntax_fmt(&BorrowIdent("fn")).state(&state)).unwrap(), "r#fn");
+    assert!(try_write(syntax_fmt(&Holding(BorrowIdent("fn"))).state_mut(&mut state)).is_err());
+    assert!(try_write(syntax_fmt(&Holding("x")).state_mut(&mut state)).is_ok());
+    assert_eq!(state.count, 2);
+}
+
+#[test]
+fn test_state_try_accessors() {
+    struct Check;
+    impl SyntaxFmt<BorrowState, Mutable> for Check {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<BorrowState, Mutable>) -> std::fmt::Result {
+            {
+                let count = f.try_state().unwrap().count;
+                assert_eq!(count, 0);
+            }
+            f.try_map_state(|f, _| {
+                assert_eq!(f.try_state().map(|s| s.count).ok(), Some(0));
+                assert_eq!(f.try_state_mut().err(), Some(StateError::Borrowed));
+            })?;
+            f.try_state_mut()?.count = 5;
+            let count = f.try_state()?.count;
+            write!(f, "{count}")
+        }
+    }
+
+    let mut state = BorrowState { count: 0 };
+    assert_eq!(try_write(syntax_fmt(&Check).state_mut(&mut state)).unwrap(), "5");
+}
+
+#[test]
+fn test_state_error_messages() {
+    assert_eq!(StateError::BorrowedMut.to_string(), "syntaxfmt state is already mutably borrowed");
+    assert_eq!(StateError::Borrowed.to_string(), "syntaxfmt state is already borrowed");
+    assert_eq!(
+        StateError::MissingComponent("Foo").to_string(),
+        "syntaxfmt state has no component `Foo`"
+    );
+
+    let state = StateMap::new();
+    struct Missing;
+    impl SyntaxFmt<StateMap> for Missing {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<StateMap>) -> std::fmt::Result {
+            let err = f.try_get::<TempPrefix>().err().unwrap();
+            assert!(matches!(err, StateError::MissingComponent(name) if name.ends_with("TempPrefix")));
+            Err(err.into())
+        }
+    }
+    assert!(try_write(syntax_fmt(&Missing).state(&state)).is_err());
+}
+
+#[test]
+#[should_panic(expected = "syntaxfmt state is already borrowed")]
+fn test_state_mut_while_borrowed() {
+    struct Nested;
+    impl SyntaxFmt<BorrowState, Mutable> for Nested {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<BorrowState, Mutable>) -> std::fmt::Result {
+            f.map_state(|f, _| f.state_mut().count += 1);
+            Ok(())
+        }
+    }
+    let mut state = BorrowState { count: 0 };
+    let _ = try_write(syntax_fmt(&Nested).state_mut(&mut state));
+}
+
+// =============================================================================
+// errors
+// =============================================================================
+
+fn checked_name<S, A>(name: &&'static str, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+    match *name {
+        "" => Err(f.error("empty name")),
+        "?" => Err(std::fmt::Error),
+        name => f.write_str(name),
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "fn ")]
+struct ErrFn {
+    #[syntax(cont_with = checked_name)]
+    r#name: &'static str,
+    #[syntax(pre = " { ", suf = " }", sep = "; ")]
+    body: Vec<ErrStmt>,
+}
+
+#[derive(SyntaxFmtDerive)]
+enum ErrStmt {
+    #[syntax(pre = "let ", sep = " = ")]
+    Let(#[syntax(cont_with = checked_name)] &'static str, ErrExpr),
+    Expr(ErrExpr),
+}
+
+#[derive(SyntaxFmtDerive)]
+enum ErrExpr {
+    Var(#[syntax(cont_with = checked_name)] &'static str),
+    #[syntax(pre = "{", suf = "}", sep = ", ", kv_sep = ": ")]
+    Record(BTreeMap<&'static str, ErrExpr>),
+}
+
+#[test]
+fn test_error_path() {
+    let ok = ErrFn { name: "f", body: vec![ErrStmt::Expr(ErrExpr::Var("x"))] };
+    assert_eq!(syntax_fmt(&ok).try_to_string().unwrap(), "fn f { x }");
+
+    let err = ErrFn { name: "", body: vec![] };
+    let err = syntax_fmt(&err).try_to_string().unwrap_err();
+    assert_eq!(err.message(), "empty name");
+    assert_eq!(err.path(), [PathSegment::Type("ErrFn"), PathSegment::Field("name")]);
+
+    let err = ErrFn {
+        name: "f",
+        body: vec![
+            ErrStmt::Expr(ErrExpr::Var("x")),
+            ErrStmt::Let("y", ErrExpr::Record(BTreeMap::from([("a", ErrExpr::Var("b")), ("c", ErrExpr::Var(""))]))),
+        ],
+    };
+    let err = syntax_fmt(&err).try_to_string().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "empty name at ErrFn.body[1] > ErrStmt::Let.1 > ErrExpr::Record.0[1] > ErrExpr::Var.0"
+    );
+}
+
+#[test]
+fn test_error_without_message() {
+    let err = ErrFn { name: "f", body: vec![ErrStmt::Let("?", ErrExpr::Var("x"))] };
+    let err = syntax_fmt(&err).try_to_string().unwrap_err();
+    assert_eq!(err.to_string(), "syntaxfmt formatting failed at ErrFn.body[0] > ErrStmt::Let.0");
+
+    assert_eq!(SyntaxError::new("bad").to_string(), "bad");
+}
+
+#[test]
+fn test_error_first_recorded() {
+    struct Outer(ErrExpr);
+    impl<S, A> SyntaxFmt<S, A> for Outer {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+            self.0.syntax_fmt(f).map_err(|_| f.error("outer"))
+        }
+    }
+    let err = syntax_fmt(&Outer(ErrExpr::Var(""))).try_to_string().unwrap_err();
+    assert_eq!(err.to_string(), "empty name at ErrExpr::Var.0");
+}
+
+#[test]
+fn test_error_from_state() {
+    let mut state = BorrowState { count: 0 };
+    let err = syntax_fmt(&Holding(BorrowIdent("fn"))).state_mut(&mut state).try_to_string().unwrap_err();
+    assert_eq!(err.to_string(), "syntaxfmt state is already mutably borrowed at BorrowIdent.0");
+}
+
+#[test]
+fn test_error_from_try_accessors() {
+    // State errors returned with `?` keep their message
+    struct ReadCount;
+    impl<A> SyntaxFmt<BorrowState, A> for ReadCount {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<BorrowState, A>) -> std::fmt::Result {
+            let count = f.try_state()?.count;
+            write!(f, "{count}")
+        }
+    }
+    let mut state = BorrowState { count: 0 };
+    let err = syntax_fmt(&Holding(ReadCount)).state_mut(&mut state).try_to_string().unwrap_err();
+    assert_eq!(err.to_string(), "syntaxfmt state is already mutably borrowed");
+
+    struct Missing;
+    impl SyntaxFmt<StateMap, Mutable> for Missing {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<StateMap, Mutable>) -> std::fmt::Result {
+            f.try_get_mut::<TempCount>()?.0 += 1;
+            Ok(())
+        }
+    }
+    let mut state = StateMap::new();
+    let err = syntax_fmt(&Missing).state_mut(&mut state).try_to_string().unwrap_err();
+    assert!(err.to_string().starts_with("syntaxfmt state has no component `"));
+}
+
+// =============================================================================
+// snapshots
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "{", suf = "}", nl = inner, ind)]
+struct SnapBlock(#[syntax(sep = [", ", ","], nl = sep)] Vec<&'static str>);
+
+#[test]
+fn test_snapshot() {
+    assert_syntax_snapshot!(syntax_fmt(&SnapBlock(vec!["a", "b"])));
+    assert_syntax_snapshot!("test_snapshot_empty", syntax_fmt(&SnapBlock(vec![])));
+}
+
+fn temp_snapshot(name: &str) -> Snapshot {
+    let path = std::env::temp_dir()
+        .join(format!("syntaxfmt-{}", std::process::id()))
+        .join(format!("{name}.snap"));
+    let _ = std::fs::remove_file(&path);
+    Snapshot::new(path)
+}
+
+#[test]
+fn test_snapshot_update_and_diff() {
+    let snapshot = temp_snapshot("update_and_diff");
+    snapshot.clone().update(true).assert(syntax_fmt(&SnapBlock(vec!["a", "b", "c"])));
+    assert_eq!(
+        std::fs::read_to_string(snapshot.path()).unwrap(),
+        "--- normal ---\n{a, b, c}\n--- pretty ---\n{\n    a,\n    b,\n    c\n}\n"
+    );
+    snapshot.clone().update(false).assert(syntax_fmt(&SnapBlock(vec!["a", "b", "c"])));
+
+    let panic = std::panic::catch_unwind(|| {
+        snapshot.clone().update(false).assert(syntax_fmt(&SnapBlock(vec!["a", "x", "c"])));
+    })
+    .unwrap_err();
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.contains("differs, set SYNTAXFMT_UPDATE_SNAPSHOTS=1 to update it"));
+    assert!(message.ends_with(
+        "@@ -1,8 +1,8 @@\n --- normal ---\n-{a, b, c}\n+{a, x, c}\n --- pretty ---\n {\n     a,\n-    b,\n+    x,\n     c\n }\n"
+    ));
+}
+
+#[test]
+fn test_snapshot_final_newline() {
+    let snapshot = temp_snapshot("final_newline");
+    snapshot.clone().update(true).assert(syntax_fmt(&SnapBlock(vec![])));
+    let text = std::fs::read_to_string(snapshot.path()).unwrap();
+    std::fs::write(snapshot.path(), text.strip_suffix('\n').unwrap()).unwrap();
+    let message = panic_message(|| snapshot.clone().update(false).assert(syntax_fmt(&SnapBlock(vec![]))));
+    assert!(message.ends_with("-}\n\\ No newline at end of file\n+}\n"));
+}
+
+#[test]
+#[should_panic(expected = "doesn't exist, set SYNTAXFMT_UPDATE_SNAPSHOTS=1 to create it")]
+fn test_snapshot_missing() {
+    temp_snapshot("missing").update(false).assert(syntax_fmt(&SnapBlock(vec![])));
+}
+
+#[test]
+#[should_panic(expected = "syntaxfmt normal formatting failed: empty name at ErrExpr::Var.0")]
+fn test_snapshot_error() {
+    temp_snapshot("error").update(true).assert(syntax_fmt(&ErrExpr::Var("")));
+}
+
+// =============================================================================
+// syntax assertions
+// =============================================================================
+
+fn panic_message(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+    let panic = std::panic::catch_unwind(f).unwrap_err();
+    panic.downcast_ref::<String>().cloned().unwrap()
+}
+
+#[test]
+fn test_assert_syntax_eq() {
+    let block = SnapBlock(vec!["a", "b"]);
+    assert_syntax_eq!(block, Mode::Normal, "{a, b}");
+    assert_syntax_eq!(block, Mode::Pretty, "{\n    a,\n    b\n}");
+    assert_syntax_eq!(syntax_fmt(&block).indent(["", "\t"]), Mode::Pretty, "{\n\ta,\n\tb\n}");
+
+    let state = BorrowState { count: 0 };
+    assert_syntax_eq!(syntax_fmt(&BorrowIdent("fn")).state(&state), Mode::Normal, "r#fn");
+}
+
+#[test]
+fn test_assert_syntax_eq_diff() {
+    let message = panic_message(|| {
+        let block = SnapBlock(vec!["a", "b"]);
+        assert_syntax_eq!(syntax_fmt(&block).indent(["", "\t"]), Mode::Pretty, "{\n    a, \n    b\n}");
+    });
+    assert_eq!(
+        message,
+        "syntaxfmt pretty output differs from expected \
+         (-expected +actual, · is a trailing space, → is a tab, ␍ is a carriage return)\n\
+         @@ -1,4 +1,4 @@\n \
+         {\n\
+         -    a,·\n\
+         -    b\n\
+         +→a,\n\
+         +→b\n \
+         }\n"
+    );
+
+    let message = panic_message(|| assert_syntax_eq!(SnapBlock(vec![]), Mode::Normal, "{ }"));
+    assert!(message.starts_with("syntaxfmt normal output differs"));
+    assert!(message.ends_with("@@ -1,1 +1,1 @@\n-{ }\n+{}\n"));
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(suf = "\n")]
+struct Line(&'static str);
+
+#[test]
+fn test_assert_syntax_eq_final_newline() {
+    let message = panic_message(|| assert_syntax_eq!(Line("a"), Mode::Normal, "a"));
+    assert!(message.ends_with("@@ -1,1 +1,1 @@\n-a\n\\ No newline at end of file\n+a\n"));
+    let message = panic_message(|| assert_syntax_eq!(SnapBlock(vec![]), Mode::Normal, "{}\n"));
+    assert!(message.ends_with("@@ -1,1 +1,1 @@\n-{}\n+{}\n\\ No newline at end of file\n"));
+    let message = panic_message(|| assert_syntax_eq!(Line(""), Mode::Normal, ""));
+    assert!(message.ends_with("@@ -1,0 +1,1 @@\n+\n"));
+}
+
+#[test]
+fn test_assert_syntax_eq_carriage_return() {
+    let block = SnapBlock(vec!["a", "b"]);
+    let message = panic_message(|| {
+        assert_syntax_eq!(syntax_fmt(&block).newline(["", "\r\n"]), Mode::Pretty, "{\n    a,\n    b\n}");
+    });
+    assert!(message.ends_with("-{\n-    a,\n-    b\n+{␍\n+    a,␍\n+    b␍\n }\n"));
+}
+
+#[test]
+#[should_panic(expected = "syntaxfmt pretty formatting failed: empty name at ErrExpr::Var.0")]
+fn test_assert_syntax_eq_error() {
+    assert_syntax_eq!(ErrExpr::Var(""), Mode::Pretty, "");
+}
+
+// =============================================================================
+// round trips
+// =============================================================================
+
+#[derive(SyntaxFmtDerive, Debug, Clone, PartialEq)]
+#[syntax(sep = [",", ", "])]
+struct RtPair(u8, u8);
+
+fn parse_pair(text: &str) -> Result<RtPair, String> {
+    let (a, b) = text.split_once(',').ok_or("expected `,`")?;
+    let num = |t: &str| t.trim().parse::<u8>().map_err(|e| e.to_string());
+    Ok(RtPair(num(a)?, num(b)?))
+}
+
+// Missing a separator, so the fields run together
+#[derive(SyntaxFmtDerive, Debug, Clone, PartialEq)]
+struct RtBadPair(u8, u8);
+
+fn parse_bad_pair(text: &str) -> Result<RtBadPair, String> {
+    let num = |t: &str| t.parse::<u8>().map_err(|e| e.to_string());
+    match text.len() {
+        0 | 1 => Err("expected two numbers".into()),
+        _ => Ok(RtBadPair(num(&text[..1])?, num(&text[1..])?)),
+    }
+}
+
+impl quickcheck::Arbitrary for RtBadPair {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        RtBadPair(u8::arbitrary(g), u8::arbitrary(g))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new((self.0, self.1).shrink().map(|(a, b)| RtBadPair(a, b)))
+    }
+}
+
+#[derive(SyntaxFmtDerive, Debug, Clone, PartialEq)]
+enum RtTerm {
+    Num(u8),
+    #[syntax(pre = "-")]
+    Neg(Box<RtTerm>),
+}
+
+// Cancels double negation, so doesn't preserve the formatted text
+fn parse_term(text: &str) -> Result<RtTerm, String> {
+    let digits = text.trim_start_matches('-');
+    let mut term = RtTerm::Num(digits.parse().map_err(|e: std::num::ParseIntError| e.to_string())?);
+    if (text.len() - digits.len()) % 2 == 1 {
+        term = RtTerm::Neg(Box::new(term));
+    }
+    Ok(term)
+}
+
+#[test]
+fn test_round_trip() {
+    RoundTrip::new(parse_pair).compare_values().assert(&RtPair(12, 3));
+    RoundTrip::new(parse_term).assert(&RtTerm::Neg(Box::new(RtTerm::Num(1))));
+
+    assert_eq!(
+        RoundTrip::new(parse_bad_pair).compare_values().check(&RtBadPair(12, 3)),
+        Err(RoundTripError::ValueChanged {
+            mode: Mode::Normal,
+            text: "123".into(),
+            difference: "RtBadPair(12, 3) != RtBadPair(1, 23)".into(),
+        })
+    );
+    assert!(RoundTrip::new(parse_bad_pair).check(&RtBadPair(12, 3)).is_ok());
+
+    let error = RoundTrip::new(parse_bad_pair).check(&RtBadPair(25, 50)).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "syntaxfmt normal output doesn't parse: number too large to fit in target type\n2550"
+    );
+
+    let error = RoundTrip::new(parse_term)
+        .check(&RtTerm::Neg(Box::new(RtTerm::Neg(Box::new(RtTerm::Num(1))))))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "syntaxfmt normal output changes when parsed and formatted again \
+         (-expected +actual, · is a trailing space, → is a tab, ␍ is a carriage return)\n\
+         @@ -1,1 +1,1 @@\n---1\n+1\n"
+    );
+}
+
+#[test]
+#[should_panic(expected = "syntaxfmt normal formatting failed: empty name at ErrExpr::Var.0")]
+fn test_round_trip_format_error() {
+    fn parse(_: &str) -> Result<ErrExpr, String> {
+        Ok(ErrExpr::Var(""))
+    }
+    RoundTrip::new(parse).assert(&ErrExpr::Var("x"));
+}
+
+#[test]
+fn test_round_trip_proptest() {
+    use proptest::prelude::*;
+
+    RoundTrip::new(parse_pair)
+        .compare_values()
+        .proptest((any::<u8>(), any::<u8>()).prop_map(|(a, b)| RtPair(a, b)));
+
+    let message = panic_message(|| {
+        RoundTrip::new(parse_bad_pair)
+            .compare_values()
+            .proptest((any::<u8>(), any::<u8>()).prop_map(|(a, b)| RtBadPair(a, b)));
+    });
+    assert!(message.contains("minimal failing input: RtBadPair("), "{message}");
+}
+
+#[test]
+fn test_round_trip_quickcheck() {
+    let message = panic_message(|| RoundTrip::new(parse_bad_pair).compare_values().quickcheck());
+    assert!(message.contains("[quickcheck] TEST FAILED"), "{message}");
+    assert!(message.contains("\nfor RtBadPair("), "{message}");
+}
+
+// =============================================================================
+// deep nesting
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "{", suf = "}", nl = [inner, cont], ind)]
+struct Nest(#[syntax(sep = ",", nl = sep)] Vec<Nested>);
+
+#[derive(SyntaxFmtDerive)]
+enum Nested {
+    Leaf(char, bool),
+    Nest(Nest),
+}
+
+fn nest(depth: usize) -> Nest {
+    let inner = match depth {
+        0 => Nested::Leaf('x', true),
+        _ => Nested::Nest(nest(depth - 1)),
+    };
+    Nest(vec![inner, Nested::Leaf('y', false)])
+}
+
+// Expected pretty output of `nest(depth)`, nested at `level`
+fn nested_text(depth: usize, level: usize, indent: &str) -> String {
+    let inner = match depth {
+        0 => "xtrue".to_string(),
+        _ => nested_text(depth - 1, level + 1, indent),
+    };
+    let pad = indent.repeat(level + 1);
+    format!("{{\n{pad}{inner},\n{pad}yfalse\n{}}}", indent.repeat(level))
+}
+
+#[test]
+fn test_deep_nesting() {
+    // Deep enough to outgrow the inline context stack and the space indent fast path
+    let tree = nest(40);
+    let normal = format!("{}", syntax_fmt(&tree));
+    assert_eq!(normal, format!("{}xtrue,yfalse{}}}", "{".repeat(41), "},yfalse".repeat(40)));
+    assert_syntax_eq!(tree, Mode::Pretty, &nested_text(40, 0, "    "));
+    assert_syntax_eq!(syntax_fmt(&tree).indent(["", "\t"]), Mode::Pretty, &nested_text(40, 0, "\t"));
+
+    // Each display starts from an empty context stack
+    assert_eq!(format!("{}", syntax_fmt(&nest(1)).pretty()), nested_text(1, 0, "    "));
+}
+
+// =============================================================================
+// scopes
+// =============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EnclosingFn(&'static str);
+
+struct InUnsafe;
+
+fn scoped_call<S>(name: &&'static str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+    let path: Vec<_> = f.scopes::<EnclosingFn>().map(|e| e.0).collect();
+    let unsafe_marker = if f.scope::<InUnsafe>().is_some() { "!" } else { "" };
+    write!(f, "{name}{unsafe_marker}@{}", path.join("<"))
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(sep = " ")]
+enum ScopedStmt {
+    Call(#[syntax(cont_with = scoped_call)] &'static str),
+    Fn(ScopedFn),
+    #[syntax(pre = "unsafe { ", suf = " }", scope = InUnsafe)]
+    Unsafe(#[syntax(sep = " ")] Vec<ScopedStmt>),
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "fn ", scope = EnclosingFn(self.name))]
+struct ScopedFn {
+    name: &'static str,
+    #[syntax(pre = " { ", suf = " }", sep = " ")]
+    body: Vec<ScopedStmt>,
+}
+
+#[test]
+fn test_scopes() {
+    let tree = ScopedFn {
+        name: "outer",
+        body: vec![
+            ScopedStmt::Call("a"),
+            ScopedStmt::Fn(ScopedFn {
+                name: "inner",
+                body: vec![ScopedStmt::Unsafe(vec![ScopedStmt::Call("b")])],
+            }),
+            ScopedStmt::Call("c"),
+        ],
+    };
+    assert_eq!(
+        format!("{}", syntax_fmt(&tree)),
+        "fn outer { a@outer fn inner { unsafe { b!@inner<outer } } c@outer }"
+    );
+}
+
+#[test]
+fn test_with_scope() {
+    let call = ScopedStmt::Call("x");
+    let text = format!("{}", syntax_fmt(&call));
+    assert_eq!(text, "x@");
+
+    struct Wrapped(ScopedStmt);
+    impl<S, A> SyntaxFmt<S, A> for Wrapped {
+        fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+            f.with_scope(EnclosingFn("manual"), |f| {
+                self.0.syntax_fmt(f)?;
+                assert_eq!(f.scope::<EnclosingFn>(), Some(&EnclosingFn("manual")));
+                Ok(())
+            })?;
+            assert_eq!(f.scope::<EnclosingFn>(), None);
+            Ok(())
+        }
+    }
+    assert_eq!(format!("{}", syntax_fmt(&Wrapped(call))), "x@manual");
+}
+
+// Scopes nested deeper than those held inline
+#[test]
+fn test_scopes_nested_deeply() {
+    const NAMES: [&str; 12] = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l"];
+    let mut stmt = ScopedStmt::Call("x");
+    for name in NAMES.iter().rev() {
+        stmt = ScopedStmt::Fn(ScopedFn { name, body: vec![stmt] });
+    }
+    let text = format!("{}", syntax_fmt(&stmt));
+    assert!(text.ends_with(" { x@l<k<j<i<h<g<f<e<d<c<b<a } } } } } } } } } } } }"), "{text}");
+}
+
+// =============================================================================
+// unions
+// =============================================================================
+
+#[derive(Clone, Copy)]
+enum ValueTag {
+    Int,
+    Float,
+}
+
+fn tagged_value_formatter(
+    value: &TaggedValue,
+    f: &mut SyntaxFormatter<ValueTag>,
+) -> std::fmt::Result {
+    let tag = *f.state();
+    // Safety: The tag in state identifies the active field
+    match tag {
+        ValueTag::Int => write!(f, "{}", unsafe { value.int }),
+        ValueTag::Float => write!(f, "{:?}", unsafe { value.float }),
+    }
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(state = ValueTag, cont_with = tagged_value_formatter)]
+union TaggedValue {
+    int: i32,
+    float: f32,
+}
+
+#[test]
+fn test_union_cont_with() {
+    let value = TaggedValue { float: 1.5 };
+    assert_eq!(format!("{}", syntax_fmt(&value).state(&ValueTag::Float)), "1.5");
+
+    let value = TaggedValue { int: 7 };
+    assert_eq!(format!("{}", syntax_fmt(&value).state(&ValueTag::Int)), "7");
+}
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "<", suf = ">", cont = "opaque")]
+union OpaqueUnion {
+    _bits: u32,
+}
+
+#[test]
+fn test_union_cont() {
+    assert_eq!(format!("{}", syntax_fmt(&OpaqueUnion { _bits: 0 })), "<opaque>");
+}
+
+// =============================================================================
+// macro wrapped
+// =============================================================================
+
+// This test mainly ensures the type compiles when wrapped in a macro
+// Had a weird error where self values were getting resolved incorrectly but only in
+// macros - presumably related to macro hygeine.
+
+macro_rules! impl_in_macro {
+    ($name:ident) => {
+        #[derive(SyntaxFmtDerive)]
+        struct $name(i32);
+    };
+}
+impl_in_macro!(InMacro);
+
+#[test]
+fn test_in_macro() {
+    let _ = format!("{}", syntax_fmt(&InMacro(123)));
+}
+
+// =============================================================================
+// grammar export
+// =============================================================================
+
+#[derive(SyntaxFmtDerive)]
+#[syntax(pre = "(", suf = ")", sep = " ")]
+struct GrammarCall {
+    name: String,
+    #[syntax(sep = ", ")]
+    args: Vec<GrammarArg>,
+    #[syntax(skip)]
+    _span: usize,
+}
+
+#[derive(SyntaxFmtDerive)]
+enum GrammarArg {
+    #[syntax(pre = "#")]
+    Int(i64),
+    #[syntax(cont = ["nil", "null"])]
+    Nil,
+    Call(Box<GrammarCall>),
+    #[syntax(skip)]
+    _Hidden,
+}
+
+#[test]
+fn test_grammar_struct() {
+    let grammar = GrammarCall::syntax_grammar();
+    assert_eq!(
+        grammar.to_string(),
+        "GrammarCall ::= \"(\" String \" \" ( GrammarArg ( \", \" GrammarArg )* )? \")\"\n"
+    );
+    assert_eq!(
+        grammar.render(GrammarStyle::Iso),
+        "GrammarCall = \"(\", String, \" \", [ GrammarArg, { \", \", GrammarArg } ], \")\" ;\n"
+    );
+}
+
+#[test]
+fn test_grammar_enum() {
+    assert_eq!(
+        GrammarArg::syntax_grammar().to_string(),
+        "GrammarArg ::= \"#\" i64 | \"nil\" | GrammarCall\n"
+    );
+}
+
+#[derive(SyntaxFmtDerive)]
+struct GrammarConditional {
+    #[syntax(eval = *flag, cont = "flag", suf = " ")]
+    flag: bool,
+    #[syntax(eval = value.is_some(), cont = "some")]
+    #[syntax_else(cont = "none")]
+    value: Option<u8>,
+    #[syntax(cont_with = my_formatter)]
+    custom: &'static str,
+}
+
+#[test]
+fn test_grammar_conditional() {
+    let grammar = GrammarConditional::syntax_grammar();
+    assert_eq!(
+        grammar.to_string(),
+        "GrammarConditional ::= ( \"flag\" \" \" )? ( \"some\" | \"none\" ) /* my_formatter */\n"
+    );
+    assert_eq!(
+        grammar.render(GrammarStyle::Iso),
+        "GrammarConditional = [ \"flag\", \" \" ], ( \"some\" | \"none\" ), ? my_formatter ? ;\n"
+    );
+    assert_eq!(
+        grammar.productions()[0].expr,
+        GrammarExpr::Sequence(vec![
+            GrammarExpr::Optional(Box::new(GrammarExpr::Sequence(vec![
+                GrammarExpr::Terminal("flag"),
+                GrammarExpr::Terminal(" "),
+            ]))),
+            GrammarExpr::Choice(vec![GrammarExpr::Terminal("some"), GrammarExpr::Terminal("none")]),
+            GrammarExpr::Special("my_formatter"),
+        ])
+    );
+}
+
+#[test]
+fn test_grammar_mixed_quotes() {
+    let mut grammar = Grammar::new();
+    grammar.push(Production::new("Q", GrammarExpr::Terminal("'\"")));
+    grammar.push(Production::new("R", GrammarExpr::Terminal("a\"b'c\"d")));
+    assert_eq!(grammar.to_string(), "Q ::= \"'\" '\"'\nR ::= 'a\"b' \"'c\" '\"d'\n");
+    assert_eq!(
+        grammar.render(GrammarStyle::Iso),
+        "Q = \"'\", '\"' ;\nR = 'a\"b', \"'c\", '\"d' ;\n"
+    );
 }
@@ -0,0 +1,88 @@
+//! Support for [`assert_syntax_eq!`](crate::assert_syntax_eq).
+
+use crate::{diff::unified_diff, Mode, SyntaxDisplay, SyntaxError, SyntaxFmt};
+
+/// Asserts that a syntax tree formats as `expected` in a mode.
+///
+/// The tree is either a value formatted without state, or a [`SyntaxDisplay`] from
+/// [`syntax_fmt`](crate::syntax_fmt), which may have state and other options. On failure, the
+/// message names the mode and shows a line diff of the output, with trailing spaces shown as `·`
+/// and tabs as `→`.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Mode, SyntaxFmt, assert_syntax_eq, syntax_fmt};
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(pre = "{", suf = "}", nl = inner, ind)]
+/// struct Block(#[syntax(sep = [", ", ","], nl = sep)] Vec<i32>);
+///
+/// let block = Block(vec![1, 2]);
+/// assert_syntax_eq!(block, Mode::Normal, "{1, 2}");
+/// assert_syntax_eq!(block, Mode::Pretty, "{\n    1,\n    2\n}");
+/// assert_syntax_eq!(syntax_fmt(&block).indent(["", "\t"]), Mode::Pretty, "{\n\t1,\n\t2\n}");
+/// ```
+///
+/// A failure shows where the output differs:
+///
+/// ```text
+/// syntaxfmt pretty output differs from expected (-expected +actual, · is a trailing space, → is a tab)
+/// @@ -1,4 +1,4 @@
+///  {
+/// -    1,
+/// +→1,
+///      2
+///  }
+/// ```
+#[macro_export]
+macro_rules! assert_syntax_eq {
+    ($node:expr, $mode:expr, $expected:expr $(,)?) => {
+        $crate::assert::assert_syntax_eq(&$node, $mode, $expected)
+    };
+}
+
+/// Formats a value in a mode, for [`assert_syntax_eq!`](crate::assert_syntax_eq).
+pub trait AssertSyntax {
+    fn syntax_string(&self, mode: Mode) -> Result<String, SyntaxError>;
+}
+
+impl<T: SyntaxFmt<()>> AssertSyntax for T {
+    fn syntax_string(&self, mode: Mode) -> Result<String, SyntaxError> {
+        crate::syntax_fmt(self).try_to_string_in(mode)
+    }
+}
+
+impl<S, E, A> AssertSyntax for SyntaxDisplay<'_, '_, S, E, A>
+where
+    E: SyntaxFmt<S, A>,
+{
+    fn syntax_string(&self, mode: Mode) -> Result<String, SyntaxError> {
+        self.try_to_string_in(mode)
+    }
+}
+
+#[track_caller]
+pub fn assert_syntax_eq(node: &impl AssertSyntax, mode: Mode, expected: &str) {
+    let actual = node
+        .syntax_string(mode)
+        .unwrap_or_else(|e| panic!("syntaxfmt {mode} formatting failed: {e}"));
+    if actual != expected {
+        panic!(
+            "syntaxfmt {mode} output differs from expected {DIFF_LEGEND}\n{}",
+            unified_diff(expected, &actual, show_whitespace)
+        );
+    }
+}
+
+/// Explains the marks of a diff from [`show_whitespace`].
+pub(crate) const DIFF_LEGEND: &str =
+    "(-expected +actual, · is a trailing space, → is a tab, ␍ is a carriage return)";
+
+// Shows tabs, carriage returns and trailing spaces, which are otherwise invisible in a diff
+pub(crate) fn show_whitespace(line: &str) -> String {
+    let content = line.trim_end_matches(' ');
+    let mut shown = content.replace('\t', "→").replace('\r', "␍");
+    shown.extend(std::iter::repeat('·').take(line.len() - content.len()));
+    shown
+}
@@ -0,0 +1,108 @@
+//! Line oriented diffs for test failure messages.
+
+use std::fmt::Write;
+
+// Lines of unchanged context around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Diffs lines by their longest common subsequence, after trimming the common prefix and suffix
+fn diff_lines<'a>(expected: &[&'a str], actual: &[&'a str]) -> Vec<Line<'a>> {
+    let prefix = expected.iter().zip(actual).take_while(|(e, a)| e == a).count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let (e, a) = (&expected[prefix..expected.len() - suffix], &actual[prefix..actual.len() - suffix]);
+
+    // lcs[i][j] is the length of the longest common subsequence of e[i..] and a[j..]
+    let mut lcs = vec![vec![0u32; a.len() + 1]; e.len() + 1];
+    for i in (0..e.len()).rev() {
+        for j in (0..a.len()).rev() {
+            lcs[i][j] = if e[i] == a[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<_> = expected[..prefix].iter().map(|l| Line::Same(l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < e.len() || j < a.len() {
+        if i < e.len() && j < a.len() && e[i] == a[j] {
+            lines.push(Line::Same(e[i]));
+            i += 1;
+            j += 1;
+        } else if i < e.len() && (j == a.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(Line::Removed(e[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(a[j]));
+            j += 1;
+        }
+    }
+    lines.extend(expected[expected.len() - suffix..].iter().map(|l| Line::Same(l)));
+    lines
+}
+
+// Writes a unified diff of `expected` to `actual`, with each line passed through `show`
+pub(crate) fn unified_diff<'a>(
+    expected: &'a str,
+    actual: &'a str,
+    show: impl Fn(&str) -> String,
+) -> String {
+    // Lines keep their newline, so a last line without one differs from the same line with one,
+    // and is marked when only one input ends with a newline
+    let marked = expected.ends_with('\n') != actual.ends_with('\n');
+    let split = |text: &'a str| text.split_inclusive('\n').collect::<Vec<_>>();
+    let expected = split(expected);
+    let actual = split(actual);
+    let lines = diff_lines(&expected, &actual);
+
+    // Group changes closer than twice the context into hunks
+    let changed: Vec<_> = (0..lines.len()).filter(|&i| !matches!(lines[i], Line::Same(_))).collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in &changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    for (start, end) in hunks {
+        // Line numbers of the hunk in each input
+        let count = |range: &[Line], keep: fn(&Line) -> bool| range.iter().filter(|l| keep(l)).count();
+        let in_expected = |l: &Line| !matches!(l, Line::Added(_));
+        let in_actual = |l: &Line| !matches!(l, Line::Removed(_));
+        let e_start = count(&lines[..start], in_expected) + 1;
+        let a_start = count(&lines[..start], in_actual) + 1;
+        let e_len = count(&lines[start..end], in_expected);
+        let a_len = count(&lines[start..end], in_actual);
+        let _ = writeln!(out, "@@ -{e_start},{e_len} +{a_start},{a_len} @@");
+        for line in &lines[start..end] {
+            let (mark, l) = match line {
+                Line::Same(l) => (' ', l),
+                Line::Removed(l) => ('-', l),
+                Line::Added(l) => ('+', l),
+            };
+            let _ = match l.strip_suffix('\n') {
+                Some(l) => writeln!(out, "{mark}{}", show(l)),
+                None if marked => writeln!(out, "{mark}{}\n\\ No newline at end of file", show(l)),
+                None => writeln!(out, "{mark}{}", show(l)),
+            };
+        }
+    }
+    out
+}
@@ -0,0 +1,132 @@
+//! Formatting errors with a message and the path to the node which failed.
+//!
+//! [`std::fmt::Error`] carries no information, so formatters which reject a node record a
+//! [`SyntaxError`] with [`SyntaxFormatter::error`](crate::SyntaxFormatter::error) and return the
+//! [`std::fmt::Error`] it gives back. As the error propagates, derived types add their type,
+//! variant and field names to its path, and collections add the index of the failed element.
+//! [`SyntaxDisplay::try_to_string`](crate::SyntaxDisplay::try_to_string) returns it.
+
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use crate::StateError;
+
+/// A step on the path from the root of a syntax tree to a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathSegment {
+    /// A derived type.
+    Type(&'static str),
+    /// An enum variant, following its type.
+    Variant(&'static str),
+    /// A field of a struct or variant, by name or position.
+    Field(&'static str),
+    /// An element of a collection.
+    Index(usize),
+}
+
+/// A formatting error with a message and the path to the node which failed.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{PathSegment, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// fn ident<S>(name: &&'static str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+///     if name.starts_with(|c: char| c.is_ascii_digit()) {
+///         return Err(f.error(format!("invalid identifier `{name}`")));
+///     }
+///     f.write_str(name)
+/// }
+///
+/// #[derive(SyntaxFmt)]
+/// struct Ident(#[syntax(cont_with = ident)] &'static str);
+///
+/// #[derive(SyntaxFmt)]
+/// enum Expr {
+///     Var(Ident),
+///     #[syntax(pre = "(", suf = ")", sep = ", ")]
+///     Tuple(Vec<Expr>),
+/// }
+///
+/// let expr = Expr::Tuple(vec![Expr::Var(Ident("a")), Expr::Var(Ident("1b"))]);
+/// let err = syntax_fmt(&expr).try_to_string().unwrap_err();
+/// assert_eq!(err.message(), "invalid identifier `1b`");
+/// assert_eq!(err.path()[..3], [PathSegment::Type("Expr"), PathSegment::Variant("Tuple"), PathSegment::Field("0")]);
+/// assert_eq!(err.to_string(), "invalid identifier `1b` at Expr::Tuple.0[1] > Expr::Var.0 > Ident.0");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    message: Cow<'static, str>,
+    path: Vec<PathSegment>,
+}
+
+impl SyntaxError {
+    /// Creates an error with an empty path.
+    #[must_use]
+    pub fn new(message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            message: message.into(),
+            path: Vec::new(),
+        }
+    }
+
+    /// The error for a formatter which failed without recording a message.
+    #[must_use]
+    pub(crate) fn unknown() -> Self {
+        Self::new("syntaxfmt formatting failed")
+    }
+
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Path from the root of the syntax tree to the node which failed.
+    #[must_use]
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    // Segments are added innermost first as the error propagates
+    pub(crate) fn push_outer(&mut self, segment: PathSegment) {
+        self.path.insert(0, segment);
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(&self.message)?;
+        for (i, segment) in self.path.iter().enumerate() {
+            match segment {
+                PathSegment::Type(name) if i == 0 => write!(f, " at {name}")?,
+                PathSegment::Type(name) => write!(f, " > {name}")?,
+                PathSegment::Variant(name) => write!(f, "::{name}")?,
+                PathSegment::Field(name) if i == 0 => write!(f, " at {name}")?,
+                PathSegment::Field(name) => write!(f, ".{name}")?,
+                PathSegment::Index(index) if i == 0 => write!(f, " at [{index}]")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+impl From<&'static str> for SyntaxError {
+    fn from(message: &'static str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<String> for SyntaxError {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<StateError> for SyntaxError {
+    fn from(error: StateError) -> Self {
+        Self::new(error.to_string())
+    }
+}
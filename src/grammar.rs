@@ -0,0 +1,391 @@
+//! Grammar export for types deriving `SyntaxFmt`.
+//!
+//! The derive macro also implements [`SyntaxGrammar`] for each type, producing a [`Grammar`]
+//! with a single [`Production`] which mirrors the attributes used for formatting. Grammars can be
+//! merged and rendered as ISO EBNF or W3C-style EBNF text.
+//!
+//! Literals are taken from normal mode, since pretty mode only adds layout.
+
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+
+/// Grammar notation used when rendering a [`Grammar`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GrammarStyle {
+    /// W3C-style EBNF, e.g. `Name ::= "(" ( Item ( "," Item )* )? ")"`
+    #[default]
+    W3c,
+    /// ISO 14977 EBNF, e.g. `Name = "(", [ Item, { ",", Item } ], ")" ;`
+    Iso,
+}
+
+/// Right hand side of a grammar production.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrammarExpr {
+    /// Matches nothing.
+    Empty,
+    /// Literal text.
+    Terminal(&'static str),
+    /// Reference to another production.
+    NonTerminal(&'static str),
+    /// Content that cannot be described, such as output of a custom formatter.
+    ///
+    /// Written as a comment in W3C style and as a special sequence in ISO style.
+    Special(&'static str),
+    /// Each expression in order.
+    Sequence(Vec<GrammarExpr>),
+    /// Any one of the expressions.
+    Choice(Vec<GrammarExpr>),
+    /// Zero or one of the expression.
+    Optional(Box<GrammarExpr>),
+    /// Zero or more of `item`, with `sep` between each.
+    Repeat {
+        item: Box<GrammarExpr>,
+        sep: &'static str,
+    },
+}
+
+impl GrammarExpr {
+    /// Creates a terminal, or `Empty` if `text` is empty.
+    #[must_use]
+    pub fn terminal(text: &'static str) -> Self {
+        if text.is_empty() {
+            Self::Empty
+        } else {
+            Self::Terminal(text)
+        }
+    }
+
+    /// Creates a sequence, flattening nested sequences and dropping empty expressions.
+    #[must_use]
+    pub fn seq(exprs: impl IntoIterator<Item = Self>) -> Self {
+        let mut items = Vec::new();
+        for expr in exprs {
+            match expr {
+                Self::Empty => {}
+                Self::Sequence(inner) => items.extend(inner),
+                expr => items.push(expr),
+            }
+        }
+        match items.len() {
+            0 => Self::Empty,
+            1 => items.remove(0),
+            _ => Self::Sequence(items),
+        }
+    }
+
+    /// Creates a choice, flattening nested choices.
+    #[must_use]
+    pub fn choice(exprs: impl IntoIterator<Item = Self>) -> Self {
+        let mut items: Vec<Self> = Vec::new();
+        for expr in exprs {
+            match expr {
+                Self::Choice(inner) => items.extend(inner),
+                expr => items.push(expr),
+            }
+        }
+        items.dedup();
+        match items.len() {
+            0 => Self::Empty,
+            1 => items.remove(0),
+            _ => Self::Choice(items),
+        }
+    }
+
+    /// Makes an expression optional, collapsing nested optionals and repeats.
+    #[must_use]
+    pub fn optional(expr: Self) -> Self {
+        match expr {
+            e @ (Self::Empty | Self::Optional(_) | Self::Repeat { .. }) => e,
+            e => Self::Optional(Box::new(e)),
+        }
+    }
+
+    /// Repeats an expression with a separator between each item.
+    #[must_use]
+    pub fn repeat(item: Self, sep: &'static str) -> Self {
+        match item {
+            Self::Empty => Self::Empty,
+            item => Self::Repeat {
+                item: Box::new(item),
+                sep,
+            },
+        }
+    }
+
+    fn write_terminal(text: &str, out: &mut impl Write, style: GrammarStyle) -> FmtResult {
+        let join = match style {
+            GrammarStyle::W3c => " ",
+            GrammarStyle::Iso => ", ",
+        };
+        // Text holding both quotes is split into adjacent terminals, each quoted with the
+        // quote it doesn't contain
+        let mut rest = text;
+        loop {
+            let quote = match rest.chars().find(|&c| c == '"' || c == '\'') {
+                Some('"') => '\'',
+                _ => '"',
+            };
+            let (part, tail) = rest.split_at(rest.find(quote).unwrap_or(rest.len()));
+            write!(out, "{quote}{part}{quote}")?;
+            if tail.is_empty() {
+                return Ok(());
+            }
+            out.write_str(join)?;
+            rest = tail;
+        }
+    }
+
+    fn write_atom(&self, out: &mut impl Write, style: GrammarStyle) -> FmtResult {
+        match self {
+            Self::Sequence(_) | Self::Choice(_) => {
+                out.write_str("( ")?;
+                self.write(out, style)?;
+                out.write_str(" )")
+            }
+            _ => self.write(out, style),
+        }
+    }
+
+    fn write(&self, out: &mut impl Write, style: GrammarStyle) -> FmtResult {
+        match (self, style) {
+            (Self::Empty, _) => out.write_str("\"\""),
+            (Self::Terminal(text), _) => Self::write_terminal(text, out, style),
+            (Self::NonTerminal(name), _) => out.write_str(name),
+            (Self::Special(text), GrammarStyle::W3c) => write!(out, "/* {text} */"),
+            (Self::Special(text), GrammarStyle::Iso) => write!(out, "? {text} ?"),
+            (Self::Sequence(items), _) => {
+                let join = match style {
+                    GrammarStyle::W3c => " ",
+                    GrammarStyle::Iso => ", ",
+                };
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.write_str(join)?;
+                    }
+                    if matches!(item, Self::Choice(_)) {
+                        item.write_atom(out, style)?;
+                    } else {
+                        item.write(out, style)?;
+                    }
+                }
+                Ok(())
+            }
+            (Self::Choice(items), _) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.write_str(" | ")?;
+                    }
+                    item.write(out, style)?;
+                }
+                Ok(())
+            }
+            (Self::Optional(expr), GrammarStyle::W3c) => {
+                expr.write_atom(out, style)?;
+                out.write_str("?")
+            }
+            (Self::Optional(expr), GrammarStyle::Iso) => {
+                out.write_str("[ ")?;
+                expr.write(out, style)?;
+                out.write_str(" ]")
+            }
+            (Self::Repeat { item, sep }, GrammarStyle::W3c) => {
+                if sep.is_empty() {
+                    item.write_atom(out, style)?;
+                    out.write_str("*")
+                } else {
+                    out.write_str("( ")?;
+                    item.write_atom(out, style)?;
+                    out.write_str(" ( ")?;
+                    Self::write_terminal(sep, out, style)?;
+                    out.write_str(" ")?;
+                    item.write_atom(out, style)?;
+                    out.write_str(" )* )?")
+                }
+            }
+            (Self::Repeat { item, sep }, GrammarStyle::Iso) => {
+                if sep.is_empty() {
+                    out.write_str("{ ")?;
+                    item.write(out, style)?;
+                    out.write_str(" }")
+                } else {
+                    out.write_str("[ ")?;
+                    item.write_atom(out, style)?;
+                    out.write_str(", { ")?;
+                    Self::write_terminal(sep, out, style)?;
+                    out.write_str(", ")?;
+                    item.write_atom(out, style)?;
+                    out.write_str(" } ]")
+                }
+            }
+        }
+    }
+}
+
+/// A named grammar production.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Production {
+    pub name: &'static str,
+    pub expr: GrammarExpr,
+}
+
+impl Production {
+    #[must_use]
+    pub fn new(name: &'static str, expr: GrammarExpr) -> Self {
+        Self { name, expr }
+    }
+}
+
+/// A set of grammar productions.
+///
+/// `Display` renders W3C-style EBNF, use [`Grammar::render`] to choose another style.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Grammar, GrammarStyle, SyntaxFmt, SyntaxGrammar};
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(pre = "[", suf = "]")]
+/// struct List {
+///     #[syntax(sep = ",")]
+///     items: Vec<Item>,
+/// }
+///
+/// #[derive(SyntaxFmt)]
+/// enum Item {
+///     Num(u32),
+///     #[syntax(cont = "null")]
+///     Null,
+/// }
+///
+/// let grammar: Grammar = [List::syntax_grammar(), Item::syntax_grammar()].into_iter().collect();
+/// assert_eq!(
+///     grammar.to_string(),
+///     "List ::= \"[\" ( Item ( \",\" Item )* )? \"]\"\nItem ::= u32 | \"null\"\n"
+/// );
+/// assert_eq!(
+///     grammar.render(GrammarStyle::Iso),
+///     "List = \"[\", [ Item, { \",\", Item } ], \"]\" ;\nItem = u32 | \"null\" ;\n"
+/// );
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Grammar {
+    productions: Vec<Production>,
+}
+
+impl Grammar {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the grammar of `T`.
+    #[must_use]
+    pub fn of<T: SyntaxGrammar + ?Sized>() -> Self {
+        T::syntax_grammar()
+    }
+
+    /// Adds a production, replacing any existing production with the same name.
+    pub fn push(&mut self, production: Production) {
+        if let Some(existing) = self
+            .productions
+            .iter_mut()
+            .find(|p| p.name == production.name)
+        {
+            *existing = production;
+        } else {
+            self.productions.push(production);
+        }
+    }
+
+    /// Adds the productions of `T`.
+    #[must_use]
+    pub fn with<T: SyntaxGrammar + ?Sized>(mut self) -> Self {
+        self.extend(T::syntax_grammar());
+        self
+    }
+
+    /// Returns the productions in insertion order.
+    #[must_use]
+    pub fn productions(&self) -> &[Production] {
+        &self.productions
+    }
+
+    /// Renders the grammar in the given style, one production per line.
+    #[must_use]
+    pub fn render(&self, style: GrammarStyle) -> String {
+        let mut out = String::new();
+        // Writing to a String cannot fail
+        let _ = self.write(&mut out, style);
+        out
+    }
+
+    fn write(&self, out: &mut impl Write, style: GrammarStyle) -> FmtResult {
+        for production in &self.productions {
+            match style {
+                GrammarStyle::W3c => {
+                    write!(out, "{} ::= ", production.name)?;
+                    production.expr.write(out, style)?;
+                    out.write_str("\n")?;
+                }
+                GrammarStyle::Iso => {
+                    write!(out, "{} = ", production.name)?;
+                    production.expr.write(out, style)?;
+                    out.write_str(" ;\n")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Production> for Grammar {
+    fn from(production: Production) -> Self {
+        Self {
+            productions: vec![production],
+        }
+    }
+}
+
+impl Extend<Production> for Grammar {
+    fn extend<I: IntoIterator<Item = Production>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|p| self.push(p));
+    }
+}
+
+impl Extend<Grammar> for Grammar {
+    fn extend<I: IntoIterator<Item = Grammar>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|g| self.extend(g.productions));
+    }
+}
+
+impl FromIterator<Grammar> for Grammar {
+    fn from_iter<I: IntoIterator<Item = Grammar>>(iter: I) -> Self {
+        let mut grammar = Self::new();
+        grammar.extend(iter);
+        grammar
+    }
+}
+
+impl IntoIterator for Grammar {
+    type Item = Production;
+    type IntoIter = std::vec::IntoIter<Production>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.productions.into_iter()
+    }
+}
+
+impl Display for Grammar {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.write(f, GrammarStyle::W3c)
+    }
+}
+
+/// Trait for types which can describe their concrete syntax as a grammar.
+///
+/// Implemented by `#[derive(SyntaxFmt)]`.
+pub trait SyntaxGrammar {
+    /// Returns a grammar containing the production for this type.
+    fn syntax_grammar() -> Grammar;
+}
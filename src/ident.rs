@@ -0,0 +1,191 @@
+//! Identifier escaping for reserved words.
+//!
+//! [`ReservedWords`] pairs a set of words with the target language's escape for them, such as
+//! `r#type` in Rust or `` `class` `` in Kotlin. The `ident` attribute argument applies a set to a
+//! field, e.g. `#[syntax(ident = rust)]`, or takes the set from state with `ident = state`.
+//!
+//! Built in sets are enabled with the `keywords-rust`, `keywords-python`, `keywords-sql` and
+//! `keywords-kotlin` cargo features.
+
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+
+use crate::{SyntaxFmt, SyntaxFormatter};
+
+/// How a reserved word is escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentEscape {
+    /// Writes text before the word, e.g. `r#type`.
+    Prefix(&'static str),
+    /// Writes text after the word, e.g. `class_`.
+    Suffix(&'static str),
+    /// Writes text either side of the word, e.g. `"select"`.
+    Wrap(&'static str, &'static str),
+}
+
+/// A set of reserved words and how to escape them.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{IdentEscape, ReservedWords, SyntaxFmt, syntax_fmt};
+///
+/// const WORDS: ReservedWords = ReservedWords::new(&["let", "in"], IdentEscape::Suffix("'"));
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(pre = "let ", sep = " = ")]
+/// struct Binding {
+///     #[syntax(ident = WORDS)]
+///     name: String,
+///     value: i32,
+/// }
+///
+/// let binding = Binding { name: "in".into(), value: 1 };
+/// assert_eq!(format!("{}", syntax_fmt(&binding)), "let in' = 1");
+/// assert_eq!(WORDS.ident("x").to_string(), "x");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedWords {
+    words: &'static [&'static str],
+    escape: IdentEscape,
+    ignore_case: bool,
+}
+
+impl ReservedWords {
+    #[must_use]
+    pub const fn new(words: &'static [&'static str], escape: IdentEscape) -> Self {
+        Self {
+            words,
+            escape,
+            ignore_case: false,
+        }
+    }
+
+    /// Matches words ignoring ASCII case.
+    #[must_use]
+    pub const fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+
+    /// Returns true if `ident` is a reserved word.
+    #[must_use]
+    pub fn is_reserved(&self, ident: &str) -> bool {
+        if self.ignore_case {
+            self.words.iter().any(|w| w.eq_ignore_ascii_case(ident))
+        } else {
+            self.words.contains(&ident)
+        }
+    }
+
+    /// Writes `ident`, escaping it if it's a reserved word.
+    ///
+    /// Escaped identifiers are written in pieces, so [`ident`](Self::ident) should be used to
+    /// format one with a [`SyntaxFormatter`], which writes it as a single token.
+    pub fn write_ident(&self, ident: &str, out: &mut impl Write) -> FmtResult {
+        if !self.is_reserved(ident) {
+            return out.write_str(ident);
+        }
+        match self.escape {
+            IdentEscape::Prefix(prefix) => write!(out, "{prefix}{ident}"),
+            IdentEscape::Suffix(suffix) => write!(out, "{ident}{suffix}"),
+            IdentEscape::Wrap(open, close) => write!(out, "{open}{ident}{close}"),
+        }
+    }
+
+    /// Wraps `ident` so it's formatted with this set.
+    #[must_use]
+    pub fn ident<T: AsRef<str>>(&self, ident: T) -> EscapedIdent<'_, T> {
+        EscapedIdent(self, ident)
+    }
+}
+
+#[cfg(feature = "keywords-rust")]
+impl ReservedWords {
+    /// Rust strict and reserved keywords, escaped as raw identifiers.
+    ///
+    /// `self`, `Self`, `super` and `crate` are excluded since they can't be raw identifiers.
+    pub const RUST: Self = Self::new(
+        &[
+            "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+            "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if",
+            "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override",
+            "priv", "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type",
+            "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+        ],
+        IdentEscape::Prefix("r#"),
+    );
+}
+
+#[cfg(feature = "keywords-python")]
+impl ReservedWords {
+    /// Python keywords, escaped with a trailing underscore.
+    pub const PYTHON: Self = Self::new(
+        &[
+            "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class",
+            "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global",
+            "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise",
+            "return", "try", "while", "with", "yield",
+        ],
+        IdentEscape::Suffix("_"),
+    );
+}
+
+#[cfg(feature = "keywords-sql")]
+impl ReservedWords {
+    /// Common SQL reserved words, matched ignoring case and escaped as quoted identifiers.
+    pub const SQL: Self = Self::new(
+        &[
+            "ALL", "ALTER", "AND", "ANY", "AS", "ASC", "BETWEEN", "BY", "CASE", "CAST", "CHECK",
+            "COLUMN", "CONSTRAINT", "CREATE", "CROSS", "CURRENT", "DEFAULT", "DELETE", "DESC",
+            "DISTINCT", "DROP", "ELSE", "END", "EXCEPT", "EXISTS", "FALSE", "FETCH", "FOR",
+            "FOREIGN", "FROM", "FULL", "GRANT", "GROUP", "HAVING", "IN", "INNER", "INSERT",
+            "INTERSECT", "INTO", "IS", "JOIN", "LEFT", "LIKE", "LIMIT", "NOT", "NULL", "OF",
+            "OFFSET", "ON", "OR", "ORDER", "OUTER", "PRIMARY", "REFERENCES", "RIGHT", "SELECT",
+            "SET", "SOME", "TABLE", "THEN", "TO", "TRUE", "UNION", "UNIQUE", "UPDATE", "USER",
+            "USING", "VALUES", "WHEN", "WHERE", "WITH",
+        ],
+        IdentEscape::Wrap("\"", "\""),
+    )
+    .ignore_case();
+}
+
+#[cfg(feature = "keywords-kotlin")]
+impl ReservedWords {
+    /// Kotlin hard keywords, escaped with backticks.
+    pub const KOTLIN: Self = Self::new(
+        &[
+            "as", "break", "class", "continue", "do", "else", "false", "for", "fun", "if", "in",
+            "interface", "is", "null", "object", "package", "return", "super", "this", "throw",
+            "true", "try", "typealias", "typeof", "val", "var", "when", "while",
+        ],
+        IdentEscape::Wrap("`", "`"),
+    );
+}
+
+/// Trait for state which provides the reserved words used by `ident = state`.
+pub trait ReservedWordsSource {
+    fn reserved_words(&self) -> &ReservedWords;
+}
+
+impl ReservedWordsSource for ReservedWords {
+    fn reserved_words(&self) -> &ReservedWords {
+        self
+    }
+}
+
+/// Formats an identifier, escaping it if it's in the given [`ReservedWords`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscapedIdent<'r, T>(pub &'r ReservedWords, pub T);
+
+// Escaped identifiers are written in pieces, which mustn't be joined or truncated
+impl<S, A, T: AsRef<str>> SyntaxFmt<S, A> for EscapedIdent<'_, T> {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_atomic(|f| self.0.write_ident(self.1.as_ref(), f))
+    }
+}
+
+impl<T: AsRef<str>> Display for EscapedIdent<'_, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.0.write_ident(self.1.as_ref(), f)
+    }
+}
@@ -13,10 +13,17 @@
 //! - **Content Replacement** - Override field formatting with literals or custom functions
 //! - **Conditional Formatting** - Format based on arbitrary boolean expressions, with else support
 //! - **Stateful Formatting** - Pass mutable or immutable state for context-aware output
+//! - **Grammar Export** - Describe the formatted syntax as EBNF, derived from the same attributes
 //!
 //! # Cargo Features
 //!
 //! - **`derive`** - enables `SyntaxFmt` derive macro (on by default)
+//! - **`keywords-rust`**, **`keywords-python`**, **`keywords-sql`**, **`keywords-kotlin`** -
+//!   enable the built in [`ReservedWords`] tables, or **`keywords`** for all of them
+//! - **`testing`** - enables the `testing` module of snapshot test helpers, such as
+//!   `assert_syntax_snapshot!`, and the `RoundTrip` parser round trip check
+//! - **`proptest`**, **`quickcheck`** - enable `testing`, and round trip checks of values from
+//!   the respective crate's generators
 //!
 //! # Quick Start
 //!
@@ -100,6 +107,86 @@
 //!
 //! The `sep` attribute argument can be applied at field, type, or `syntax_else` level.
 //!
+//! Maps write each entry as key and value, with `kv_sep` between them. Unlike `sep`, `kv_sep`
+//! carries into nested contexts, so it can be set once on an outer type. Hash based collections
+//! iterate in an arbitrary order, so use `sorted` for deterministic output:
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! struct Attrs {
+//!     #[syntax(sorted, sep = " ", kv_sep = "=")]
+//!     attrs: HashMap<&'static str, u32>,
+//! }
+//!
+//! let attrs = Attrs { attrs: HashMap::from([("width", 2), ("height", 1)]) };
+//! assert_eq!(format!("{}", syntax_fmt(&attrs)), "height=1 width=2");
+//! ```
+//!
+//! See [`Entries`] for formatting key value pairs from other iterators.
+//!
+//! Collection fields can be transformed as they're formatted, without changing the value:
+//! `filter = |item| ...`, `sort`, `sort_by = compare`, `dedup` and `rev`. These apply in that
+//! order and only allocate when sorting or deduplicating. A `filter` closure taking a second
+//! argument also receives the state. It tests each element just before it's written, or every
+//! element up front when sorting or deduplicating.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(state = bool)]
+//! struct Imports {
+//!     #[syntax(sep = "\n", filter = |path, std: &bool| *std || !path.starts_with("std"), sort, dedup)]
+//!     paths: Vec<&'static str>,
+//! }
+//!
+//! let imports = Imports { paths: vec!["std::fmt", "alloc", "std::fmt", "core"] };
+//! assert_eq!(format!("{}", syntax_fmt(&imports).state(&true)), "alloc\ncore\nstd::fmt");
+//! assert_eq!(format!("{}", syntax_fmt(&imports).state(&false)), "alloc\ncore");
+//! ```
+//!
+//! Use `last_sep` for a distinct separator before the last element, e.g. `sep = ", "` with
+//! `last_sep = " and "` writes `a, b and c`. Custom formatters can also get the current element's
+//! [`Position`] from [`SyntaxFormatter::position`].
+//!
+//! To write filtered or mapped children without collecting them, use `iter` with an iterator
+//! expression. A leading `.` continues from the field, e.g. `iter = .iter().filter(|c| c.shown)`.
+//! See [`iter()`] for the equivalent wrapper, including [`Iter::with_index`] for index dependent
+//! output.
+//!
+//! # Field Order and Field Separators
+//!
+//! Fields are written in declaration order unless given an `order`. Fields are sorted by `order`,
+//! and fields without one are keyed by their declaration index, keeping declaration order on ties.
+//! Orders may be negative, to move a field before fields without an order.
+//!
+//! Use `field_sep` instead of `sep` at type or variant level to only write the separator between
+//! fields which produced output. Variants use the type's `field_sep` unless they set their own.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(field_sep = " ")]
+//! struct FnDecl {
+//!     #[syntax(pre = "fn ", suf = "()", order = 2)]
+//!     name: &'static str,
+//!     #[syntax(cont = "pub", eval = *is_pub, order = 0)]
+//!     is_pub: bool,
+//!     #[syntax(cont = "async", eval = *is_async, order = 1)]
+//!     is_async: bool,
+//! }
+//!
+//! let decl = FnDecl { name: "run", is_pub: true, is_async: false };
+//! assert_eq!(format!("{}", syntax_fmt(&decl)), "pub fn run()");
+//! ```
+//!
+//! The `order` attribute argument can only be applied at field level, and `field_sep` at type or
+//! variant level.
+//!
 //! # Skip Types and Fields
 //!
 //! Use `skip` to exclude fields or entire types from output, useful for metadata or internal state.
@@ -121,6 +208,44 @@
 //! assert_eq!(format!("{}", syntax_fmt(&node)), "data");
 //! ```
 //!
+//! # Transparent and Flattened Types
+//!
+//! Use `transparent` on a newtype to format it exactly as its single non-skipped field, with no
+//! context of its own. `PhantomData` fields are ignored.
+//!
+//! Use `flatten` on a field to write a nested struct's fields as part of the parent's field
+//! sequence. The nested struct's own decorations are dropped and the parent's separators apply.
+//! No separator is written for a flattened struct which writes nothing.
+//!
+//! The `transparent` attribute argument can only be applied at type level, and `flatten` at field
+//! level.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(transparent)]
+//! struct Ident(&'static str);
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "(", suf = ")", sep = ", ")]
+//! struct Position {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "point(", suf = ")", sep = ", ")]
+//! struct Point {
+//!     name: Ident,
+//!     #[syntax(flatten)]
+//!     pos: Position,
+//! }
+//!
+//! let point = Point { name: Ident("p"), pos: Position { x: 1, y: 2 } };
+//! assert_eq!(format!("{}", syntax_fmt(&point)), "point(p, 1, 2)");
+//! ```
+//!
 //! # Basic Pretty Printing
 //!
 //! Enable pretty printing with the `.pretty()` builder method. Use modal attributes
@@ -273,6 +398,45 @@
 //! assert_eq!(format!("{}", syntax_fmt(&hex)), "0xff");
 //! ```
 //!
+//! Use `quote` to write a string field as an escaped string literal of the target language. See
+//! [`Quoted`] for the equivalent wrapper type.
+//!
+//! Use `ident` to escape a string field which collides with a reserved word, e.g. writing `type`
+//! as `r#type`. It takes a built in table (`rust`, `python`, `sql` or `kotlin`), a path to a
+//! [`ReservedWords`] value, or `state` to get the table from a state implementing
+//! [`ReservedWordsSource`]. See [`EscapedIdent`] for the equivalent wrapper type.
+//!
+//! Use `num(...)` to write a numeric field as a literal, e.g. `num(hex, digits = 2)` or
+//! `num(point, suffix = "f32")`. Arguments match the [`NumFmt`] builder methods, with `exp` or
+//! `exp = never|always|auto` setting the [`Exponent`] style. See [`Num`] for the equivalent wrapper
+//! type.
+//!
+//! Unions can derive `SyntaxFmt` only when the type level content is replaced with `cont` or
+//! `cont_with`, since the active field isn't known to the derive. State can carry a
+//! discriminant which lives outside the union.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+//!
+//! fn number_formatter(n: &Number, f: &mut SyntaxFormatter<bool>) -> std::fmt::Result {
+//!     let is_float = *f.state();
+//!     // Safety: State says which field is active
+//!     unsafe {
+//!         if is_float { write!(f, "{:?}", n.float) } else { write!(f, "{}", n.int) }
+//!     }
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(state = bool, cont_with = number_formatter)]
+//! union Number {
+//!     int: i64,
+//!     float: f64,
+//! }
+//!
+//! let n = Number { float: 2.0 };
+//! assert_eq!(format!("{}", syntax_fmt(&n).state(&true)), "2.0");
+//! ```
+//!
 //! # Conditional Formatting
 //!
 //! For conditional logic with arbitrary expressions, use `eval`.
@@ -412,12 +576,110 @@
 //! assert_eq!(format!("{}", syntax_fmt(&none)), "none");
 //! ```
 //!
+//! # Depth Limits
+//!
+//! Huge or deeply nested trees can be cut short with the `.max_depth(n)` builder method. Each
+//! derived type counts as one level, and types nested deeper than `n` are written as `...`, or
+//! the text given to `.elision(...)`. A type can set its own placeholder with
+//! `#[syntax(elide = "...")]`, or opt out with `#[syntax(elide = false)]`, in which case it's
+//! always written and doesn't count as a level. Custom formatters can read the current depth
+//! with `SyntaxFormatter::depth`.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! enum Expr {
+//!     #[syntax(pre = "[", suf = "]")]
+//!     List(#[syntax(sep = ", ")] Vec<Expr>),
+//!     Lit(Lit),
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(elide = "..")]
+//! struct Lit(i32);
+//!
+//! let lit = |n| Expr::Lit(Lit(n));
+//! let expr = Expr::List(vec![lit(1), Expr::List(vec![lit(2)])]);
+//! assert_eq!(format!("{}", syntax_fmt(&expr)), "[1, [2]]");
+//! assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(3)), "[1, [..]]");
+//! assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(2)), "[.., [...]]");
+//! assert_eq!(format!("{}", syntax_fmt(&expr).max_depth(1).elision("_")), "[_, _]");
+//! ```
+//!
+//! # Width Limits
+//!
+//! Output can be capped at roughly `n` characters with the `.max_width(n)` builder method, for
+//! snippets in logs and error messages. Width is checked before each node, field and sequence
+//! element, and once it's reached an ellipsis is written (`…`, or the text given to
+//! `.ellipsis(...)`) and the rest is skipped. With `.balanced()`, suffixes of nodes left open are
+//! still written so delimiters stay closed. Custom implementations should write closing
+//! delimiters with `SyntaxFormatter::write_suffix` to take part.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "[", suf = "]")]
+//! struct List(#[syntax(sep = ", ")] Vec<i32>);
+//!
+//! let list = List(vec![1, 2, 3, 4, 5, 6]);
+//! assert_eq!(format!("{}", syntax_fmt(&list).max_width(8)), "[1, 2, 3, …");
+//! assert_eq!(format!("{}", syntax_fmt(&list).max_width(8).balanced()), "[1, 2, 3, …]");
+//! ```
+//!
+//! # Error Reporting
+//!
+//! Formatters which reject a node, such as an invalid identifier, record a [`SyntaxError`] with
+//! [`SyntaxFormatter::error`] and return the [`std::fmt::Error`] it gives back. Derived types add
+//! their type, variant and field names to the error's path as it propagates, and
+//! [`SyntaxDisplay::try_to_string`] returns it rather than panicking like `to_string`.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+//!
+//! fn write_label<S>(label: &&'static str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+//!     if label.is_empty() {
+//!         return Err(f.error("empty label"));
+//!     }
+//!     write!(f, "'{label}")
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "break ")]
+//! struct Break {
+//!     #[syntax(cont_with = write_label)]
+//!     label: &'static str,
+//! }
+//!
+//! assert_eq!(syntax_fmt(&Break { label: "a" }).try_to_string().unwrap(), "break 'a");
+//! let err = syntax_fmt(&Break { label: "" }).try_to_string().unwrap_err();
+//! assert_eq!(err.to_string(), "empty label at Break.label");
+//! ```
+//!
+//! # Testing Output
+//!
+//! [`assert_syntax_eq!`] compares output in a mode to an expected string, and shows a line diff
+//! with visible whitespace when they differ. With the `testing` cargo feature,
+//! `assert_syntax_snapshot!` compares output in every mode to a snapshot file instead.
+//!
+//! ```
+//! use syntaxfmt::{Mode, SyntaxFmt, assert_syntax_eq};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "[", suf = "]", nl = inner, ind)]
+//! struct List(#[syntax(sep = [", ", ","], nl = sep)] Vec<u8>);
+//!
+//! assert_syntax_eq!(List(vec![1, 2]), Mode::Normal, "[1, 2]");
+//! assert_syntax_eq!(List(vec![1, 2]), Mode::Pretty, "[\n    1,\n    2\n]");
+//! ```
+//!
 //! # Stateful Formatting
 //!
 //! Pass mutable or immutable state through formatting to enable context-aware output like
 //! symbol resolution, ID generation, or tracking. Use the `state` or `bound` attribute with
-//! the derive macro to specify the state type or trait bound. Both `state` and `bound` can
-//! only be applied at type level.
+//! the derive macro to specify the state type or trait bound. Both `state` and `bound = Trait`
+//! can only be applied at type level.
 //!
 //! ```
 //! use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
@@ -450,12 +712,168 @@
 //! assert_eq!(format!("{}", syntax_fmt(&ident).state(&resolver)), "resolved_foo");
 //! ```
 //!
+//! Types which mutate state are marked `state_mut`. They implement `SyntaxFmt<S, Mutable>`, so
+//! they can only be formatted with `.state_mut(&mut state)`, and their custom formatters receive a
+//! formatter with [`SyntaxFormatter::state_mut`]. Forgetting to pass mutable state is then a
+//! compile error rather than a panic. Other derived types can be formatted with either, and
+//! manual implementations which don't mutate state should also be generic over the access
+//! parameter `A`. Like `state`, `state_mut` must be set on every type containing a `state_mut`
+//! type, at any depth, unless the contained type is a type parameter. Deriving for a type
+//! without it fails to compile, since its fields are formatted for any access `A` but the
+//! `state_mut` type only implements `SyntaxFmt<S, Mutable>`.
+//!
+//! ```
+//! use syntaxfmt::{Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+//!
+//! fn fresh_name(prefix: &str, f: &mut SyntaxFormatter<usize, Mutable>) -> std::fmt::Result {
+//!     let id = f.map_state_mut(|_, next| std::mem::replace(next, *next + 1));
+//!     write!(f, "{prefix}{id}")
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(state = usize, state_mut)]
+//! struct Temp(#[syntax(cont_with = fresh_name)] &'static str);
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(state = usize, state_mut)]
+//! struct Temps(#[syntax(sep = ", ")] Vec<Temp>);
+//!
+//! let mut next = 0;
+//! let temps = Temps(vec![Temp("t"), Temp("u")]);
+//! assert_eq!(format!("{}", syntax_fmt(&temps).state_mut(&mut next)), "t0, u1");
+//! assert_eq!(next, 2);
+//! ```
+//!
+//! ## State Components
+//!
+//! Formatters meant to be reused with different state can be bound by [`StateComponents`] and
+//! read the values they need by type with [`SyntaxFormatter::get`] and
+//! [`SyntaxFormatter::get_mut`]. [`StateMap`] holds one value of each type, so library and
+//! application components can be combined in one state without implementing traits for it.
+//!
+//! ```
+//! use syntaxfmt::{StateComponents, StateMap, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+//!
+//! struct Quote(char);
+//!
+//! fn quoted<S: StateComponents>(s: &str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+//!     let q = f.get::<Quote>().0;
+//!     write!(f, "{q}{s}{q}")
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(bound = StateComponents)]
+//! struct Str(#[syntax(cont_with = quoted)] &'static str);
+//!
+//! let state = StateMap::new().with(Quote('\''));
+//! assert_eq!(format!("{}", syntax_fmt(&Str("a")).state(&state)), "'a'");
+//! ```
+//!
+//! ## Scoped State
+//!
+//! The `scope` attribute makes a value visible to everything a type or field writes, which is
+//! useful for context like the enclosing function or whether output is inside an `unsafe` block.
+//! Scoped values are looked up by type with [`SyntaxFormatter::scope`], which returns the
+//! innermost value, or [`SyntaxFormatter::scopes`]. The expression may refer to `self`.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+//!
+//! struct Loop(&'static str);
+//!
+//! fn break_label<S>(_: &Stmt, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+//!     match f.scope::<Loop>().map(|l| l.0) {
+//!         Some(label) => write!(f, "break '{label}"),
+//!         None => f.write_str("break"),
+//!     }
+//! }
+//!
+//! #[derive(SyntaxFmt)]
+//! enum Stmt {
+//!     #[syntax(cont_with = break_label)]
+//!     Break,
+//!     #[syntax(pre = "'", suf = " }", scope = Loop(label))]
+//!     Loop {
+//!         label: &'static str,
+//!         #[syntax(pre = ": loop { ")]
+//!         body: Box<Stmt>,
+//!     },
+//! }
+//!
+//! let stmt = Stmt::Loop { label: "outer", body: Box::new(Stmt::Break) };
+//! assert_eq!(format!("{}", syntax_fmt(&stmt)), "'outer: loop { break 'outer }");
+//! ```
+//!
 //! ## Additional State Examples
 //!
+//! - [`SyntaxFormatter::get`]
+//! - [`SyntaxFormatter::get_mut`]
+//! - [`SyntaxFormatter::with_scope`]
 //! - [`SyntaxFormatter::state`]
 //! - [`SyntaxFormatter::state_mut`]
 //! - [`SyntaxFormatter::map_state`]
 //! - [`SyntaxFormatter::map_state_mut`]
+//! - [`SyntaxFormatter::try_state`] and [`SyntaxFormatter::try_state_mut`], which return a
+//!   [`StateError`] rather than panicking when state is borrowed by an enclosing formatter
+//!
+//! # Generic Types
+//!
+//! The derive adds a `T: SyntaxFmt<S, A>` bound for each type parameter which appears in a
+//! formatted field. Associated types such as `T::Item` or `<T as Trait>::Item` are bounded
+//! themselves instead of `T`. Parameters only used by skipped fields, `PhantomData`, or fields
+//! whose content is replaced with `cont` or `cont_with` are left unbounded.
+//!
+//! When inference isn't right, replace the bounds with `bound(...)` or `bound = "..."`, either for
+//! the whole type or for a single field. An empty string removes the bounds entirely. The state
+//! type parameter is named `__SyntaxFmtState` unless set with `state`, and the state access
+//! parameter `__SyntaxFmtAccess` unless the type is `state_mut`.
+//!
+//! ```
+//! use syntaxfmt::{SyntaxFmt, syntax_fmt};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "<", suf = ">", sep = ", ")]
+//! struct Pair<K, V> {
+//!     key: K,
+//!     #[syntax(bound(V: SyntaxFmt<__SyntaxFmtState, __SyntaxFmtAccess> + Copy))]
+//!     value: V,
+//! }
+//!
+//! let pair = Pair { key: "x", value: 1 };
+//! assert_eq!(format!("{}", syntax_fmt(&pair)), "<x, 1>");
+//! ```
+//!
+//! # Grammar Export
+//!
+//! The derive macro also implements [`SyntaxGrammar`], which describes the syntax produced in
+//! normal mode as a single grammar production. Productions from several types can be combined
+//! into a [`Grammar`] and rendered as W3C-style or ISO EBNF.
+//!
+//! Field types are referenced by name, collections become repetitions using the field separator,
+//! `Option` and `eval` become optional, and `syntax_else` becomes a choice. Content which can't be
+//! described statically, such as `cont_with`, is rendered as a special sequence.
+//!
+//! ```
+//! use syntaxfmt::{Grammar, GrammarStyle, SyntaxFmt, SyntaxGrammar};
+//!
+//! #[derive(SyntaxFmt)]
+//! #[syntax(pre = "fn ")]
+//! struct Function<'a> {
+//!     name: &'a str,
+//!     #[syntax(pre = "(", suf = ")", sep = ", ")]
+//!     params: Vec<&'a str>,
+//! }
+//!
+//! let grammar = Grammar::of::<Function>();
+//! assert_eq!(
+//!     grammar.to_string(),
+//!     "Function ::= \"fn \" str \"(\" ( str ( \", \" str )* )? \")\"\n"
+//! );
+//! assert_eq!(
+//!     grammar.render(GrammarStyle::Iso),
+//!     "Function = \"fn \", str, \"(\", [ str, { \", \", str } ], \")\" ;\n"
+//! );
+//! ```
 //!
 //! # Putting it all Together
 //!
@@ -476,15 +894,34 @@
 //! | `pre` | Text before content | field/type/else |
 //! | `suf` | Text after content | field/type/else |
 //! | `sep` | Separator between collection elements | field/type/else |
+//! | `last_sep` | Separator before the last collection element | field/type/else |
+//! | `field_sep` | Separator between fields which wrote output | type/variant |
+//! | `kv_sep` | Separator between map keys and values | field/type/else |
+//! | `sorted` | Write a hash map or set in sorted order | field/else |
+//! | `iter` | Write items of an iterator expression lazily, `.` prefixed to continue from the field or `self` | field/type/else |
+//! | `filter` | Skip collection elements, with a closure of the element and optionally state | field/else |
+//! | `sort`, `sort_by` | Sort collection elements, by `Ord` or with a compare function | field/else |
+//! | `dedup` | Remove consecutive duplicate collection elements | field/else |
+//! | `rev` | Reverse collection elements | field/else |
+//! | `order` | Output position of a field | field |
 //! | `cont` | Literal replacement for field value | field/type/else |
 //! | `cont_with` | Custom formatter function/closure | field/type/else |
+//! | `quote` | Write as a `rust`, `c`, `json`, `python`, or `sql` string literal | field/type/else |
+//! | `ident` | Escape reserved words from a built in table, `ReservedWords` path, or `state` | field/type/else |
+//! | `num` | Write as a numeric literal, e.g. `num(hex, digits = 4, group = 2, suffix = "u32")` | field/type/else |
 //! | `eval` | Conditional expression | field/type |
 //! | `eval_with` | Conditional function/closure | field/type |
 //! | `nl` | Newline positions (see table below) | field/type/else |
 //! | `ind` | Increase indent level for field content | field/type/else |
 //! | `skip` | Omit field from formatting | field/type |
+//! | `transparent` | Format as the single non-skipped field | type |
+//! | `flatten` | Join a nested struct's fields into the parent's sequence | field |
+//! | `elide` | Placeholder when nested beyond `max_depth`, or `false` to never elide | type |
 //! | `state` | Specify state type (type-level only) | type |
-//! | `bound` | Add trait bound to state (type-level only) | type |
+//! | `state_mut` | Require mutable state, for types which mutate it | type |
+//! | `scope` | Value visible to everything the type or field writes, see `SyntaxFormatter::with_scope` | field/type/else |
+//! | `bound = Trait` | Add trait bound to state (type-level only) | type |
+//! | `bound(...)` / `bound = "..."` | Replace inferred generic bounds (`""` for none) | field/type |
 //!
 //! ### Newline (`nl`) Positions
 //!
@@ -517,7 +954,8 @@
 //! `SyntaxFmt` is implemented for:
 //! - Primitives: `i8` to `i128`, `u8` to `u128`, `isize`, `usize`, `f32`, `f64`, `bool`, `char`
 //! - Strings: `str`, `String`
-//! - Collections: `Vec<T>`, `&[T]`, `[T; N]`
+//! - Collections: `Vec<T>`, `&[T]`, `[T; N]`, `VecDeque<T>`, `LinkedList<T>`, `HashSet<T>`, `BTreeSet<T>`
+//! - Maps: `HashMap<K, V>`, `BTreeMap<K, V>`
 //! - Options: `Option<T>`
 //! - References: `&T`, `Box<T>`, `Rc<T>`, `Arc<T>`
 //! - Tuples: Up to 8 elements
@@ -532,17 +970,54 @@
 //! - `.pretty()` - Enable pretty printing mode
 //! - `.indent(["normal", "pretty"])` - Set indentation strings (default: `["", "    "]`)
 //! - `.newline(["normal", "pretty"])` - Set newline strings (default: `["", "\n"]`)
+//! - `.join_tokens(TokenClass::DEFAULT)` - Insert spaces between writes which would lex together
 //! - `.state(&state)` - Pass immutable state
 //! - `.state_mut(&mut state)` - Pass mutable state
 
-use core::panic;
-use std::cell::{Ref, RefCell, RefMut};
-use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::ops::{Deref, DerefMut};
+use std::any::{type_name, Any};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::fmt::{Alignment, Arguments, Display, Error, Formatter, Result as FmtResult, Write};
 use std::marker::PhantomData;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, LinkedList, VecDeque};
 
 pub use syntaxfmt_macros::SyntaxFmt;
 
+pub mod grammar;
+
+pub use grammar::{Grammar, GrammarExpr, GrammarStyle, Production, SyntaxGrammar};
+
+pub mod quote;
+
+pub use quote::{Quote, Quoted};
+
+pub mod ident;
+
+pub use ident::{EscapedIdent, IdentEscape, ReservedWords, ReservedWordsSource};
+
+pub mod num;
+
+pub use num::{Exponent, Num, NumFmt, NumLit, Radix};
+
+pub mod state;
+
+pub use state::{StateComponents, StateError, StateMap};
+
+pub mod error;
+
+pub use error::{PathSegment, SyntaxError};
+
+mod diff;
+
+mod stack;
+
+use stack::InlineStack;
+
+#[doc(hidden)]
+pub mod assert;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
 /// Formatter mode
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
@@ -552,10 +1027,61 @@ pub enum Mode {
     // When adding new modes, ensure they are not numerically inserted between existing modes
 }
 
+impl Mode {
+    /// Every mode, in numeric order.
+    pub const ALL: [Mode; NUM_MODES] = [Mode::Normal, Mode::Pretty];
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            Mode::Normal => "normal",
+            Mode::Pretty => "pretty",
+        })
+    }
+}
+
 pub const NUM_MODES: usize = 2;
 pub type Strs = [&'static str; NUM_MODES];
 
-type Strings = [String; NUM_MODES];
+// Contexts, sequence positions and scopes held without allocating, enough for typical nesting depths
+const CONTEXT_INLINE: usize = 32;
+const POSITIONS_INLINE: usize = 16;
+const SCOPES_INLINE: usize = 8;
+
+// Indents of up to this many spaces are sliced from it
+const SPACES: &str = "                                                                ";
+
+/// A class of characters which lex as a single token when adjacent.
+///
+/// Used with [`SyntaxDisplay::join_tokens`] to insert a space between writes which would
+/// otherwise merge, such as `a` followed by `b`, or `-` followed by `-x`.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenClass(pub fn(char) -> bool);
+
+impl TokenClass {
+    /// Identifier and number characters.
+    pub const IDENT: Self = Self(|c| c.is_alphanumeric() || c == '_');
+
+    /// Operator and punctuation characters which commonly combine into longer operators.
+    pub const OPERATOR: Self = Self(|c| {
+        matches!(
+            c,
+            '+' | '-' | '*' | '/' | '%' | '=' | '<' | '>' | '!' | '&' | '|' | '^' | '~' | '?' | ':'
+                | '.'
+        )
+    });
+
+    /// Default classes, [`IDENT`](Self::IDENT) and [`OPERATOR`](Self::OPERATOR).
+    pub const DEFAULT: &'static [Self] = &[Self::IDENT, Self::OPERATOR];
+
+    /// Returns true if `a` followed by `b` would merge into one token.
+    #[must_use]
+    #[inline]
+    pub fn joins(&self, a: char, b: char) -> bool {
+        (self.0)(a) && (self.0)(b)
+    }
+}
 
 // Holds state reference
 enum StateRef<'s, S> {
@@ -598,70 +1124,294 @@ impl<'s, S> StateRef<'s, S> {
     #[track_caller]
     fn as_mut(&mut self) -> &mut S {
         match self {
-            StateRef::None(_) => panic!("StateRef: no state has been set"),
-            StateRef::Immutable(_) => panic!("StateRef: state is immutable"),
             StateRef::Mutable(r) => r,
+            // Formatters only have mutable access when created with mutable state
+            _ => unreachable!("StateRef: state is immutable"),
         }
     }
 }
 
+/// Decorations of a derived type or field, written around its content by
+/// [`SyntaxFormatter::decorated`].
+///
+/// The derive stores these in statics, so each type or field compiles to a single call.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub struct Decor {
+    pub prefix: Strs,
+    pub suffix: Strs,
+    pub sep: Strs,
+    pub kv_sep: Option<Strs>,
+    pub last_sep: Option<Strs>,
+    pub indent: bool,
+    pub nl_beg: bool,
+    pub nl_pre: bool,
+    pub nl_cont: bool,
+    pub nl_suf: bool,
+    pub nl_sep: bool,
+}
+
+impl Decor {
+    /// No decorations, with an empty separator.
+    pub const NONE: Self = Self {
+        prefix: ["", ""],
+        suffix: ["", ""],
+        sep: ["", ""],
+        kv_sep: None,
+        last_sep: None,
+        indent: false,
+        nl_beg: false,
+        nl_pre: false,
+        nl_cont: false,
+        nl_suf: false,
+        nl_sep: false,
+    };
+}
+
+#[derive(Clone, Copy)]
 struct Context {
     sep: Strs,
+    kv_sep: Strs,
+    last_sep: Option<Strs>,
     indent: bool,
     nl_sep: bool,
 }
 
-/// Context passed to formatting implementations, containing the formatter and formatting state.
-pub struct SyntaxFormatter<'sr, 's, 'f, 'w, S> {
-    f: &'f mut Formatter<'w>,
-    state: &'sr RefCell<StateRef<'s, S>>,
-    mode: Mode,
-    newline: Strs,
-    single_indent: Strs,
-    indent: Strings,
-    context: Vec<Context>,
+/// Position of the element being formatted within a sequence, such as a slice or tuple.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// fn numbered<S>(step: &Step, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+///     let position = f.position().unwrap();
+///     write!(f, "{}/{} {}", position.index() + 1, position.len().unwrap(), step.0)
+/// }
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(cont_with = numbered)]
+/// struct Step(&'static str);
+///
+/// #[derive(SyntaxFmt)]
+/// struct Steps {
+///     #[syntax(sep = "; ", last_sep = "; and finally ")]
+///     steps: Vec<Step>,
+/// }
+///
+/// let steps = Steps { steps: vec![Step("a"), Step("b"), Step("c")] };
+/// assert_eq!(format!("{}", syntax_fmt(&steps)), "1/3 a; 2/3 b; and finally 3/3 c");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    index: usize,
+    is_last: bool,
+    len: Option<usize>,
 }
 
-impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
+impl Position {
+    /// Returns the index of the element.
     #[must_use]
     #[inline]
-    fn new(
-        f: &'f mut Formatter<'w>,
-        state: &'sr RefCell<StateRef<'s, S>>,
-        newline: Strs,
-        indent: Strs,
-        mode: Mode,
-    ) -> Self {
-        Self {
-            f,
-            state,
-            mode,
-            newline,
-            single_indent: indent,
-            indent: Default::default(),
-            context: Vec::new(),
-        }
+    pub fn index(&self) -> usize {
+        self.index
     }
 
+    /// Returns true if this is the first element.
     #[must_use]
-    #[inline(always)]
-    fn imode(&self) -> usize {
-        self.mode as usize
+    #[inline]
+    pub fn is_first(&self) -> bool {
+        self.index == 0
     }
 
-    /// Returns current mode; `Normal`, `Pretty`
+    /// Returns true if this is the last element.
+    ///
+    /// Sequences filtered with state test each element only once the previous one is written, so
+    /// this is always false for their elements unless a last separator is set.
     #[must_use]
     #[inline]
-    pub fn mode(&self) -> Mode {
-        self.mode
+    pub fn is_last(&self) -> bool {
+        self.is_last
     }
 
-    /// Returns a reference to the user-defined state.
-    ///
-    /// # Panics
-    /// * Panics if state is `()`.
-    ///
-    /// # Example
+    /// Returns the number of elements, if known up front.
+    ///
+    /// Always known for slices, tuples and collections, but not for all iterators.
+    // A position always belongs to a non-empty sequence, so there's no `is_empty`
+    #[allow(clippy::len_without_is_empty)]
+    #[must_use]
+    #[inline]
+    pub fn len(&self) -> Option<usize> {
+        self.len
+    }
+}
+
+// Output limits set on SyntaxDisplay
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_depth: Option<usize>,
+    elision: &'static str,
+    max_width: Option<usize>,
+    ellipsis: &'static str,
+    balanced: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            elision: "...",
+            max_width: None,
+            ellipsis: "…",
+            balanced: false,
+        }
+    }
+}
+
+/// Marks a formatter or display with immutable state, see [`Mutable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Immutable {}
+
+/// Marks a formatter or display with mutable state.
+///
+/// State is mutable when given with [`SyntaxDisplay::state_mut`], and only formatters with
+/// mutable state provide [`SyntaxFormatter::state_mut`]. Implementations which mutate state
+/// implement `SyntaxFmt<S, Mutable>`, so formatting them without mutable state is a compile error
+/// rather than a panic.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// struct Temp;
+///
+/// impl SyntaxFmt<usize, Mutable> for Temp {
+///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<usize, Mutable>) -> std::fmt::Result {
+///         let id = f.map_state_mut(|_, next| std::mem::replace(next, *next + 1));
+///         write!(f, "t{id}")
+///     }
+/// }
+///
+/// let mut next = 0;
+/// let temps = [Temp, Temp];
+/// assert_eq!(format!("{}", syntax_fmt(&temps).state_mut(&mut next)), "t0t1");
+/// assert_eq!(next, 2);
+/// ```
+///
+/// Formatting with immutable state doesn't compile:
+///
+/// ```compile_fail
+/// # use syntaxfmt::{Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+/// # struct Temp;
+/// # impl SyntaxFmt<usize, Mutable> for Temp {
+/// #     fn syntax_fmt(&self, f: &mut SyntaxFormatter<usize, Mutable>) -> std::fmt::Result {
+/// #         f.write_str("t")
+/// #     }
+/// # }
+/// let next = 0usize;
+/// let text = format!("{}", syntax_fmt(&Temp).state(&next));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutable {}
+
+/// Context passed to formatting implementations, containing the formatter and formatting state.
+///
+/// `A` is [`Mutable`] when formatting with mutable state, and [`Immutable`] otherwise. It doesn't
+/// deref to the inner [`Formatter`], whose output wouldn't be tracked. Its flags are forwarded by
+/// accessors such as [`alternate`](Self::alternate) and [`width`](Self::width), and `write!` can
+/// format with them.
+// Layout must not depend on `A`, see `as_immutable`
+#[repr(C)]
+pub struct SyntaxFormatter<'sr, 's, 'f, 'w, S, A = Immutable> {
+    f: &'f mut Formatter<'w>,
+    state: &'sr RefCell<StateRef<'s, S>>,
+    mode: Mode,
+    newline: Strs,
+    single_indent: Strs,
+    // Indentation depth, and the active mode's indentation repeated to the deepest depth so far
+    indent_depth: usize,
+    indent: String,
+    context: InlineStack<Context, CONTEXT_INLINE>,
+    written: usize,
+    // Separator written before the next non-empty write, and whether a newline follows it
+    deferred_sep: Option<(Strs, bool)>,
+    token_classes: &'static [TokenClass],
+    last_char: Option<char>,
+    // Inside `write_atomic`, and whether the token has been started, so later pieces aren't joined
+    in_token: bool,
+    token_started: bool,
+    // Sequence positions, with the context depth of the sequence
+    positions: InlineStack<(usize, Position), POSITIONS_INLINE>,
+    limits: Limits,
+    depth: usize,
+    // Characters written, only counted with a maximum width
+    width: usize,
+    truncated: bool,
+    // Scoped values borrowed from `with_scope`, innermost last
+    scopes: InlineStack<*const dyn Any, SCOPES_INLINE>,
+    // First error recorded, with its path so far. State accessors record errors through `&self`
+    error: Cell<Option<SyntaxError>>,
+    access: PhantomData<A>,
+}
+
+impl<'sr, 's, 'f, 'w, S, A> SyntaxFormatter<'sr, 's, 'f, 'w, S, A> {
+    #[must_use]
+    #[inline]
+    fn new(
+        f: &'f mut Formatter<'w>,
+        state: &'sr RefCell<StateRef<'s, S>>,
+        newline: Strs,
+        indent: Strs,
+        mode: Mode,
+        token_classes: &'static [TokenClass],
+        limits: Limits,
+    ) -> Self {
+        Self {
+            f,
+            state,
+            mode,
+            newline,
+            single_indent: indent,
+            indent_depth: 0,
+            indent: String::new(),
+            context: InlineStack::new(),
+            written: 0,
+            deferred_sep: None,
+            token_classes,
+            last_char: None,
+            in_token: false,
+            token_started: false,
+            positions: InlineStack::new(),
+            limits,
+            depth: 0,
+            width: 0,
+            truncated: false,
+            scopes: InlineStack::new(),
+            error: Cell::new(None),
+            access: PhantomData,
+        }
+    }
+
+    #[must_use]
+    #[inline(always)]
+    fn imode(&self) -> usize {
+        self.mode as usize
+    }
+
+    /// Returns current mode; `Normal`, `Pretty`
+    #[must_use]
+    #[inline]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Returns a reference to the user-defined state.
+    ///
+    /// # Panics
+    /// * Panics if state is mutably borrowed, see [`try_state`](Self::try_state).
+    ///
+    /// # Example
     ///
     /// ```
     /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
@@ -695,55 +1445,25 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     /// ```
     #[must_use]
     #[inline]
-    pub fn state(&self) -> Ref<S> {
-        Ref::map(self.state.borrow(), |s| s.as_ref())
+    #[track_caller]
+    pub fn state(&self) -> Ref<'_, S> {
+        match self.try_state() {
+            Ok(state) => state,
+            Err(e) => panic!("{e}"),
+        }
     }
 
-    /// Returns a mutable reference to the user-defined state.
+    /// Returns a reference to the user-defined state, or an error if it's mutably borrowed.
     ///
-    /// # Panics
-    /// * Panics if state is `()`.
-    /// * Panics if state is immutable.
-    /// 
     /// # Example
     ///
-    /// ```
-    /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
-    ///
-    /// struct IdGenerator {
-    ///     next_id: usize,
-    /// }
-    ///
-    /// impl IdGenerator {
-    ///     fn next(&mut self) -> usize {
-    ///         let id = self.next_id;
-    ///         self.next_id += 1;
-    ///         id
-    ///     }
-    /// }
-    ///
-    /// struct VarDecl {
-    ///     name: &'static str,
-    /// }
-    ///
-    /// impl SyntaxFmt<IdGenerator> for VarDecl {
-    ///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<IdGenerator>) -> std::fmt::Result {
-    ///         let id = f.state_mut().next();
-    ///         write!(f, "let {}_{}", self.name, id)
-    ///     }
-    /// }
-    ///
-    /// let mut id_gen = IdGenerator { next_id: 0 };
-    /// let x = VarDecl { name: "x" };
-    /// let y = VarDecl { name: "y" };
-    /// assert_eq!(format!("{}", syntax_fmt(&x).state_mut(&mut id_gen)), "let x_0");
-    /// assert_eq!(format!("{}", syntax_fmt(&y).state_mut(&mut id_gen)), "let y_1");
-    /// ```
-    #[must_use]
+    /// See [`StateError`].
     #[inline]
-    #[track_caller]
-    pub fn state_mut<'a>(&'a mut self) -> RefMut<'a, S> {
-        RefMut::map(self.state.borrow_mut(), |s| s.as_mut())
+    pub fn try_state(&self) -> Result<Ref<'_, S>, StateError> {
+        self.state
+            .try_borrow()
+            .map(|s| Ref::map(s, |s| s.as_ref()))
+            .map_err(|_| self.state_error(StateError::BorrowedMut))
     }
 
     /// Takes a closure that receives this formatter and immutable access to state and returns a value of its choice.
@@ -755,8 +1475,11 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     ///
     /// * `map` - mapping function with signature `FnOnce(&mut Self, &S) -> R`
     ///
+    /// State stays borrowed while `map` runs, so formatting within it may read state but not
+    /// mutate it.
+    ///
     /// # Panics
-    /// * Panics if state is `()`.
+    /// * Panics if state is mutably borrowed, see [`try_map_state`](Self::try_map_state).
     ///
     /// # Example
     ///
@@ -794,11 +1517,734 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     /// ```
     #[must_use]
     #[inline]
+    #[track_caller]
     pub fn map_state<F, R>(&mut self, map: F) -> R
     where
         F: FnOnce(&mut Self, &S) -> R,
     {
-        map(self, self.state.borrow().as_ref())
+        match self.try_map_state(map) {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Like [`map_state`](Self::map_state), but returns an error if state is mutably borrowed.
+    #[inline]
+    pub fn try_map_state<F, R>(&mut self, map: F) -> Result<R, StateError>
+    where
+        F: FnOnce(&mut Self, &S) -> R,
+    {
+        let state = self.state.try_borrow().map_err(|_| self.state_error(StateError::BorrowedMut))?;
+        Ok(map(self, state.as_ref()))
+    }
+
+    /// Returns the state component of type `T`.
+    ///
+    /// # Panics
+    /// * Panics if state has no component of type `T`, or is mutably borrowed.
+    ///
+    /// # Example
+    ///
+    /// See [`StateComponents`].
+    #[must_use]
+    #[track_caller]
+    pub fn get<T: Any>(&self) -> Ref<'_, T>
+    where
+        S: StateComponents,
+    {
+        match self.try_get() {
+            Ok(component) => component,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Returns the state component of type `T`, or an error if there isn't one or state is
+    /// mutably borrowed.
+    pub fn try_get<T: Any>(&self) -> Result<Ref<'_, T>, StateError>
+    where
+        S: StateComponents,
+    {
+        Ref::filter_map(self.try_state()?, |s| s.component::<T>())
+            .map_err(|_| self.state_error(StateError::MissingComponent(type_name::<T>())))
+    }
+
+    /// Writes a string, first writing any deferred separator if the string isn't empty.
+    ///
+    /// Nothing is written once output has been truncated, see [`truncated`](Self::truncated).
+    #[inline]
+    pub fn write_str(&mut self, s: &str) -> FmtResult {
+        if s.is_empty() || self.truncated {
+            return Ok(());
+        }
+        if let Some((sep, nl)) = self.deferred_sep.take() {
+            // The separator isn't part of a token being written
+            let token_started = self.token_started;
+            self.write_tracked(sep[self.imode()])?;
+            if nl {
+                self.write_newline()?;
+            }
+            self.token_started = token_started;
+            if self.truncated {
+                return Ok(());
+            }
+        }
+        self.write_tracked(s)
+    }
+
+    /// Writes a single token with `fmt`, which may write it in several pieces.
+    ///
+    /// Token joining (see [`SyntaxDisplay::join_tokens`]) only applies before the token, not
+    /// between its pieces, and output isn't truncated within it. Use it for literals written in
+    /// parts, such as escaped strings, where an inserted space would change their value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, TokenClass, syntax_fmt};
+    ///
+    /// struct Str(&'static str);
+    ///
+    /// impl<S, A> SyntaxFmt<S, A> for Str {
+    ///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+    ///         f.write_atomic(|f| {
+    ///             f.write_str("\"")?;
+    ///             for c in self.0.escape_default() {
+    ///                 f.write_str(c.encode_utf8(&mut [0; 4]))?;
+    ///             }
+    ///             f.write_str("\"")
+    ///         })
+    ///     }
+    /// }
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// #[syntax(pre = "let", sep = "=")]
+    /// struct Let(&'static str, Str);
+    ///
+    /// let stmt = Let("x", Str("a\nb"));
+    /// assert_eq!(format!("{}", syntax_fmt(&stmt).join_tokens(TokenClass::DEFAULT)), r#"let x="a\nb""#);
+    /// ```
+    pub fn write_atomic(&mut self, fmt: impl FnOnce(&mut Self) -> FmtResult) -> FmtResult {
+        if self.in_token {
+            return fmt(self);
+        }
+        self.in_token = true;
+        let result = fmt(self);
+        self.in_token = false;
+        self.token_started = false;
+        result
+    }
+
+    // Writes directly to the output, joining tokens if needed
+    #[inline]
+    fn write_tracked(&mut self, s: &str) -> FmtResult {
+        let Some(next) = s.chars().next() else {
+            return Ok(());
+        };
+        if let (Some(last), false) = (self.last_char, self.token_started) {
+            if self.token_classes.iter().any(|class| class.joins(last, next)) {
+                // The joining space counts towards the width, so truncate here if it's used up
+                let full = self.limits.max_width.is_some_and(|max| self.width >= max);
+                if full && !self.truncated && !self.in_token {
+                    self.truncated = true;
+                    return self.write_tracked(self.limits.ellipsis);
+                }
+                self.written += 1;
+                if self.limits.max_width.is_some() {
+                    self.width += 1;
+                }
+                self.f.write_str(" ")?;
+            }
+        }
+        self.token_started = self.in_token;
+        self.last_char = s.chars().next_back();
+        self.written += s.len();
+        if self.limits.max_width.is_some() {
+            self.width += s.chars().count();
+        }
+        self.f.write_str(s)
+    }
+
+    /// Returns the last character written, if any.
+    #[must_use]
+    #[inline]
+    pub fn last_char(&self) -> Option<char> {
+        self.last_char
+    }
+
+    /// Writes formatted arguments, so that `write!` output is tracked like [`write_str`].
+    ///
+    /// [`write_str`]: SyntaxFormatter::write_str
+    #[inline]
+    pub fn write_fmt(&mut self, args: Arguments<'_>) -> FmtResult {
+        if let Some(s) = args.as_str() {
+            self.write_str(s)
+        } else {
+            std::fmt::write(self, args)
+        }
+    }
+
+    /// Returns the fill character of the inner [`Formatter`], see [`Formatter::fill`].
+    ///
+    /// The inner formatter's flags are forwarded rather than exposing it, so all output goes through
+    /// [`write_str`](Self::write_str) and is tracked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+    ///
+    /// struct Hex(u32);
+    ///
+    /// impl<S, A> SyntaxFmt<S, A> for Hex {
+    ///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> std::fmt::Result {
+    ///         match f.width() {
+    ///             Some(width) => write!(f, "{:#0width$x}", self.0),
+    ///             None => write!(f, "{:#x}", self.0),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(format!("{}", syntax_fmt(&Hex(255))), "0xff");
+    /// assert_eq!(format!("{:6}", syntax_fmt(&Hex(255))), "0x00ff");
+    /// ```
+    #[must_use]
+    #[inline]
+    pub fn fill(&self) -> char {
+        self.f.fill()
+    }
+
+    /// Returns the alignment of the inner [`Formatter`], see [`Formatter::align`].
+    #[must_use]
+    #[inline]
+    pub fn align(&self) -> Option<Alignment> {
+        self.f.align()
+    }
+
+    /// Returns the width of the inner [`Formatter`], see [`Formatter::width`].
+    #[must_use]
+    #[inline]
+    pub fn width(&self) -> Option<usize> {
+        self.f.width()
+    }
+
+    /// Returns the precision of the inner [`Formatter`], see [`Formatter::precision`].
+    #[must_use]
+    #[inline]
+    pub fn precision(&self) -> Option<usize> {
+        self.f.precision()
+    }
+
+    /// Returns true if the `+` flag was given, see [`Formatter::sign_plus`].
+    #[must_use]
+    #[inline]
+    pub fn sign_plus(&self) -> bool {
+        self.f.sign_plus()
+    }
+
+    /// Returns true if the `-` flag was given, see [`Formatter::sign_minus`].
+    #[must_use]
+    #[inline]
+    pub fn sign_minus(&self) -> bool {
+        self.f.sign_minus()
+    }
+
+    /// Returns true if the `#` flag was given, see [`Formatter::alternate`].
+    #[must_use]
+    #[inline]
+    pub fn alternate(&self) -> bool {
+        self.f.alternate()
+    }
+
+    /// Returns true if the `0` flag was given, see [`Formatter::sign_aware_zero_pad`].
+    #[must_use]
+    #[inline]
+    pub fn sign_aware_zero_pad(&self) -> bool {
+        self.f.sign_aware_zero_pad()
+    }
+
+    /// Returns the position of the innermost sequence element being formatted, if any.
+    #[must_use]
+    #[inline]
+    pub fn position(&self) -> Option<Position> {
+        self.positions.last().map(|&(_, position)| position)
+    }
+
+    /// Returns the number of nodes being formatted, including the current one.
+    ///
+    /// Derived implementations each count as a node, see [`node`](Self::node).
+    #[must_use]
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Formats a node of the tree with `fmt`, unless the node is deeper than the maximum depth.
+    ///
+    /// Nodes beyond the maximum depth are replaced with the elision placeholder, and their
+    /// children aren't visited. Derived implementations call this unless given `elide = false`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, syntax_fmt};
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// enum Type {
+    ///     #[syntax(pre = "Vec<", suf = ">")]
+    ///     Vec(Box<Type>),
+    ///     #[syntax(cont = "i32")]
+    ///     I32,
+    /// }
+    ///
+    /// let ty = Type::Vec(Box::new(Type::Vec(Box::new(Type::I32))));
+    /// assert_eq!(format!("{}", syntax_fmt(&ty)), "Vec<Vec<i32>>");
+    /// assert_eq!(format!("{}", syntax_fmt(&ty).max_depth(2)), "Vec<Vec<...>>");
+    /// assert_eq!(format!("{}", syntax_fmt(&ty).max_depth(1).elision("_")), "Vec<_>");
+    /// ```
+    #[inline]
+    pub fn node(&mut self, fmt: impl FnOnce(&mut Self) -> FmtResult) -> FmtResult {
+        self.node_with(self.limits.elision, fmt)
+    }
+
+    /// Formats a node of the tree like [`node`](Self::node), but with its own elision placeholder.
+    #[inline]
+    pub fn node_with(
+        &mut self,
+        elision: &str,
+        fmt: impl FnOnce(&mut Self) -> FmtResult,
+    ) -> FmtResult {
+        if self.truncate()? {
+            return Ok(());
+        }
+        self.depth += 1;
+        let result = match self.limits.max_depth {
+            Some(max_depth) if self.depth > max_depth => self.write_str(elision),
+            _ => fmt(self),
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// Records an error with a message, returning the [`Error`] for the formatter to return.
+    ///
+    /// Derived types add their names to the error's path as it propagates, and
+    /// [`SyntaxDisplay::try_to_string`] returns it. Only the first error is kept, so an error
+    /// recorded by a nested formatter isn't replaced by its parents.
+    ///
+    /// # Example
+    ///
+    /// See [`SyntaxError`].
+    #[must_use]
+    pub fn error(&mut self, error: impl Into<SyntaxError>) -> Error {
+        let recorded = self.error.take();
+        self.error.set(Some(recorded.unwrap_or_else(|| error.into())));
+        Error
+    }
+
+    // Records a state error so its message is reported if formatting fails, see `error`
+    #[cold]
+    fn state_error(&self, error: StateError) -> StateError {
+        let recorded = self.error.take();
+        self.error.set(Some(recorded.unwrap_or_else(|| error.into())));
+        error
+    }
+
+    /// Formats with `fmt`, adding `segment` to the path of any error it returns.
+    #[doc(hidden)]
+    #[inline]
+    pub fn breadcrumb(
+        &mut self,
+        segment: PathSegment,
+        fmt: impl FnOnce(&mut Self) -> FmtResult,
+    ) -> FmtResult {
+        let result = fmt(self);
+        if result.is_err() {
+            self.add_breadcrumb(segment);
+        }
+        result
+    }
+
+    /// Formats a field of a derived type with `fmt`, unless output has been truncated.
+    #[doc(hidden)]
+    #[inline]
+    pub fn field(
+        &mut self,
+        name: &'static str,
+        fmt: impl FnOnce(&mut Self) -> FmtResult,
+    ) -> FmtResult {
+        if self.truncate()? {
+            return Ok(());
+        }
+        self.breadcrumb(PathSegment::Field(name), fmt)
+    }
+
+    // Errors returned without a message are still given a path
+    #[cold]
+    fn add_breadcrumb(&mut self, segment: PathSegment) {
+        let mut error = self.error.take().unwrap_or_else(SyntaxError::unknown);
+        error.push_outer(segment);
+        self.error.set(Some(error));
+    }
+
+    /// Formats with `fmt` while `value` is in scope.
+    ///
+    /// Scoped values are looked up by type with [`scope`](Self::scope), so everything formatted
+    /// within `fmt` can see the values of its enclosing scopes without them being part of the
+    /// state. The `scope` attribute argument does the same for a type or field.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+    ///
+    /// struct InPattern;
+    ///
+    /// fn binding<S>(name: &str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+    ///     match f.scope::<InPattern>() {
+    ///         Some(_) => write!(f, "ref {name}"),
+    ///         None => write!(f, "{name}"),
+    ///     }
+    /// }
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// struct Var(#[syntax(cont_with = binding)] &'static str);
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// #[syntax(pre = "let ", sep = " = ", suf = ";")]
+    /// struct Let {
+    ///     #[syntax(scope = InPattern)]
+    ///     pattern: Var,
+    ///     value: Var,
+    /// }
+    ///
+    /// let stmt = Let { pattern: Var("x"), value: Var("y") };
+    /// assert_eq!(format!("{}", syntax_fmt(&stmt)), "let ref x = y;");
+    /// ```
+    #[inline]
+    pub fn with_scope<T: Any>(
+        &mut self,
+        value: T,
+        fmt: impl FnOnce(&mut Self) -> FmtResult,
+    ) -> FmtResult {
+        // The value outlives its entry, which the guard pops even if `fmt` panics
+        let scoped = ScopeGuard(self);
+        scoped.0.scopes.push(&value as &dyn Any);
+        fmt(&mut *scoped.0)
+    }
+
+    /// Returns the innermost scoped value of type `T`, if any, see [`with_scope`](Self::with_scope).
+    #[must_use]
+    #[inline]
+    pub fn scope<T: Any>(&self) -> Option<&T> {
+        self.scopes().next()
+    }
+
+    /// Returns the scoped values of type `T`, innermost first.
+    #[inline]
+    pub fn scopes<T: Any>(&self) -> impl Iterator<Item = &T> {
+        // SAFETY: entries are only present while `with_scope` holds their values
+        self.scopes.iter().rev().filter_map(|&value| unsafe { &*value }.downcast_ref())
+    }
+
+    /// Returns this formatter with immutable state.
+    ///
+    /// Used to pass a formatter with mutable state to functions which only read state.
+    #[must_use]
+    #[inline]
+    pub fn as_immutable(&mut self) -> &mut SyntaxFormatter<'sr, 's, 'f, 'w, S, Immutable> {
+        // SAFETY: the formatter is `repr(C)`, so its fields are laid out in declaration order
+        // with the same offsets whenever their types match. `A` only appears in the last field,
+        // a `PhantomData` which is zero sized with alignment 1 for every `A`, so it adds no
+        // padding and every other field is identical. The layouts therefore match, which is
+        // checked by `test_formatter_layout`. Immutable formatters can't access state mutably,
+        // so this can't be used to gain access.
+        unsafe { &mut *(self as *mut Self).cast() }
+    }
+
+    /// Returns true once output has been truncated at the maximum width.
+    ///
+    /// The width is checked at each node, field and sequence element. Once it's exceeded the ellipsis is
+    /// written, and nothing else is written except suffixes of `balanced` displays.
+    #[must_use]
+    #[inline]
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    // Truncates output if the maximum width is exceeded, returning true if output is truncated
+    #[inline]
+    fn truncate(&mut self) -> Result<bool, Error> {
+        // Tokens are never cut, see `write_atomic`
+        if self.in_token {
+            return Ok(self.truncated);
+        }
+        if !self.truncated {
+            match self.limits.max_width {
+                Some(max_width) if self.width >= max_width => {
+                    self.write_str(self.limits.ellipsis)?;
+                    self.truncated = true;
+                }
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of bytes written so far.
+    #[must_use]
+    #[inline]
+    pub fn written_len(&self) -> usize {
+        self.written
+    }
+
+    /// Defers a separator until the next non-empty write, replacing any already deferred.
+    #[inline]
+    pub fn defer_sep(&mut self, sep: Strs) {
+        self.deferred_sep = Some((sep, false));
+    }
+
+    /// Discards any deferred separator.
+    #[inline]
+    pub fn clear_deferred_sep(&mut self) {
+        self.deferred_sep = None;
+    }
+
+    /// Writes a string set to the formatter based on current mode.
+    #[inline]
+    pub fn write_strs(&mut self, strs: Strs) -> FmtResult {
+        self.write_str(strs[self.imode()])
+    }
+
+    /// Writes a suffix string set based on current mode.
+    ///
+    /// Unlike [`write_strs`](Self::write_strs), suffixes are still written after truncation
+    /// when the display is `balanced`, so delimiters stay closed.
+    #[inline]
+    pub fn write_suffix(&mut self, strs: Strs) -> FmtResult {
+        match (self.truncated, self.limits.balanced) {
+            (false, _) => self.write_strs(strs),
+            (true, true) => self.write_tracked(strs[self.imode()]),
+            (true, false) => Ok(()),
+        }
+    }
+
+    /// Formats with `fmt` between the prefix and suffix of `decor`, in a context of its own.
+    #[doc(hidden)]
+    #[inline]
+    pub fn decorated(
+        &mut self,
+        decor: &Decor,
+        fmt: impl FnOnce(&mut Self) -> FmtResult,
+    ) -> FmtResult {
+        self.open_decor(decor)?;
+        fmt(self)?;
+        self.close_decor(decor)
+    }
+
+    // The context is pushed after the prefix and popped before the suffix, so the indentation of
+    // the content doesn't apply to them
+    fn open_decor(&mut self, decor: &Decor) -> FmtResult {
+        if decor.nl_beg {
+            self.write_newline()?;
+        }
+        self.write_strs(decor.prefix)?;
+        self.push_context(decor.sep, decor.indent, decor.nl_sep);
+        if let Some(kv_sep) = decor.kv_sep {
+            self.set_kv_sep(kv_sep);
+        }
+        if let Some(last_sep) = decor.last_sep {
+            self.set_last_sep(last_sep);
+        }
+        if decor.nl_pre {
+            self.write_newline()?;
+        }
+        Ok(())
+    }
+
+    fn close_decor(&mut self, decor: &Decor) -> FmtResult {
+        self.pop_context();
+        if decor.nl_cont {
+            self.write_newline()?;
+        }
+        self.write_suffix(decor.suffix)?;
+        if decor.nl_suf {
+            self.write_newline()?;
+        }
+        Ok(())
+    }
+
+    /// Pushes contextual information to the stack.
+    ///
+    /// The key value separator is inherited from the enclosing context.
+    #[inline]
+    pub fn push_context(&mut self, sep: Strs, indent: bool, nl_sep: bool) {
+        let kv_sep = self.context.last().map_or(["", ""], |ctx| ctx.kv_sep);
+        self.context.push(Context {
+            sep,
+            kv_sep,
+            last_sep: None,
+            indent,
+            nl_sep,
+        });
+        if indent {
+            self.indent_depth += 1;
+        }
+    }
+
+    /// Pops contextual information from the stack.
+    #[inline]
+    pub fn pop_context(&mut self) {
+        if let Some(ctx) = self.context.pop() {
+            if ctx.indent {
+                self.indent_depth -= 1;
+            }
+        }
+    }
+
+    /// Writes newline and current indentation based on current mode.
+    #[inline]
+    pub fn write_newline(&mut self) -> FmtResult {
+        let newline = self.newline[self.imode()];
+        self.write_str(newline)?;
+        let single = self.single_indent[self.imode()];
+        let len = self.indent_depth * single.len();
+        if len == 0 {
+            return Ok(());
+        }
+        // Indents of spaces are written without building a string
+        if len <= SPACES.len() && single.bytes().all(|b| b == b' ') {
+            return self.write_str(&SPACES[..len]);
+        }
+        while self.indent.len() < len {
+            self.indent.push_str(single);
+        }
+        let indent = std::mem::take(&mut self.indent);
+        let result = self.write_str(&indent[..len]);
+        self.indent = indent;
+        result
+    }
+
+    /// Sets the key value separator of the current context.
+    #[inline]
+    pub fn set_kv_sep(&mut self, kv_sep: Strs) {
+        if let Some(ctx) = self.context.last_mut() {
+            ctx.kv_sep = kv_sep;
+        }
+    }
+
+    /// Sets the separator written before the last element of a sequence in the current context.
+    #[inline]
+    pub fn set_last_sep(&mut self, last_sep: Strs) {
+        if let Some(ctx) = self.context.last_mut() {
+            ctx.last_sep = Some(last_sep);
+        }
+    }
+
+    /// Writes the current key value separator to the output based on current mode.
+    #[inline]
+    pub fn write_kv_sep(&mut self) -> FmtResult {
+        match self.context.last() {
+            Some(ctx) => self.write_strs(ctx.kv_sep),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the current separator to the output based on current mode.
+    ///
+    /// Before the last element of a sequence formatted in the current context, writes the last
+    /// separator instead if one is set.
+    #[inline]
+    pub fn write_sep(&mut self) -> FmtResult {
+        if let Some((sep, nl_sep)) = self.context_sep() {
+            self.write_strs(sep)?;
+            if nl_sep {
+                self.write_newline()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Defers the current separator until the next non-empty write, like
+    /// [`defer_sep`](Self::defer_sep) but with the newline written by [`write_sep`](Self::write_sep).
+    #[inline]
+    pub fn defer_context_sep(&mut self) {
+        if let Some(sep) = self.context_sep() {
+            self.deferred_sep = Some(sep);
+        }
+    }
+
+    // The separator of the current context, and whether a newline follows it
+    fn context_sep(&self) -> Option<(Strs, bool)> {
+        let ctx = self.context.last()?;
+        let depth = self.context.len();
+        let sep = match (self.positions.last(), ctx.last_sep) {
+            (Some(&(d, position)), Some(last_sep)) if d == depth && position.is_last => last_sep,
+            _ => ctx.sep,
+        };
+        Some((sep, ctx.nl_sep))
+    }
+}
+
+impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S, Mutable> {
+    /// Returns a mutable reference to the user-defined state.
+    ///
+    /// Only formatters with mutable state have this method, see [`Mutable`].
+    ///
+    /// # Panics
+    /// * Panics if state is already borrowed, see [`try_state_mut`](Self::try_state_mut).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+    ///
+    /// struct IdGenerator {
+    ///     next_id: usize,
+    /// }
+    ///
+    /// impl IdGenerator {
+    ///     fn next(&mut self) -> usize {
+    ///         let id = self.next_id;
+    ///         self.next_id += 1;
+    ///         id
+    ///     }
+    /// }
+    ///
+    /// struct VarDecl {
+    ///     name: &'static str,
+    /// }
+    ///
+    /// impl SyntaxFmt<IdGenerator, Mutable> for VarDecl {
+    ///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<IdGenerator, Mutable>) -> std::fmt::Result {
+    ///         let id = f.state_mut().next();
+    ///         write!(f, "let {}_{}", self.name, id)
+    ///     }
+    /// }
+    ///
+    /// let mut id_gen = IdGenerator { next_id: 0 };
+    /// let x = VarDecl { name: "x" };
+    /// let y = VarDecl { name: "y" };
+    /// assert_eq!(format!("{}", syntax_fmt(&x).state_mut(&mut id_gen)), "let x_0");
+    /// assert_eq!(format!("{}", syntax_fmt(&y).state_mut(&mut id_gen)), "let y_1");
+    /// ```
+    #[must_use]
+    #[inline]
+    #[track_caller]
+    pub fn state_mut<'a>(&'a mut self) -> RefMut<'a, S> {
+        match self.try_state_mut() {
+            Ok(state) => state,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Returns a mutable reference to the user-defined state, or an error if it's already
+    /// borrowed.
+    #[inline]
+    pub fn try_state_mut(&mut self) -> Result<RefMut<'_, S>, StateError> {
+        self.state
+            .try_borrow_mut()
+            .map(|s| RefMut::map(s, |s| s.as_mut()))
+            .map_err(|_| self.state_error(StateError::Borrowed))
     }
 
     /// Takes a closure that receives this formatter and mutable access to state and returns a value of its choice.
@@ -810,14 +2256,17 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     ///
     /// * `map` - mapping function with signature `FnOnce(&mut Self, &mut S) -> R`
     ///
+    /// State stays mutably borrowed while `map` runs, so formatting within it which accesses
+    /// state fails, or panics if it uses the panicking accessors. Nested formatting should use
+    /// the `try_` accessors, as the derive macro does.
+    ///
     /// # Panics
-    /// * Panics if state is `()`.
-    /// * Panics if state is immutable.
+    /// * Panics if state is already borrowed, see [`try_map_state_mut`](Self::try_map_state_mut).
     ///
     /// # Example
     ///
     /// ```
-    /// use syntaxfmt::{SyntaxFmt, SyntaxFormatter, syntax_fmt};
+    /// use syntaxfmt::{Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
     ///
     /// trait Counter {
     ///     fn increment(&mut self) -> usize;
@@ -835,7 +2284,10 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     ///     }
     /// }
     ///
-    /// fn format_with_id<S: Counter>(name: &str, f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+    /// fn format_with_id<S: Counter>(
+    ///     name: &str,
+    ///     f: &mut SyntaxFormatter<S, Mutable>,
+    /// ) -> std::fmt::Result {
     ///     f.map_state_mut(|f, state| {
     ///         let id = state.increment();
     ///         write!(f, "{}_{}", name, id)
@@ -843,7 +2295,7 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     /// }
     ///
     /// #[derive(SyntaxFmt)]
-    /// #[syntax(bound = Counter)]
+    /// #[syntax(bound = Counter, state_mut)]
     /// struct Node {
     ///     #[syntax(cont_with = format_with_id)]
     ///     name: &'static str,
@@ -856,100 +2308,116 @@ impl<'sr, 's, 'f, 'w, S> SyntaxFormatter<'sr, 's, 'f, 'w, S> {
     /// ```
     #[must_use]
     #[inline]
+    #[track_caller]
     pub fn map_state_mut<F, R>(&mut self, map: F) -> R
     where
         F: FnOnce(&mut Self, &mut S) -> R,
     {
-        map(self, self.state.borrow_mut().as_mut())
-    }
-
-    /// Writes a string set to the formatter based on current mode.
-    #[must_use]
-    #[inline]
-    pub fn write_strs(&mut self, strs: Strs) -> FmtResult {
-        write!(self.f, "{}", strs[self.imode()])
-    }
-
-    /// Pushes contextual information to the stack.
-    #[inline]
-    pub fn push_context(&mut self, sep: Strs, indent: bool, nl_sep: bool) {
-        self.context.push(Context { sep, indent, nl_sep });
-        if indent {
-            (0..NUM_MODES).for_each(|i| self.indent[i].push_str(self.single_indent[i]));
+        match self.try_map_state_mut(map) {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
         }
     }
 
-    /// Pops contextual information from the stack.
-    #[inline]
-    pub fn pop_context(&mut self) {
-        if let Some(ctx) = self.context.pop() {
-            if ctx.indent {
-                (0..NUM_MODES).for_each(|i| {
-                    self.indent[i].truncate(self.indent[i].len() - self.single_indent[i].len())
-                });
-            }
-        }
-    }
-
-    /// Writes newline and current indentation based on current mode.
+    /// Like [`map_state_mut`](Self::map_state_mut), but returns an error if state is already
+    /// borrowed.
+    ///
+    /// # Example
+    ///
+    /// See [`StateError`].
     #[inline]
-    pub fn write_newline(&mut self) -> FmtResult {
-        let newline = self.newline[self.imode()];
-        let indent = self.indent[self.imode()].as_str();
-        write!(self.f, "{newline}{indent}")
+    pub fn try_map_state_mut<F, R>(&mut self, map: F) -> Result<R, StateError>
+    where
+        F: FnOnce(&mut Self, &mut S) -> R,
+    {
+        let mut state = self.state.try_borrow_mut().map_err(|_| self.state_error(StateError::Borrowed))?;
+        Ok(map(self, state.as_mut()))
     }
 
-    /// Writes the current separator to the output based on current mode.
-    #[inline]
-    pub fn write_sep(&mut self) -> FmtResult {
-        if let Some(ctx) = self.context.last() {
-            write!(self.f, "{}", ctx.sep[self.imode()])?;
-            if ctx.nl_sep {
-                self.write_newline()?;
-            }
+    /// Returns the state component of type `T` mutably.
+    ///
+    /// # Panics
+    /// * Panics if state has no component of type `T`, or is already borrowed.
+    ///
+    /// # Example
+    ///
+    /// See [`StateMap`].
+    #[must_use]
+    #[track_caller]
+    pub fn get_mut<T: Any>(&mut self) -> RefMut<'_, T>
+    where
+        S: StateComponents,
+    {
+        match self.try_get_mut() {
+            Ok(component) => component,
+            Err(e) => panic!("{e}"),
         }
-        Ok(())
+    }
+
+    /// Returns the state component of type `T` mutably, or an error if there isn't one or state
+    /// is already borrowed.
+    pub fn try_get_mut<T: Any>(&mut self) -> Result<RefMut<'_, T>, StateError>
+    where
+        S: StateComponents,
+    {
+        // Borrowed through the state reference, so errors can be recorded while it's held
+        let state = self.state;
+        let state = state.try_borrow_mut().map_err(|_| self.state_error(StateError::Borrowed))?;
+        RefMut::filter_map(state, |s| s.as_mut().component_mut::<T>())
+            .map_err(|_| self.state_error(StateError::MissingComponent(type_name::<T>())))
     }
 }
 
-impl<'sr, 's, 'f, 'w, S> Deref for SyntaxFormatter<'sr, 's, 'f, 'w, S> {
-    type Target = Formatter<'w>;
+// Pops the innermost scope when dropped, so no entry outlives its value
+struct ScopeGuard<'a, 'sr, 's, 'f, 'w, S, A>(&'a mut SyntaxFormatter<'sr, 's, 'f, 'w, S, A>);
 
+impl<S, A> Drop for ScopeGuard<'_, '_, '_, '_, '_, S, A> {
     #[inline]
-    fn deref(&self) -> &Self::Target {
-        self.f
+    fn drop(&mut self) {
+        self.0.scopes.pop();
     }
 }
 
-impl<'sr, 's, 'f, 'w, S> DerefMut for SyntaxFormatter<'sr, 's, 'f, 'w, S> {
+impl<S, A> Write for SyntaxFormatter<'_, '_, '_, '_, S, A> {
     #[inline]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.f
+    fn write_str(&mut self, s: &str) -> FmtResult {
+        SyntaxFormatter::write_str(self, s)
     }
 }
 
 /// A wrapper that implements `Display` for types implementing `SyntaxFmt`.
-pub struct SyntaxDisplay<'s, 'e, S, E> {
+///
+/// `A` is [`Mutable`] once given mutable state with [`state_mut`](Self::state_mut).
+pub struct SyntaxDisplay<'s, 'e, S, E, A = Immutable> {
     state: RefCell<StateRef<'s, S>>,
     elem: &'e E,
     newline: Strs,
     indent: Strs,
     mode: Mode,
+    token_classes: &'static [TokenClass],
+    limits: Limits,
+    // Error recorded by the last failed formatting
+    error: RefCell<Option<SyntaxError>>,
+    access: PhantomData<A>,
 }
 
-impl<'s, 'e, S, E> SyntaxDisplay<'s, 'e, S, E> {
+impl<'s, 'e, S, E, A> SyntaxDisplay<'s, 'e, S, E, A> {
     /// Set the state to use during formatting (immutable).
     ///
     /// See [`SyntaxFormatter::state`] for usage examples.
     #[must_use]
     #[inline]
-    pub fn state<'s2, S2>(self, state: &'s2 S2) -> SyntaxDisplay<'s2, 'e, S2, E> {
+    pub fn state<'s2, S2>(self, state: &'s2 S2) -> SyntaxDisplay<'s2, 'e, S2, E, Immutable> {
         SyntaxDisplay {
             state: RefCell::new(StateRef::new_ref(state)),
             elem: self.elem,
             newline: self.newline,
             indent: self.indent,
             mode: self.mode,
+            token_classes: self.token_classes,
+            limits: self.limits,
+            error: RefCell::new(None),
+            access: PhantomData,
         }
     }
 
@@ -958,13 +2426,20 @@ impl<'s, 'e, S, E> SyntaxDisplay<'s, 'e, S, E> {
     /// See [`SyntaxFormatter::state_mut`] for usage examples.
     #[must_use]
     #[inline]
-    pub fn state_mut<'s2, S2>(self, state: &'s2 mut S2) -> SyntaxDisplay<'s2, 'e, S2, E> {
+    pub fn state_mut<'s2, S2>(
+        self,
+        state: &'s2 mut S2,
+    ) -> SyntaxDisplay<'s2, 'e, S2, E, Mutable> {
         SyntaxDisplay {
             state: RefCell::new(StateRef::new_mut(state)),
             elem: self.elem,
             newline: self.newline,
             indent: self.indent,
             mode: self.mode,
+            token_classes: self.token_classes,
+            limits: self.limits,
+            error: RefCell::new(None),
+            access: PhantomData,
         }
     }
 
@@ -976,6 +2451,14 @@ impl<'s, 'e, S, E> SyntaxDisplay<'s, 'e, S, E> {
         self
     }
 
+    /// Set the formatting mode.
+    #[must_use]
+    #[inline]
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
     /// Set the indentation string (default is "" for Normal mode and "    " for Pretty mode).
     #[must_use]
     #[inline]
@@ -991,15 +2474,165 @@ impl<'s, 'e, S, E> SyntaxDisplay<'s, 'e, S, E> {
         self.newline = newline;
         self
     }
+
+    /// Inserts a single space between writes whose boundary characters share a [`TokenClass`].
+    ///
+    /// Formatters which write one token in several pieces, such as escaped literals, use
+    /// [`SyntaxFormatter::write_atomic`] so no space is inserted between them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, TokenClass, syntax_fmt};
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// enum Expr {
+    ///     #[syntax(pre = "-")]
+    ///     Neg(Box<Expr>),
+    ///     Var(&'static str),
+    /// }
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// struct Let {
+    ///     #[syntax(cont = "let")]
+    ///     kw: (),
+    ///     name: &'static str,
+    ///     #[syntax(pre = "=")]
+    ///     value: Expr,
+    /// }
+    ///
+    /// let stmt = Let { kw: (), name: "x", value: Expr::Neg(Box::new(Expr::Neg(Box::new(Expr::Var("y"))))) };
+    /// assert_eq!(format!("{}", syntax_fmt(&stmt)), "letx=--y");
+    /// assert_eq!(format!("{}", syntax_fmt(&stmt).join_tokens(TokenClass::DEFAULT)), "let x= - -y");
+    /// ```
+    #[must_use]
+    #[inline]
+    pub fn join_tokens(mut self, token_classes: &'static [TokenClass]) -> Self {
+        self.token_classes = token_classes;
+        self
+    }
+
+    /// Replaces nodes deeper than `max_depth` with the elision placeholder.
+    ///
+    /// Limits output for deeply nested trees, and bounds recursion. See
+    /// [`SyntaxFormatter::node`] for an example.
+    #[must_use]
+    #[inline]
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.limits.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets the placeholder for elided nodes (default is "...").
+    #[must_use]
+    #[inline]
+    pub fn elision(mut self, elision: &'static str) -> Self {
+        self.limits.elision = elision;
+        self
+    }
+
+    /// Stops formatting once `max_width` characters are written, and writes the ellipsis.
+    ///
+    /// The width is checked before each node, field and sequence element, and before a space
+    /// joining tokens (see [`join_tokens`](Self::join_tokens)), which counts towards it. Output may
+    /// run past `max_width` by the node being written when it's reached, but is never cut mid-token.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::{SyntaxFmt, syntax_fmt};
+    ///
+    /// #[derive(SyntaxFmt)]
+    /// struct Call {
+    ///     name: &'static str,
+    ///     #[syntax(pre = "(", suf = ")", sep = ", ")]
+    ///     args: Vec<&'static str>,
+    /// }
+    ///
+    /// let call = Call { name: "foo", args: vec!["a", "b", "c", "d"] };
+    /// assert_eq!(format!("{}", syntax_fmt(&call).max_width(9)), "foo(a, b, …");
+    /// assert_eq!(format!("{}", syntax_fmt(&call).max_width(9).balanced()), "foo(a, b, …)");
+    /// assert_eq!(format!("{}", syntax_fmt(&call).max_width(9).ellipsis("...")), "foo(a, b, ...");
+    /// ```
+    #[must_use]
+    #[inline]
+    pub fn max_width(mut self, max_width: usize) -> Self {
+        self.limits.max_width = Some(max_width);
+        self
+    }
+
+    /// Sets the ellipsis written when output is truncated (default is "…").
+    #[must_use]
+    #[inline]
+    pub fn ellipsis(mut self, ellipsis: &'static str) -> Self {
+        self.limits.ellipsis = ellipsis;
+        self
+    }
+
+    /// Writes suffixes of nodes left open by truncation, so delimiters stay balanced.
+    #[must_use]
+    #[inline]
+    pub fn balanced(mut self) -> Self {
+        self.limits.balanced = true;
+        self
+    }
+}
+
+impl<'s, 'e, S, E, A> SyntaxDisplay<'s, 'e, S, E, A>
+where
+    E: SyntaxFmt<S, A>,
+{
+    /// Formats to a string, returning the [`SyntaxError`] recorded by a failed formatter rather
+    /// than panicking like `to_string`.
+    ///
+    /// # Example
+    ///
+    /// See [`SyntaxError`].
+    pub fn try_to_string(&self) -> Result<String, SyntaxError> {
+        self.try_to_string_in(self.mode)
+    }
+
+    // Formats to a string in `mode`, whatever the display's own mode
+    fn try_to_string_in(&self, mode: Mode) -> Result<String, SyntaxError> {
+        struct InMode<'d, 's, 'e, S, E, A>(&'d SyntaxDisplay<'s, 'e, S, E, A>, Mode);
+
+        impl<S, E: SyntaxFmt<S, A>, A> Display for InMode<'_, '_, '_, S, E, A> {
+            fn fmt(&self, f: &mut Formatter) -> FmtResult {
+                self.0.fmt_in(f, self.1)
+            }
+        }
+
+        let mut out = String::new();
+        match write!(out, "{}", InMode(self, mode)) {
+            Ok(()) => Ok(out),
+            Err(_) => Err(self.error.take().unwrap_or_else(SyntaxError::unknown)),
+        }
+    }
+
+    fn fmt_in(&self, f: &mut Formatter, mode: Mode) -> FmtResult {
+        let mut f = SyntaxFormatter::new(
+            f,
+            &self.state,
+            self.newline,
+            self.indent,
+            mode,
+            self.token_classes,
+            self.limits,
+        );
+        let result = self.elem.syntax_fmt(&mut f);
+        if result.is_err() {
+            *self.error.borrow_mut() = f.error.take();
+        }
+        result
+    }
 }
 
-impl<'s, 'e, S, E> Display for SyntaxDisplay<'s, 'e, S, E>
+impl<'s, 'e, S, E, A> Display for SyntaxDisplay<'s, 'e, S, E, A>
 where
-    E: SyntaxFmt<S>,
+    E: SyntaxFmt<S, A>,
 {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        let mut f = SyntaxFormatter::new(f, &self.state, self.newline, self.indent, self.mode);
-        self.elem.syntax_fmt(&mut f)
+        self.fmt_in(f, self.mode)
     }
 }
 
@@ -1035,7 +2668,7 @@ where
 /// ## With custom state
 ///
 /// ```
-/// use syntaxfmt::{Mode, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+/// use syntaxfmt::{Mode, Mutable, SyntaxFmt, SyntaxFormatter, syntax_fmt};
 ///
 /// struct Counter {
 ///     count: usize,
@@ -1051,8 +2684,8 @@ where
 ///
 /// struct Item;
 ///
-/// impl SyntaxFmt<Counter> for Item {
-///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<Counter>) -> std::fmt::Result {
+/// impl SyntaxFmt<Counter, Mutable> for Item {
+///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<Counter, Mutable>) -> std::fmt::Result {
 ///         let count = f.state_mut().next();
 ///         if f.mode() == Mode::Pretty {
 ///             write!(f, "pretty_item_{}", count)
@@ -1079,17 +2712,33 @@ pub fn syntax_fmt<'e, E>(elem: &'e E) -> SyntaxDisplay<'static, 'e, (), E> {
         mode: Mode::Normal,
         newline: ["", "\n"],
         indent: ["", "    "],
+        token_classes: &[],
+        limits: Limits::default(),
+        error: RefCell::new(None),
+        access: PhantomData,
     }
 }
 
 /// Trait for types that can be formatted as syntax.
-pub trait SyntaxFmt<S> {
+///
+/// `A` is [`Mutable`] for implementations which need mutable state.
+pub trait SyntaxFmt<S, A = Immutable> {
     /// Formats this value using the given context.
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult;
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult;
+
+    /// Formats only the fields of this value, as part of the parent's field sequence.
+    ///
+    /// Used for fields marked `flatten`. Derived structs write their fields without their own
+    /// decorations or context, so the parent's separators apply. Defaults to [`syntax_fmt`].
+    ///
+    /// [`syntax_fmt`]: SyntaxFmt::syntax_fmt
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        self.syntax_fmt(f)
+    }
 }
 
-impl<S, T> SyntaxFmt<S> for PhantomData<T> {
-    fn syntax_fmt(&self, _ctx: &mut SyntaxFormatter<S>) -> FmtResult {
+impl<S, A, T> SyntaxFmt<S, A> for PhantomData<T> {
+    fn syntax_fmt(&self, _ctx: &mut SyntaxFormatter<S, A>) -> FmtResult {
         Ok(())
     }
 }
@@ -1098,8 +2747,8 @@ impl<S, T> SyntaxFmt<S> for PhantomData<T> {
 macro_rules! impl_syntax_fmt_display {
     ($($ty:ty),*) => {
         $(
-            impl<S> SyntaxFmt<S> for $ty {
-                fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+            impl<S, A> SyntaxFmt<S, A> for $ty {
+                fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
                     write!(f, "{}", self)
                 }
             }
@@ -1108,15 +2757,39 @@ macro_rules! impl_syntax_fmt_display {
 }
 
 impl_syntax_fmt_display!(
-    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, char, bool, str,
-    String
+    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64
 );
 
-impl<S, T> SyntaxFmt<S> for Option<T>
+// Strings are written directly, without the formatting machinery
+impl<S, A> SyntaxFmt<S, A> for str {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_str(self)
+    }
+}
+
+impl<S, A> SyntaxFmt<S, A> for String {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_str(self)
+    }
+}
+
+impl<S, A> SyntaxFmt<S, A> for char {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_str(self.encode_utf8(&mut [0; 4]))
+    }
+}
+
+impl<S, A> SyntaxFmt<S, A> for bool {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_str(if *self { "true" } else { "false" })
+    }
+}
+
+impl<S, A, T> SyntaxFmt<S, A> for Option<T>
 where
-    T: SyntaxFmt<S>,
+    T: SyntaxFmt<S, A>,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         match self {
             Some(inner) => inner.syntax_fmt(f),
             None => Ok(()),
@@ -1125,120 +2798,467 @@ where
 }
 
 // Implement SyntaxFmt for collections
-impl<S, T> SyntaxFmt<S> for Vec<T>
+impl<S, A, T> SyntaxFmt<S, A> for Vec<T>
 where
-    T: SyntaxFmt<S>,
+    T: SyntaxFmt<S, A>,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         self.as_slice().syntax_fmt(f)
     }
 }
 
-impl<S, T> SyntaxFmt<S> for [T]
+impl<S, A, T> SyntaxFmt<S, A> for [T]
+where
+    T: SyntaxFmt<S, A>,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_elems(self, f)
+    }
+}
+
+impl<S, A, T, const N: usize> SyntaxFmt<S, A> for [T; N]
 where
-    T: SyntaxFmt<S>,
+    T: SyntaxFmt<S, A>,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
-        for (i, elem) in self.iter().enumerate() {
-            if i > 0 {
-                f.write_sep()?;
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        self.as_slice().syntax_fmt(f)
+    }
+}
+
+// Writes each item with `fmt_item`, separated by the current separator, tracking the position of
+// each item
+fn fmt_seq<S, A, T>(
+    items: impl IntoIterator<Item = T>,
+    f: &mut SyntaxFormatter<S, A>,
+    mut fmt_item: impl FnMut(usize, T, &mut SyntaxFormatter<S, A>) -> FmtResult,
+) -> FmtResult {
+    let mut items = items.into_iter().peekable();
+    let len = match items.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None,
+    };
+    let depth = f.context.len();
+    let mut index = 0;
+    while let Some(item) = items.next() {
+        let is_last = items.peek().is_none();
+        if !fmt_seq_item(f, depth, Position { index, is_last, len }, item, &mut fmt_item)? {
+            break;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+// Like `fmt_seq`, but skips items for which `keep` returns false. Each item is tested after the
+// previous one is written, so `keep` sees the state it left. Items are only tested ahead to find
+// the last one when the context has a last separator
+fn fmt_seq_filtered<S, A, T>(
+    items: impl IntoIterator<Item = T>,
+    f: &mut SyntaxFormatter<S, A>,
+    mut keep: impl FnMut(&T, &SyntaxFormatter<S, A>) -> Result<bool, Error>,
+    mut fmt_item: impl FnMut(usize, T, &mut SyntaxFormatter<S, A>) -> FmtResult,
+) -> FmtResult {
+    let mut items = items.into_iter();
+    let mut next_kept = |f: &SyntaxFormatter<S, A>| -> Result<Option<T>, Error> {
+        for item in items.by_ref() {
+            if keep(&item, f)? {
+                return Ok(Some(item));
             }
-            elem.syntax_fmt(f)?;
         }
-        Ok(())
+        Ok(None)
+    };
+    let depth = f.context.len();
+    let lookahead = f.context.last().is_some_and(|ctx| ctx.last_sep.is_some());
+    let mut ahead = None;
+    let mut index = 0;
+    loop {
+        let item = match ahead.take() {
+            Some(item) => item,
+            None => match next_kept(f)? {
+                Some(item) => item,
+                None => break,
+            },
+        };
+        if lookahead {
+            ahead = next_kept(f)?;
+        }
+        let is_last = lookahead && ahead.is_none();
+        if !fmt_seq_item(f, depth, Position { index, is_last, len: None }, item, &mut fmt_item)? {
+            break;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+// Writes a sequence item at `position` after its separator, returning false once output has been
+// truncated
+fn fmt_seq_item<S, A, T>(
+    f: &mut SyntaxFormatter<S, A>,
+    depth: usize,
+    position: Position,
+    item: T,
+    fmt_item: &mut impl FnMut(usize, T, &mut SyntaxFormatter<S, A>) -> FmtResult,
+) -> Result<bool, Error> {
+    let index = position.index;
+    f.positions.push((depth, position));
+    let result = if index > 0 { f.write_sep() } else { Ok(()) }
+        .and_then(|_| f.truncate())
+        .and_then(|truncated| if truncated { Ok(()) } else { fmt_item(index, item, f) });
+    f.positions.pop();
+    if result.is_err() {
+        f.add_breadcrumb(PathSegment::Index(index));
+    }
+    result.map(|()| !f.truncated)
+}
+
+// Writes each element, separated by the current separator
+fn fmt_elems<S, A, T: SyntaxFmt<S, A>>(
+    elems: impl IntoIterator<Item = T>,
+    f: &mut SyntaxFormatter<S, A>,
+) -> FmtResult {
+    fmt_seq(elems, f, |_, elem, f| elem.syntax_fmt(f))
+}
+
+// Writes each entry as key, key value separator and value, separated by the current separator
+fn fmt_entries<S, A, K: SyntaxFmt<S, A>, V: SyntaxFmt<S, A>>(
+    entries: impl IntoIterator<Item = (K, V)>,
+    f: &mut SyntaxFormatter<S, A>,
+) -> FmtResult {
+    fmt_seq(entries, f, |_, (key, value), f| {
+        key.syntax_fmt(f)?;
+        f.write_kv_sep()?;
+        value.syntax_fmt(f)
+    })
+}
+
+macro_rules! impl_syntax_fmt_elems {
+    ($($ty:ident <T $(, $H:ident)?>),*) => {
+        $(
+            impl<S, A, T $(, $H)?> SyntaxFmt<S, A> for $ty<T $(, $H)?>
+            where
+                T: SyntaxFmt<S, A>,
+            {
+                fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+                    fmt_elems(self, f)
+                }
+            }
+        )*
+    };
+}
+
+impl_syntax_fmt_elems!(VecDeque<T>, LinkedList<T>, BTreeSet<T>, HashSet<T, H>);
+
+impl<S, A, K, V> SyntaxFmt<S, A> for BTreeMap<K, V>
+where
+    K: SyntaxFmt<S, A>,
+    V: SyntaxFmt<S, A>,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_entries(self, f)
     }
 }
 
-impl<S, T, const N: usize> SyntaxFmt<S> for [T; N]
+impl<S, A, K, V, H> SyntaxFmt<S, A> for HashMap<K, V, H>
 where
-    T: SyntaxFmt<S>,
+    K: SyntaxFmt<S, A>,
+    V: SyntaxFmt<S, A>,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
-        self.as_slice().syntax_fmt(f)
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_entries(self, f)
+    }
+}
+
+/// Formats the entries of a hash based collection sorted, for deterministic output.
+///
+/// Maps are sorted by key. The `sorted` attribute argument wraps a field in `Sorted`.
+///
+/// # Example
+///
+/// ```
+/// use std::collections::HashMap;
+/// use syntaxfmt::{Sorted, SyntaxFmt, syntax_fmt};
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(pre = "{", suf = "}")]
+/// struct Object {
+///     #[syntax(sorted, sep = ", ", kv_sep = ": ")]
+///     fields: HashMap<&'static str, i32>,
+/// }
+///
+/// let object = Object { fields: HashMap::from([("b", 2), ("a", 1), ("c", 3)]) };
+/// assert_eq!(format!("{}", syntax_fmt(&object)), "{a: 1, b: 2, c: 3}");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sorted<T>(pub T);
+
+impl<S, A, K, V, H> SyntaxFmt<S, A> for Sorted<&HashMap<K, V, H>>
+where
+    K: SyntaxFmt<S, A> + Ord,
+    V: SyntaxFmt<S, A>,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        fmt_entries(entries, f)
+    }
+}
+
+impl<S, A, T, H> SyntaxFmt<S, A> for Sorted<&HashSet<T, H>>
+where
+    T: SyntaxFmt<S, A> + Ord,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        let mut elems: Vec<_> = self.0.iter().collect();
+        elems.sort();
+        fmt_elems(elems, f)
+    }
+}
+
+/// Formats key value pairs from any cloneable iterator, such as that of an insertion ordered map.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Entries, SyntaxFmt, syntax_fmt};
+///
+/// #[derive(SyntaxFmt)]
+/// struct Settings {
+///     #[syntax(sep = "; ", kv_sep = " = ", cont = Entries(self.pairs.iter().map(|(k, v)| (k, v))))]
+///     pairs: Vec<(&'static str, bool)>,
+/// }
+///
+/// let settings = Settings { pairs: vec![("fast", true), ("safe", false)] };
+/// assert_eq!(format!("{}", syntax_fmt(&settings)), "fast = true; safe = false");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entries<I>(pub I);
+
+impl<S, A, I, K, V> SyntaxFmt<S, A> for Entries<I>
+where
+    I: Iterator<Item = (K, V)> + Clone,
+    K: SyntaxFmt<S, A>,
+    V: SyntaxFmt<S, A>,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_entries(self.0.clone(), f)
+    }
+}
+
+/// Formats the items of an iterator lazily, separated by the current separator.
+///
+/// The iterator is cloned each time it's formatted, so filtered and mapped children can be
+/// written without collecting them. The `iter` attribute argument does the same for a field.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{SyntaxFmt, syntax_fmt};
+///
+/// #[derive(SyntaxFmt)]
+/// struct Args {
+///     #[syntax(sep = ", ", iter = .iter().filter(|a| !a.is_empty()))]
+///     args: Vec<&'static str>,
+/// }
+///
+/// let args = Args { args: vec!["a", "", "b"] };
+/// assert_eq!(format!("{}", syntax_fmt(&args)), "a, b");
+///
+/// let odd = syntaxfmt::iter((1..6).step_by(2));
+/// assert_eq!(format!("{}", syntax_fmt(&odd)), "135");
+/// ```
+#[must_use]
+#[inline]
+pub fn iter<I>(iter: I) -> Iter<I::IntoIter>
+where
+    I: IntoIterator,
+    I::IntoIter: Clone,
+{
+    Iter(iter.into_iter())
+}
+
+/// Formats the items of a cloneable iterator, see [`iter`].
+#[derive(Debug, Clone)]
+pub struct Iter<I>(pub I);
+
+impl<I: Iterator + Clone> Iter<I> {
+    /// Formats each item with `fmt_item`, which also receives the item's index.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::syntax_fmt;
+    ///
+    /// let params = syntaxfmt::iter(["x", "y"]).with_index(|i, name, f| write!(f, "{name}: T{i}"));
+    /// assert_eq!(format!("{}", syntax_fmt(&params)), "x: T0y: T1");
+    /// ```
+    #[must_use]
+    #[inline]
+    pub fn with_index<S, F>(self, fmt_item: F) -> IterWith<I, F>
+    where
+        F: Fn(usize, I::Item, &mut SyntaxFormatter<S>) -> FmtResult,
+    {
+        IterWith(self.0, fmt_item)
+    }
+
+    /// Skips items for which `predicate` returns false, given the item and formatter state.
+    ///
+    /// The predicate runs lazily as items are formatted, so must not be called while state is
+    /// mutably borrowed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use syntaxfmt::syntax_fmt;
+    ///
+    /// let over = syntaxfmt::iter([1, 5, 2, 7]).filter_state(|n, min: &i32| n > min);
+    /// assert_eq!(format!("{}", syntax_fmt(&over).state(&3)), "57");
+    /// ```
+    #[must_use]
+    #[inline]
+    pub fn filter_state<S, F>(self, predicate: F) -> IterFilter<I, F>
+    where
+        F: Fn(&I::Item, &S) -> bool,
+    {
+        IterFilter(self.0, predicate)
+    }
+}
+
+impl<S, A, I> SyntaxFmt<S, A> for Iter<I>
+where
+    I: Iterator + Clone,
+    I::Item: SyntaxFmt<S, A>,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_elems(self.0.clone(), f)
+    }
+}
+
+/// Formats the items of a cloneable iterator which pass a state dependent predicate, see
+/// [`Iter::filter_state`].
+#[derive(Debug, Clone)]
+pub struct IterFilter<I, F>(I, F);
+
+impl<S, A, I, F> SyntaxFmt<S, A> for IterFilter<I, F>
+where
+    I: Iterator + Clone,
+    I::Item: SyntaxFmt<S, A>,
+    F: Fn(&I::Item, &S) -> bool,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        // State is borrowed only while testing each item, not while formatting it
+        fmt_seq_filtered(
+            self.0.clone(),
+            f,
+            |item, f| Ok((self.1)(item, &*f.try_state()?)),
+            |_, item, f| item.syntax_fmt(f),
+        )
+    }
+}
+
+/// Formats the items of a cloneable iterator with a function of their index, see
+/// [`Iter::with_index`].
+#[derive(Debug, Clone)]
+pub struct IterWith<I, F>(I, F);
+
+impl<S, A, I, F> SyntaxFmt<S, A> for IterWith<I, F>
+where
+    I: Iterator + Clone,
+    F: Fn(usize, I::Item, &mut SyntaxFormatter<S>) -> FmtResult,
+{
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        fmt_seq(self.0.clone(), f, |index, item, f| (self.1)(index, item, f.as_immutable()))
     }
 }
 
 // Implement SyntaxFmt for references and smart pointers
-impl<S, T> SyntaxFmt<S> for &T
+impl<S, A, T> SyntaxFmt<S, A> for &T
 where
-    T: SyntaxFmt<S> + ?Sized,
+    T: SyntaxFmt<S, A> + ?Sized,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         (*self).syntax_fmt(f)
     }
+
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        (*self).syntax_fmt_flat(f)
+    }
 }
 
-impl<S, T> SyntaxFmt<S> for Box<T>
+impl<S, A, T> SyntaxFmt<S, A> for Box<T>
 where
-    T: SyntaxFmt<S> + ?Sized,
+    T: SyntaxFmt<S, A> + ?Sized,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         (**self).syntax_fmt(f)
     }
+
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        (**self).syntax_fmt_flat(f)
+    }
 }
 
-impl<S, T> SyntaxFmt<S> for std::rc::Rc<T>
+impl<S, A, T> SyntaxFmt<S, A> for std::rc::Rc<T>
 where
-    T: SyntaxFmt<S> + ?Sized,
+    T: SyntaxFmt<S, A> + ?Sized,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         (**self).syntax_fmt(f)
     }
+
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        (**self).syntax_fmt_flat(f)
+    }
 }
 
-impl<S, T> SyntaxFmt<S> for std::sync::Arc<T>
+impl<S, A, T> SyntaxFmt<S, A> for std::sync::Arc<T>
 where
-    T: SyntaxFmt<S> + ?Sized,
+    T: SyntaxFmt<S, A> + ?Sized,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         (**self).syntax_fmt(f)
     }
+
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        (**self).syntax_fmt_flat(f)
+    }
 }
 
-impl<S, T> SyntaxFmt<S> for std::borrow::Cow<'_, T>
+impl<S, A, T> SyntaxFmt<S, A> for std::borrow::Cow<'_, T>
 where
-    T: SyntaxFmt<S> + ToOwned + ?Sized,
+    T: SyntaxFmt<S, A> + ToOwned + ?Sized,
 {
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
         self.as_ref().syntax_fmt(f)
     }
-}
 
-// Tuple types
-impl<S> SyntaxFmt<S> for () {
-    fn syntax_fmt(&self, _ctx: &mut SyntaxFormatter<S>) -> FmtResult {
-        Ok(())
+    fn syntax_fmt_flat(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        self.as_ref().syntax_fmt_flat(f)
     }
 }
 
-impl<S, T0> SyntaxFmt<S> for (T0,)
-where
-    T0: SyntaxFmt<S>,
-{
-    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
-        self.0.syntax_fmt(f)
+// Tuple types
+impl<S, A> SyntaxFmt<S, A> for () {
+    fn syntax_fmt(&self, _ctx: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        Ok(())
     }
 }
 
 macro_rules! impl_syntax_fmt_tuple {
     ($($T:ident : $idx:tt),+) => {
-        impl<S, $($T),+> SyntaxFmt<S> for ($($T,)+)
+        impl<S, A, $($T),+> SyntaxFmt<S, A> for ($($T,)+)
         where
-            $($T: SyntaxFmt<S>,)+
+            $($T: SyntaxFmt<S, A>,)+
         {
-            fn syntax_fmt(&self, f: &mut SyntaxFormatter<S>) -> FmtResult {
-                $(
-                    if $idx > 0 {
-                        f.write_sep()?;
-                    }
-                    self.$idx.syntax_fmt(f)?;
-                )+
-                Ok(())
+            fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+                let elems: [&dyn SyntaxFmt<S, A>; [$($idx),+].len()] = [$(&self.$idx),+];
+                fmt_elems(elems, f)
             }
         }
     };
 }
 
+impl_syntax_fmt_tuple!(T0: 0);
 impl_syntax_fmt_tuple!(T0: 0, T1: 1);
 impl_syntax_fmt_tuple!(T0: 0, T1: 1, T2: 2);
 impl_syntax_fmt_tuple!(T0: 0, T1: 1, T2: 2, T3: 3);
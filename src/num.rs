@@ -0,0 +1,320 @@
+//! Numeric literal formatting.
+//!
+//! [`NumFmt`] describes how a number is written as a literal: radix, minimum digits, `_` digit
+//! grouping, forced decimal point, exponent style and suffix. [`Num`] wraps a number with a
+//! format, and the `num` attribute argument applies one to a field, e.g.
+//! `#[syntax(num(hex, digits = 2))]`.
+//!
+//! Floats are written with the shortest representation which round trips.
+
+use std::fmt::{Binary, Display, Formatter, LowerHex, Octal, Result as FmtResult, UpperHex, Write};
+
+use crate::{SyntaxFmt, SyntaxFormatter};
+
+/// Radix of integer literals.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Radix {
+    /// Binary, prefixed with `0b`.
+    Bin,
+    /// Octal, prefixed with `0o`.
+    Oct,
+    /// Decimal.
+    #[default]
+    Dec,
+    /// Hexadecimal, prefixed with `0x`.
+    Hex,
+}
+
+/// When floats are written with an exponent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Exponent {
+    /// Never written with an exponent.
+    #[default]
+    Never,
+    /// Always written with an exponent, e.g. `1.5e3`.
+    Always,
+    /// Written with an exponent when very large or small, below `1e-5` or from `1e16`.
+    Auto,
+}
+
+/// Describes how a number is written as a literal.
+///
+/// Radix and prefix apply to integers, and exponent to floats. The rest apply to both.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Exponent, Num, NumFmt, SyntaxFmt, syntax_fmt};
+///
+/// assert_eq!(Num(NumFmt::new().point(), 1.0f64).to_string(), "1.0");
+/// assert_eq!(Num(NumFmt::new().hex().digits(4).group(2), 255u16).to_string(), "0x00_ff");
+/// assert_eq!(Num(NumFmt::new().exp(Exponent::Always).point(), 1500.0).to_string(), "1.5e3");
+/// assert_eq!(Num(NumFmt::new().group(3).suffix("u32"), 1000000u32).to_string(), "1_000_000u32");
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(sep = ", ")]
+/// struct Color {
+///     #[syntax(num(hex, digits = 2))]
+///     rgb: u8,
+///     #[syntax(num(point, suffix = "f32"))]
+///     alpha: f32,
+/// }
+///
+/// let color = Color { rgb: 10, alpha: 1.0 };
+/// assert_eq!(format!("{}", syntax_fmt(&color)), "0x0a, 1.0f32");
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NumFmt {
+    radix: Radix,
+    no_prefix: bool,
+    upper: bool,
+    digits: usize,
+    group: usize,
+    point: bool,
+    exp: Exponent,
+    suffix: &'static str,
+}
+
+impl NumFmt {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            radix: Radix::Dec,
+            no_prefix: false,
+            upper: false,
+            digits: 0,
+            group: 0,
+            point: false,
+            exp: Exponent::Never,
+            suffix: "",
+        }
+    }
+
+    /// Sets the radix of integers.
+    #[must_use]
+    pub const fn radix(mut self, radix: Radix) -> Self {
+        self.radix = radix;
+        self
+    }
+
+    /// Writes integers in binary.
+    #[must_use]
+    pub const fn bin(self) -> Self {
+        self.radix(Radix::Bin)
+    }
+
+    /// Writes integers in octal.
+    #[must_use]
+    pub const fn oct(self) -> Self {
+        self.radix(Radix::Oct)
+    }
+
+    /// Writes integers in hexadecimal.
+    #[must_use]
+    pub const fn hex(self) -> Self {
+        self.radix(Radix::Hex)
+    }
+
+    /// Omits the radix prefix, such as `0x`.
+    #[must_use]
+    pub const fn no_prefix(mut self) -> Self {
+        self.no_prefix = true;
+        self
+    }
+
+    /// Writes hexadecimal digits and exponents in upper case.
+    #[must_use]
+    pub const fn upper(mut self) -> Self {
+        self.upper = true;
+        self
+    }
+
+    /// Pads the integer part with zeros to at least `digits` digits.
+    #[must_use]
+    pub const fn digits(mut self, digits: usize) -> Self {
+        self.digits = digits;
+        self
+    }
+
+    /// Separates the integer part into groups of `group` digits with `_`.
+    #[must_use]
+    pub const fn group(mut self, group: usize) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Always writes a decimal point, e.g. `1.0` rather than `1`.
+    ///
+    /// Applies to decimal numbers only.
+    #[must_use]
+    pub const fn point(mut self) -> Self {
+        self.point = true;
+        self
+    }
+
+    /// Sets when floats are written with an exponent.
+    #[must_use]
+    pub const fn exp(mut self, exp: Exponent) -> Self {
+        self.exp = exp;
+        self
+    }
+
+    /// Writes `suffix` after the number, e.g. `u8` or `f32`.
+    #[must_use]
+    pub const fn suffix(mut self, suffix: &'static str) -> Self {
+        self.suffix = suffix;
+        self
+    }
+
+    /// Writes `value` with this format.
+    ///
+    /// The literal is written in pieces, so [`Num`] should be used to format it with a
+    /// [`SyntaxFormatter`], which writes it as a single token.
+    pub fn write_num<T: NumLit + ?Sized>(&self, value: &T, out: &mut impl Write) -> FmtResult {
+        value.write_num(self, out)
+    }
+
+    // Writes a number already split into parts, where `int` holds the integer digits
+    fn write_parts(
+        &self,
+        out: &mut impl Write,
+        negative: bool,
+        int: &str,
+        frac: Option<&str>,
+        exp: Option<&str>,
+    ) -> FmtResult {
+        if negative {
+            out.write_char('-')?;
+        }
+        if !self.no_prefix {
+            match self.radix {
+                Radix::Bin => out.write_str("0b")?,
+                Radix::Oct => out.write_str("0o")?,
+                Radix::Dec => {}
+                Radix::Hex => out.write_str("0x")?,
+            }
+        }
+
+        let pad = self.digits.saturating_sub(int.len());
+        let total = pad + int.len();
+        let digits = std::iter::repeat('0').take(pad).chain(int.chars());
+        for (i, c) in digits.enumerate() {
+            if self.group > 0 && i > 0 && (total - i) % self.group == 0 {
+                out.write_char('_')?;
+            }
+            out.write_char(c)?;
+        }
+
+        match frac {
+            Some(frac) => write!(out, ".{frac}")?,
+            None if self.point && self.radix == Radix::Dec => out.write_str(".0")?,
+            None => {}
+        }
+        if let Some(exp) = exp {
+            out.write_char(if self.upper { 'E' } else { 'e' })?;
+            out.write_str(exp)?;
+        }
+        out.write_str(self.suffix)
+    }
+}
+
+/// Trait for numbers which can be written with a [`NumFmt`].
+pub trait NumLit {
+    fn write_num(&self, fmt: &NumFmt, out: &mut impl Write) -> FmtResult;
+}
+
+impl<T: NumLit + ?Sized> NumLit for &T {
+    fn write_num(&self, fmt: &NumFmt, out: &mut impl Write) -> FmtResult {
+        (**self).write_num(fmt, out)
+    }
+}
+
+fn radix_digits<T>(fmt: &NumFmt, value: T) -> String
+where
+    T: Binary + Octal + Display + LowerHex + UpperHex,
+{
+    match (fmt.radix, fmt.upper) {
+        (Radix::Bin, _) => format!("{value:b}"),
+        (Radix::Oct, _) => format!("{value:o}"),
+        (Radix::Dec, _) => format!("{value}"),
+        (Radix::Hex, false) => format!("{value:x}"),
+        (Radix::Hex, true) => format!("{value:X}"),
+    }
+}
+
+macro_rules! impl_num_lit_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl NumLit for $ty {
+                fn write_num(&self, fmt: &NumFmt, out: &mut impl Write) -> FmtResult {
+                    fmt.write_parts(out, false, &radix_digits(fmt, *self), None, None)
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_num_lit_signed {
+    ($($ty:ty),*) => {
+        $(
+            impl NumLit for $ty {
+                fn write_num(&self, fmt: &NumFmt, out: &mut impl Write) -> FmtResult {
+                    let digits = radix_digits(fmt, self.unsigned_abs());
+                    fmt.write_parts(out, *self < 0, &digits, None, None)
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_num_lit_float {
+    ($($ty:ty),*) => {
+        $(
+            impl NumLit for $ty {
+                fn write_num(&self, fmt: &NumFmt, out: &mut impl Write) -> FmtResult {
+                    if !self.is_finite() {
+                        return write!(out, "{self}");
+                    }
+                    let abs = self.abs();
+                    let use_exp = match fmt.exp {
+                        Exponent::Never => false,
+                        Exponent::Always => true,
+                        Exponent::Auto => abs != 0.0 && !(1e-5..1e16).contains(&abs),
+                    };
+                    // Display and LowerExp both give the shortest representation which round trips
+                    let text = if use_exp { format!("{abs:e}") } else { format!("{abs}") };
+                    let (mantissa, exp) = match text.split_once('e') {
+                        Some((mantissa, exp)) => (mantissa, Some(exp)),
+                        None => (text.as_str(), None),
+                    };
+                    let (int, frac) = match mantissa.split_once('.') {
+                        Some((int, frac)) => (int, Some(frac)),
+                        None => (mantissa, None),
+                    };
+                    fmt.radix(Radix::Dec).write_parts(out, self.is_sign_negative(), int, frac, exp)
+                }
+            }
+        )*
+    };
+}
+
+impl_num_lit_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_num_lit_signed!(i8, i16, i32, i64, i128, isize);
+impl_num_lit_float!(f32, f64);
+
+/// Formats a number as a literal with the given [`NumFmt`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Num<T>(pub NumFmt, pub T);
+
+// Digits, separators and affixes are written in pieces, which mustn't be joined or truncated
+impl<S, A, T: NumLit> SyntaxFmt<S, A> for Num<T> {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_atomic(|f| self.1.write_num(&self.0, f))
+    }
+}
+
+impl<T: NumLit> Display for Num<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.1.write_num(&self.0, f)
+    }
+}
@@ -0,0 +1,249 @@
+//! String literal quoting for target languages.
+//!
+//! [`Quoted`] wraps a string so that it's written as a literal of the chosen language, and the
+//! `quote` attribute argument applies the same to a field, e.g. `#[syntax(quote = json)]`.
+
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+
+use crate::{SyntaxFmt, SyntaxFormatter};
+
+/// Target language for string literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quote {
+    /// Rust string, using a raw string with enough `#`s when that avoids escapes.
+    Rust,
+    /// C string, using octal and universal character name escapes.
+    C,
+    /// JSON string.
+    Json,
+    /// Python string, choosing the quote character which needs fewer escapes.
+    Python,
+    /// SQL string, doubling single quotes.
+    Sql,
+}
+
+impl Quote {
+    /// Writes `s` as a string literal.
+    ///
+    /// The literal is written in pieces, so [`Quoted`] should be used to format it with a
+    /// [`SyntaxFormatter`], which writes it as a single token.
+    pub fn write_quoted(self, s: &str, out: &mut impl Write) -> FmtResult {
+        match self {
+            Self::Rust => write_rust(s, out),
+            Self::C => write_c(s, out),
+            Self::Json => write_json(s, out),
+            Self::Python => write_python(s, out),
+            Self::Sql => write_sql(s, out),
+        }
+    }
+
+    /// Returns `s` as a string literal.
+    #[must_use]
+    pub fn quote(self, s: &str) -> String {
+        let mut out = String::with_capacity(s.len() + 2);
+        // Writing to a String cannot fail
+        let _ = self.write_quoted(s, &mut out);
+        out
+    }
+}
+
+/// Formats a string as a literal of the given language.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Quote, Quoted, SyntaxFmt, syntax_fmt};
+///
+/// assert_eq!(Quoted(Quote::Json, "a\"b\n").to_string(), r#""a\"b\n""#);
+/// assert_eq!(Quoted(Quote::Rust, "a\"b").to_string(), r###"r#"a"b"#"###);
+/// assert_eq!(Quoted(Quote::Sql, "it's").to_string(), "'it''s'");
+///
+/// #[derive(SyntaxFmt)]
+/// struct Attr {
+///     name: &'static str,
+///     #[syntax(pre = "=", quote = python)]
+///     value: String,
+/// }
+///
+/// let attr = Attr { name: "title", value: "Bob's".into() };
+/// assert_eq!(format!("{}", syntax_fmt(&attr)), "title=\"Bob's\"");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quoted<T>(pub Quote, pub T);
+
+// Escapes are written in pieces, which mustn't be joined or truncated
+impl<S, A, T: AsRef<str>> SyntaxFmt<S, A> for Quoted<T> {
+    fn syntax_fmt(&self, f: &mut SyntaxFormatter<S, A>) -> FmtResult {
+        f.write_atomic(|f| self.0.write_quoted(self.1.as_ref(), f))
+    }
+}
+
+impl<T: AsRef<str>> Display for Quoted<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.0.write_quoted(self.1.as_ref(), f)
+    }
+}
+
+// Holds a single escape sequence, the longest being `\U0010ffff`
+#[derive(Default)]
+struct EscapeBuf {
+    buf: [u8; 16],
+    len: usize,
+}
+
+impl EscapeBuf {
+    fn as_str(&self) -> &str {
+        // Only whole strs are ever copied in, so the contents are valid UTF-8
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for EscapeBuf {
+    fn write_str(&mut self, s: &str) -> FmtResult {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(std::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Writes `s` with each character either passed through, or replaced by `escape`, writing
+// unescaped runs as slices
+fn write_escaped(
+    s: &str,
+    out: &mut impl Write,
+    mut escape: impl FnMut(char, &mut dyn Write) -> Option<FmtResult>,
+) -> FmtResult {
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let mut escaped = EscapeBuf::default();
+        if let Some(result) = escape(c, &mut escaped) {
+            result?;
+            out.write_str(&s[start..i])?;
+            out.write_str(escaped.as_str())?;
+            start = i + c.len_utf8();
+        }
+    }
+    out.write_str(&s[start..])
+}
+
+// Number of `#`s a raw string needs, or None if it can't be raw
+fn rust_raw_hashes(s: &str) -> Option<usize> {
+    if s.chars().any(char::is_control) {
+        return None;
+    }
+    let mut hashes = 0;
+    let mut run = None;
+    for c in s.chars() {
+        match (c, run) {
+            ('"', _) => run = Some(0),
+            ('#', Some(n)) => run = Some(n + 1),
+            _ => run = None,
+        }
+        if let Some(n) = run {
+            hashes = hashes.max(n + 1);
+        }
+    }
+    Some(hashes)
+}
+
+fn write_rust(s: &str, out: &mut impl Write) -> FmtResult {
+    if s.contains(['"', '\\']) {
+        if let Some(hashes) = rust_raw_hashes(s) {
+            let hashes = "#".repeat(hashes);
+            return write!(out, "r{hashes}\"{s}\"{hashes}");
+        }
+    }
+    out.write_char('"')?;
+    write_escaped(s, out, |c, out| match c {
+        '"' => Some(out.write_str("\\\"")),
+        '\\' => Some(out.write_str("\\\\")),
+        '\n' => Some(out.write_str("\\n")),
+        '\r' => Some(out.write_str("\\r")),
+        '\t' => Some(out.write_str("\\t")),
+        '\0' => Some(out.write_str("\\0")),
+        c if c.is_control() => Some(write!(out, "\\u{{{:x}}}", c as u32)),
+        _ => None,
+    })?;
+    out.write_char('"')
+}
+
+fn write_c(s: &str, out: &mut impl Write) -> FmtResult {
+    out.write_char('"')?;
+    let mut prev = '\0';
+    write_escaped(s, out, |c, out| {
+        let result = match c {
+            '"' => Some(out.write_str("\\\"")),
+            '\\' => Some(out.write_str("\\\\")),
+            '\n' => Some(out.write_str("\\n")),
+            '\r' => Some(out.write_str("\\r")),
+            '\t' => Some(out.write_str("\\t")),
+            '\x07' => Some(out.write_str("\\a")),
+            '\x08' => Some(out.write_str("\\b")),
+            '\x0b' => Some(out.write_str("\\v")),
+            '\x0c' => Some(out.write_str("\\f")),
+            // Avoids forming a trigraph
+            '?' if prev == '?' => Some(out.write_str("\\?")),
+            // Always three digits, so a following digit can't extend the escape
+            c if c.is_ascii_control() => Some(write!(out, "\\{:03o}", c as u32)),
+            // Universal character names can't be below U+00A0, so C1 controls are written as
+            // their UTF-8 bytes
+            c if !c.is_ascii() && c < '\u{a0}' => Some(
+                c.encode_utf8(&mut [0; 4])
+                    .bytes()
+                    .try_for_each(|b| write!(out, "\\{b:03o}")),
+            ),
+            c if !c.is_ascii() && (c as u32) <= 0xffff => Some(write!(out, "\\u{:04x}", c as u32)),
+            c if !c.is_ascii() => Some(write!(out, "\\U{:08x}", c as u32)),
+            _ => None,
+        };
+        prev = c;
+        result
+    })?;
+    out.write_char('"')
+}
+
+fn write_json(s: &str, out: &mut impl Write) -> FmtResult {
+    out.write_char('"')?;
+    write_escaped(s, out, |c, out| match c {
+        '"' => Some(out.write_str("\\\"")),
+        '\\' => Some(out.write_str("\\\\")),
+        '\n' => Some(out.write_str("\\n")),
+        '\r' => Some(out.write_str("\\r")),
+        '\t' => Some(out.write_str("\\t")),
+        '\x08' => Some(out.write_str("\\b")),
+        '\x0c' => Some(out.write_str("\\f")),
+        c if (c as u32) < 0x20 => Some(write!(out, "\\u{:04x}", c as u32)),
+        _ => None,
+    })?;
+    out.write_char('"')
+}
+
+fn write_python(s: &str, out: &mut impl Write) -> FmtResult {
+    // Same choice as Python's `repr`
+    let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+    out.write_char(quote)?;
+    write_escaped(s, out, |c, out| match c {
+        '\\' => Some(out.write_str("\\\\")),
+        '\n' => Some(out.write_str("\\n")),
+        '\r' => Some(out.write_str("\\r")),
+        '\t' => Some(out.write_str("\\t")),
+        c if c == quote => Some(write!(out, "\\{c}")),
+        c if (c as u32) <= 0xff && c.is_control() => Some(write!(out, "\\x{:02x}", c as u32)),
+        c if c.is_control() => Some(write!(out, "\\u{:04x}", c as u32)),
+        _ => None,
+    })?;
+    out.write_char(quote)
+}
+
+fn write_sql(s: &str, out: &mut impl Write) -> FmtResult {
+    out.write_char('\'')?;
+    write_escaped(s, out, |c, out| match c {
+        '\'' => Some(out.write_str("''")),
+        _ => None,
+    })?;
+    out.write_char('\'')
+}
@@ -0,0 +1,78 @@
+//! A stack which holds its first elements inline, so shallow trees format without allocating.
+
+use std::mem::MaybeUninit;
+
+pub(crate) struct InlineStack<T, const N: usize> {
+    // The first `min(len, N)` elements are initialized
+    inline: [MaybeUninit<T>; N],
+    // Elements beyond the first `N`
+    spilled: Vec<T>,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> InlineStack<T, N> {
+    #[must_use]
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self {
+            inline: [MaybeUninit::uninit(); N],
+            spilled: Vec::new(),
+            len: 0,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub(crate) fn push(&mut self, value: T) {
+        match self.inline.get_mut(self.len) {
+            Some(slot) => *slot = MaybeUninit::new(value),
+            None => self.spilled.push(value),
+        }
+        self.len += 1;
+    }
+
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let value = *self.last()?;
+        if self.len > N {
+            self.spilled.pop();
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    #[must_use]
+    #[inline]
+    pub(crate) fn last(&self) -> Option<&T> {
+        match self.len {
+            0 => None,
+            // SAFETY: inline elements below `len` are initialized
+            len if len <= N => Some(unsafe { self.inline[len - 1].assume_init_ref() }),
+            _ => self.spilled.last(),
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub(crate) fn last_mut(&mut self) -> Option<&mut T> {
+        match self.len {
+            0 => None,
+            // SAFETY: inline elements below `len` are initialized
+            len if len <= N => Some(unsafe { self.inline[len - 1].assume_init_mut() }),
+            _ => self.spilled.last_mut(),
+        }
+    }
+
+    // Elements from the bottom of the stack
+    #[inline]
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let inline = &self.inline[..self.len.min(N)];
+        // SAFETY: inline elements below `len` are initialized
+        inline.iter().map(|value| unsafe { value.assume_init_ref() }).chain(&self.spilled)
+    }
+}
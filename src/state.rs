@@ -0,0 +1,237 @@
+//! State made of independent components looked up by type.
+//!
+//! A formatter's state is a single type `S`, so formatters reused across crates would otherwise
+//! need a trait bound implemented by every state type they're used with. [`StateMap`] holds any
+//! number of values keyed by their type, and formatters bound by [`StateComponents`] read them
+//! with [`SyntaxFormatter::get`](crate::SyntaxFormatter::get) and
+//! [`SyntaxFormatter::get_mut`](crate::SyntaxFormatter::get_mut).
+//!
+//! State which can't be accessed, because it's borrowed by an enclosing formatter or is missing a
+//! component, is reported by the `try_` accessors as a [`StateError`].
+
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Error, Formatter, Result as FmtResult};
+
+/// Why state couldn't be accessed.
+///
+/// Converts to [`std::fmt::Error`], so it can be returned with `?` from formatters. The `try_`
+/// accessors also record it on the formatter, so its message is reported by
+/// [`SyntaxDisplay::try_to_string`](crate::SyntaxDisplay::try_to_string).
+///
+/// # Example
+///
+/// ```
+/// use std::fmt::Write;
+/// use syntaxfmt::{Mutable, StateError, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// struct Count;
+///
+/// impl<A> SyntaxFmt<usize, A> for Count {
+///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<usize, A>) -> std::fmt::Result {
+///         let count = *f.try_state()?;
+///         write!(f, "{count}")
+///     }
+/// }
+///
+/// struct Counted;
+///
+/// impl SyntaxFmt<usize, Mutable> for Counted {
+///     fn syntax_fmt(&self, f: &mut SyntaxFormatter<usize, Mutable>) -> std::fmt::Result {
+///         f.try_map_state_mut(|f, count| {
+///             *count += 1;
+///             // State is borrowed by this closure, so can't be read by nested formatting
+///             assert_eq!(f.try_state().err(), Some(StateError::BorrowedMut));
+///             Count.syntax_fmt(f)
+///         })?
+///     }
+/// }
+///
+/// let mut count = 0;
+/// let mut out = String::new();
+/// assert!(write!(out, "{}", syntax_fmt(&Counted).state_mut(&mut count)).is_err());
+/// assert_eq!(format!("{}", syntax_fmt(&Count).state_mut(&mut count)), "1");
+///
+/// let err = syntax_fmt(&Counted).state_mut(&mut count).try_to_string().unwrap_err();
+/// assert_eq!(err.to_string(), "syntaxfmt state is already mutably borrowed");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// State is mutably borrowed, e.g. by an enclosing `map_state_mut`.
+    BorrowedMut,
+    /// State is borrowed, so can't be borrowed mutably.
+    Borrowed,
+    /// State has no component of the named type.
+    MissingComponent(&'static str),
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::BorrowedMut => f.write_str("syntaxfmt state is already mutably borrowed"),
+            Self::Borrowed => f.write_str("syntaxfmt state is already borrowed"),
+            Self::MissingComponent(name) => write!(f, "syntaxfmt state has no component `{name}`"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<StateError> for Error {
+    #[inline]
+    fn from(_: StateError) -> Self {
+        Error
+    }
+}
+
+/// State which provides components by type.
+///
+/// Implemented by [`StateMap`], and may be implemented by application state which stores its
+/// components as fields.
+///
+/// # Example
+///
+/// ```
+/// use std::any::Any;
+/// use syntaxfmt::{StateComponents, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// struct Indent(usize);
+///
+/// struct AppState {
+///     indent: Indent,
+/// }
+///
+/// impl StateComponents for AppState {
+///     fn component<T: Any>(&self) -> Option<&T> {
+///         (&self.indent as &dyn Any).downcast_ref()
+///     }
+///
+///     fn component_mut<T: Any>(&mut self) -> Option<&mut T> {
+///         (&mut self.indent as &mut dyn Any).downcast_mut()
+///     }
+/// }
+///
+/// fn depth<S: StateComponents>(_: &(), f: &mut SyntaxFormatter<S>) -> std::fmt::Result {
+///     let width = f.get::<Indent>().0;
+///     write!(f, "{width}")
+/// }
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(bound = StateComponents)]
+/// struct Width(#[syntax(cont_with = depth)] ());
+///
+/// let state = AppState { indent: Indent(4) };
+/// assert_eq!(format!("{}", syntax_fmt(&Width(())).state(&state)), "4");
+/// ```
+pub trait StateComponents {
+    /// Returns the component of type `T`, if there is one.
+    fn component<T: Any>(&self) -> Option<&T>;
+
+    /// Returns the component of type `T` mutably, if there is one.
+    fn component_mut<T: Any>(&mut self) -> Option<&mut T>;
+}
+
+/// State holding at most one value of each type.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::{Mutable, StateComponents, StateMap, SyntaxFmt, SyntaxFormatter, syntax_fmt};
+///
+/// struct Prefix(&'static str);
+/// struct NextId(usize);
+///
+/// fn fresh<S: StateComponents>(name: &str, f: &mut SyntaxFormatter<S, Mutable>) -> std::fmt::Result {
+///     let prefix = f.get::<Prefix>().0;
+///     let id = {
+///         let mut next = f.get_mut::<NextId>();
+///         next.0 += 1;
+///         next.0
+///     };
+///     write!(f, "{prefix}{name}{id}")
+/// }
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(bound = StateComponents, state_mut)]
+/// struct Temp(#[syntax(cont_with = fresh)] &'static str);
+///
+/// let mut state = StateMap::new().with(Prefix("_")).with(NextId(0));
+/// assert_eq!(format!("{}", syntax_fmt(&Temp("t")).state_mut(&mut state)), "_t1");
+/// assert_eq!(state.get::<NextId>().map(|n| n.0), Some(1));
+/// ```
+#[derive(Default)]
+pub struct StateMap {
+    components: HashMap<TypeId, (&'static str, Box<dyn Any>)>,
+}
+
+impl StateMap {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a component, replacing any existing component of the same type.
+    #[must_use]
+    pub fn with<T: Any>(mut self, value: T) -> Self {
+        self.insert(value);
+        self
+    }
+
+    /// Adds a component, returning the existing component of the same type.
+    pub fn insert<T: Any>(&mut self, value: T) -> Option<T> {
+        self.components
+            .insert(TypeId::of::<T>(), (type_name::<T>(), Box::new(value)))
+            .and_then(|(_, old)| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// Removes and returns the component of type `T`.
+    pub fn remove<T: Any>(&mut self) -> Option<T> {
+        self.components
+            .remove(&TypeId::of::<T>())
+            .and_then(|(_, old)| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// Returns true if there is a component of type `T`.
+    #[must_use]
+    pub fn contains<T: Any>(&self) -> bool {
+        self.components.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Returns the component of type `T`, if there is one.
+    #[must_use]
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.components
+            .get(&TypeId::of::<T>())
+            .and_then(|(_, value)| value.downcast_ref())
+    }
+
+    /// Returns the component of type `T` mutably, if there is one.
+    #[must_use]
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.components
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|(_, value)| value.downcast_mut())
+    }
+}
+
+impl StateComponents for StateMap {
+    #[inline]
+    fn component<T: Any>(&self) -> Option<&T> {
+        self.get()
+    }
+
+    #[inline]
+    fn component_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.get_mut()
+    }
+}
+
+impl Debug for StateMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_set()
+            .entries(self.components.values().map(|(name, _)| name))
+            .finish()
+    }
+}
@@ -0,0 +1,424 @@
+//! Snapshot testing of formatted syntax, enabled by the `testing` cargo feature.
+//!
+//! [`assert_syntax_snapshot!`](crate::assert_syntax_snapshot) formats a syntax tree in every
+//! [`Mode`] and compares the output to a snapshot file in a `snapshots` directory next to the
+//! test's source file, showing a line diff when they differ. Snapshots are written rather than
+//! compared when the `SYNTAXFMT_UPDATE_SNAPSHOTS` environment variable is set, e.g.
+//!
+//! ```text
+//! SYNTAXFMT_UPDATE_SNAPSHOTS=1 cargo test
+//! ```
+//!
+//! A snapshot file holds the output of each mode under a `--- <mode> ---` header.
+//!
+//! For types which also have a parser, [`RoundTrip`] checks that formatting is stable when the
+//! output is parsed and formatted again. With the `proptest` or `quickcheck` cargo features it
+//! also checks values from their generators.
+
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use crate::assert::{show_whitespace, DIFF_LEGEND};
+use crate::{diff::unified_diff, syntax_fmt, Mode, SyntaxDisplay, SyntaxError, SyntaxFmt};
+
+/// Environment variable which makes snapshot assertions write their snapshots.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "SYNTAXFMT_UPDATE_SNAPSHOTS";
+
+/// Asserts that a [`SyntaxDisplay`](crate::SyntaxDisplay) formats as its snapshot in every mode.
+///
+/// The snapshot is named after the test function, or by an explicit name, which is needed when a
+/// test has more than one snapshot. See the [`testing`](crate::testing) module.
+///
+/// # Example
+///
+/// ```no_run
+/// use syntaxfmt::{SyntaxFmt, assert_syntax_snapshot, syntax_fmt};
+///
+/// #[derive(SyntaxFmt)]
+/// #[syntax(pre = "{", suf = "}", nl = inner, ind)]
+/// struct Block(#[syntax(sep = [",", ","], nl = sep)] Vec<i32>);
+///
+/// #[test]
+/// fn block() {
+///     // Compares to `snapshots/<file>__block.snap`
+///     assert_syntax_snapshot!(syntax_fmt(&Block(vec![1, 2])));
+///     assert_syntax_snapshot!("empty_block", syntax_fmt(&Block(vec![])));
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_syntax_snapshot {
+    ($display:expr $(,)?) => {{
+        fn __syntaxfmt_snapshot() {}
+        let name = $crate::testing::function_name(__syntaxfmt_snapshot);
+        $crate::assert_syntax_snapshot!(name, $display)
+    }};
+    ($name:expr, $display:expr $(,)?) => {
+        $crate::testing::Snapshot::new($crate::testing::snapshot_path(
+            ::core::env!("CARGO_MANIFEST_DIR"),
+            ::core::file!(),
+            $name,
+        ))
+        .assert($display)
+    };
+}
+
+/// A snapshot file which formatted syntax is compared to.
+///
+/// Usually created by [`assert_syntax_snapshot!`](crate::assert_syntax_snapshot).
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    path: PathBuf,
+    update: bool,
+}
+
+impl Snapshot {
+    /// Creates a snapshot at `path`, updated if [`UPDATE_SNAPSHOTS_ENV`] is set to anything other
+    /// than `0`.
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let update = std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some_and(|v| !v.is_empty() && v != "0");
+        Self {
+            path: path.into(),
+            update,
+        }
+    }
+
+    /// Writes the snapshot rather than comparing to it.
+    #[must_use]
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Formats `display` in every mode and compares the output to the snapshot, or writes it in
+    /// update mode.
+    ///
+    /// Mutable state is shared by the modes, so each mode sees the state left by the previous.
+    ///
+    /// # Panics
+    /// * Panics if the output differs from the snapshot, the snapshot doesn't exist, or
+    ///   formatting fails.
+    #[track_caller]
+    pub fn assert<S, E, A>(&self, display: SyntaxDisplay<'_, '_, S, E, A>)
+    where
+        E: SyntaxFmt<S, A>,
+    {
+        let actual = render(display);
+        if self.update {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)
+                    .unwrap_or_else(|e| panic!("syntaxfmt can't create `{}`: {e}", dir.display()));
+            }
+            fs::write(&self.path, &actual)
+                .unwrap_or_else(|e| panic!("syntaxfmt can't write `{}`: {e}", self.path.display()));
+            return;
+        }
+
+        let expected = match fs::read_to_string(&self.path) {
+            Ok(expected) => expected.replace("\r\n", "\n"),
+            Err(e) if e.kind() == ErrorKind::NotFound => panic!(
+                "syntaxfmt snapshot `{}` doesn't exist, set {UPDATE_SNAPSHOTS_ENV}=1 to create it",
+                self.path.display()
+            ),
+            Err(e) => panic!("syntaxfmt can't read `{}`: {e}", self.path.display()),
+        };
+        if expected != actual {
+            panic!(
+                "syntaxfmt snapshot `{}` differs, set {UPDATE_SNAPSHOTS_ENV}=1 to update it {DIFF_LEGEND}\n{}",
+                self.path.display(),
+                unified_diff(&expected, &actual, show_whitespace)
+            );
+        }
+    }
+}
+
+// Formats in every mode, each under a header
+fn render<S, E, A>(mut display: SyntaxDisplay<'_, '_, S, E, A>) -> String
+where
+    E: SyntaxFmt<S, A>,
+{
+    let mut out = String::new();
+    for mode in Mode::ALL {
+        display = display.mode(mode);
+        let text = display
+            .try_to_string()
+            .unwrap_or_else(|e| panic!("syntaxfmt {mode} formatting failed: {e}"));
+        out.push_str(&format!("--- {mode} ---\n{text}\n"));
+    }
+    out
+}
+
+/// Returns the path of snapshot `name` for the test source file `file`.
+///
+/// `file` is as given by `file!()`, which may be relative to the package in `manifest_dir` or to
+/// its workspace.
+#[must_use]
+pub fn snapshot_path(manifest_dir: &str, file: &str, name: &str) -> PathBuf {
+    let manifest_dir = Path::new(manifest_dir);
+    let source = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join(file))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| manifest_dir.join(file));
+    let stem = source.file_stem().and_then(|s| s.to_str()).unwrap_or("snapshot");
+    let dir = source.parent().unwrap_or(manifest_dir);
+    dir.join("snapshots").join(format!("{stem}__{name}.snap"))
+}
+
+/// Returns the name of the function enclosing the function item `f`.
+#[doc(hidden)]
+#[must_use]
+pub fn function_name<F>(_: F) -> &'static str {
+    let path = std::any::type_name::<F>();
+    let mut segments = path.rsplit("::");
+    segments.next();
+    segments.next().unwrap_or(path)
+}
+
+/// Checks that formatting survives a round trip through a parser.
+///
+/// In every mode, a value is formatted, parsed by the given function and formatted again, and
+/// the two outputs must be identical. With [`compare_values`](Self::compare_values), the parsed
+/// value must also equal the original. This catches missing separators and other output which
+/// doesn't parse as it was meant to.
+///
+/// # Example
+///
+/// ```
+/// use syntaxfmt::SyntaxFmt;
+/// use syntaxfmt::testing::RoundTrip;
+///
+/// #[derive(SyntaxFmt, Debug, PartialEq)]
+/// #[syntax(pre = "let ", sep = " = ")]
+/// struct Let(&'static str, u32);
+///
+/// fn parse(text: &str) -> Result<Let, String> {
+///     let (name, value) = text
+///         .strip_prefix("let ")
+///         .and_then(|rest| rest.split_once(" = "))
+///         .ok_or("expected `let name = value`")?;
+///     let name = Box::leak(name.to_string().into_boxed_str());
+///     Ok(Let(name, value.parse().map_err(|e| format!("{e}"))?))
+/// }
+///
+/// RoundTrip::new(parse).compare_values().assert(&Let("x", 1));
+/// ```
+pub struct RoundTrip<T, P> {
+    parse: P,
+    compare: Option<fn(&T, &T) -> Option<String>>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T, P, E> RoundTrip<T, P>
+where
+    T: SyntaxFmt<()>,
+    P: Fn(&str) -> Result<T, E>,
+    E: Display,
+{
+    /// Creates a round trip check using `parse` to parse formatted output.
+    #[must_use]
+    pub fn new(parse: P) -> Self {
+        Self {
+            parse,
+            compare: None,
+            value: PhantomData,
+        }
+    }
+
+    /// Also checks that parsed values equal the original.
+    #[must_use]
+    pub fn compare_values(mut self) -> Self
+    where
+        T: PartialEq + Debug,
+    {
+        self.compare = Some(|value, parsed| (value != parsed).then(|| format!("{value:?} != {parsed:?}")));
+        self
+    }
+
+    /// Checks `value` in every mode, returning the first failure.
+    pub fn check(&self, value: &T) -> Result<(), RoundTripError> {
+        for mode in Mode::ALL {
+            let format = |value: &T| {
+                syntax_fmt(value)
+                    .mode(mode)
+                    .try_to_string()
+                    .map_err(|error| RoundTripError::Format { mode, error })
+            };
+            let text = format(value)?;
+            let parsed = (self.parse)(&text).map_err(|error| RoundTripError::Parse {
+                mode,
+                text: text.clone(),
+                error: error.to_string(),
+            })?;
+            let reformatted = format(&parsed)?;
+            if reformatted != text {
+                return Err(RoundTripError::Unstable {
+                    mode,
+                    text,
+                    reformatted,
+                });
+            }
+            if let Some(difference) = self.compare.and_then(|compare| compare(value, &parsed)) {
+                return Err(RoundTripError::ValueChanged {
+                    mode,
+                    text,
+                    difference,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `value` in every mode.
+    ///
+    /// # Panics
+    /// * Panics with the first failure, see [`RoundTripError`].
+    #[track_caller]
+    pub fn assert(&self, value: &T) {
+        if let Err(e) = self.check(value) {
+            panic!("{e}");
+        }
+    }
+}
+
+/// A failed [`RoundTrip`] check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundTripError {
+    /// Formatting the original or parsed value failed.
+    Format { mode: Mode, error: SyntaxError },
+    /// Formatted output didn't parse.
+    Parse { mode: Mode, text: String, error: String },
+    /// The parsed value formatted differently to the original.
+    Unstable { mode: Mode, text: String, reformatted: String },
+    /// The parsed value isn't equal to the original.
+    ValueChanged { mode: Mode, text: String, difference: String },
+}
+
+impl Display for RoundTripError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Format { mode, error } => write!(f, "syntaxfmt {mode} formatting failed: {error}"),
+            Self::Parse { mode, text, error } => {
+                write!(f, "syntaxfmt {mode} output doesn't parse: {error}\n{text}")
+            }
+            Self::Unstable { mode, text, reformatted } => write!(
+                f,
+                "syntaxfmt {mode} output changes when parsed and formatted again {DIFF_LEGEND}\n{}",
+                unified_diff(text, reformatted, show_whitespace)
+            ),
+            Self::ValueChanged { mode, text, difference } => write!(
+                f,
+                "syntaxfmt {mode} output parses as a different value: {difference}\n{text}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RoundTripError {}
+
+#[cfg(feature = "proptest")]
+impl<T, P, E> RoundTrip<T, P>
+where
+    T: SyntaxFmt<()> + Debug,
+    P: Fn(&str) -> Result<T, E>,
+    E: Display,
+{
+    /// Checks values generated by a proptest strategy, with the default test count.
+    ///
+    /// # Panics
+    /// * Panics with the smallest failing value found and its failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(feature = "proptest")] {
+    /// use proptest::prelude::*;
+    /// use syntaxfmt::SyntaxFmt;
+    /// use syntaxfmt::testing::RoundTrip;
+    ///
+    /// #[derive(SyntaxFmt, Debug)]
+    /// struct Sum(#[syntax(sep = " + ")] Vec<u8>);
+    ///
+    /// fn parse(text: &str) -> Result<Sum, std::num::ParseIntError> {
+    ///     text.split(" + ").filter(|t| !t.is_empty()).map(str::parse).collect::<Result<_, _>>().map(Sum)
+    /// }
+    ///
+    /// RoundTrip::new(parse).proptest(prop::collection::vec(any::<u8>(), 0..8).prop_map(Sum));
+    /// # }
+    /// ```
+    #[track_caller]
+    pub fn proptest<S>(&self, strategy: S)
+    where
+        S: proptest::strategy::Strategy<Value = T>,
+    {
+        // Failures aren't persisted, since there's no source file to persist them next to
+        let config = proptest::test_runner::Config {
+            failure_persistence: None,
+            ..Default::default()
+        };
+        let mut runner = proptest::test_runner::TestRunner::new(config);
+        if let Err(e) = runner.run(&strategy, |value| self.check(&value).map_err(Into::into)) {
+            panic!("{e}");
+        }
+    }
+}
+
+#[cfg(feature = "quickcheck")]
+impl<T, P, E> RoundTrip<T, P>
+where
+    T: SyntaxFmt<()> + quickcheck::Arbitrary + Debug,
+    P: Fn(&str) -> Result<T, E> + 'static,
+    E: Display,
+{
+    /// Checks values generated by quickcheck's [`Arbitrary`](quickcheck::Arbitrary), with the
+    /// default test count.
+    ///
+    /// # Panics
+    /// * Panics with the smallest failing value found and its failure.
+    #[track_caller]
+    pub fn quickcheck(self) {
+        quickcheck::QuickCheck::new().quickcheck(self);
+    }
+}
+
+#[cfg(feature = "quickcheck")]
+impl<T, P, E> quickcheck::Testable for RoundTrip<T, P>
+where
+    T: SyntaxFmt<()> + quickcheck::Arbitrary + Debug,
+    P: Fn(&str) -> Result<T, E> + 'static,
+    E: Display,
+{
+    fn result(&self, g: &mut quickcheck::Gen) -> quickcheck::TestResult {
+        let mut value = T::arbitrary(g);
+        let Err(mut error) = self.check(&value) else {
+            return quickcheck::TestResult::passed();
+        };
+        // Shrink to the smallest value which still fails
+        'shrink: loop {
+            for smaller in value.shrink() {
+                if let Err(e) = self.check(&smaller) {
+                    value = smaller;
+                    error = e;
+                    continue 'shrink;
+                }
+            }
+            break;
+        }
+        quickcheck::TestResult::error(format!("{error}\nfor {value:?}"))
+    }
+}
+
+#[cfg(feature = "quickcheck")]
+impl From<RoundTripError> for quickcheck::TestResult {
+    fn from(error: RoundTripError) -> Self {
+        Self::error(error.to_string())
+    }
+}